@@ -1,4 +1,5 @@
 SHADERS_DEFERRED="eruptrace_deferred/src/shaders"
+SHADERS_DENOISER="eruptrace_denoiser/src/shaders"
 SHADERS_PURE="eruptrace_pure/src/shaders"
 SHADERS_RENDER_SURFACE="src/shaders"
 
@@ -6,6 +7,9 @@ glslc $SHADERS_DEFERRED/mesh.vert -o $SHADERS_DEFERRED/mesh_vert.spv
 glslc $SHADERS_DEFERRED/mesh.frag -o $SHADERS_DEFERRED/mesh_frag.spv
 glslc $SHADERS_DEFERRED/lighting.vert -o $SHADERS_DEFERRED/lighting_vert.spv
 glslc $SHADERS_DEFERRED/lighting.frag -o $SHADERS_DEFERRED/lighting_frag.spv -I./glsl_include/
+glslc $SHADERS_DEFERRED/resolve.frag -o $SHADERS_DEFERRED/resolve_frag.spv -I./glsl_include/
+
+glslc $SHADERS_DENOISER/atrous.comp -o $SHADERS_DENOISER/atrous_comp.spv
 
 glslc $SHADERS_PURE/image.vert -o $SHADERS_PURE/image_vert.spv
 glslc $SHADERS_PURE/image.frag -o $SHADERS_PURE/image_frag.spv -I./glsl_include/
@@ -161,17 +161,7 @@ impl GeometryPass {
         };
 
         let camera_uniforms = {
-            let view = glm::look_at(&camera.position, &camera.look_at, &camera.up);
-            let proj = glm::perspective(
-                camera.img_size[0] as f32 / camera.img_size[1] as f32,
-                camera.vertical_fov.to_radians(),
-                0.0001,
-                100.0,
-            );
-            let uniforms = vec![CameraUniforms {
-                view_transform:       eruptrace_vk::std140::mat4x4(&view),
-                projection_transform: eruptrace_vk::std140::mat4x4(&proj),
-            }];
+            let uniforms = vec![CameraUniforms::new(camera)];
             AllocatedBuffer::with_data(
                 vk_ctx.allocator.clone(),
                 &uniform_buffer_info,
@@ -295,25 +285,22 @@ impl GeometryPass {
         self.graphics_pipeline.destroy(device);
     }
 
+    pub fn update_mesh_transform(&mut self, mesh_index: usize, transform: glm::Mat4x4) {
+        let mesh = &mut self.meshes[mesh_index];
+        mesh.transform = transform;
+        self.mesh_metas.set_data_at(mesh_index * size_of::<MeshMetas>(), &[MeshMetas {
+            model_transform: eruptrace_vk::std140::mat4x4(&mesh.transform),
+            material_index:  std140::uint(mesh.material_index),
+        }]);
+    }
+
     pub fn update_camera(&mut self, vk_ctx: VulkanContext, camera: Camera) {
-        let view = glm::look_at(&camera.position, &camera.look_at, &camera.up);
-        let mut proj = glm::perspective(
-            camera.img_size[0] as f32 / camera.img_size[1] as f32,
-            camera.vertical_fov.to_radians(),
-            0.001,
-            100.0,
-        );
-        proj[(1, 1)] *= -1.0;
-        let data = CameraUniforms {
-            view_transform:       eruptrace_vk::std140::mat4x4(&view),
-            projection_transform: eruptrace_vk::std140::mat4x4(&proj),
-        };
-        self.camera_uniforms.set_data(&[data]);
+        self.camera_uniforms.set_data(&[CameraUniforms::new(&camera)]);
 
-        self.output_extent = vk::Extent2D {
-            width:  camera.img_size[0] * camera.sqrt_samples,
-            height: camera.img_size[1] * camera.sqrt_samples,
-        };
+        // The filter weights in samples from past the region's borders, so a margin of them is rasterised around it.
+        let [width, height] =
+            camera.rendered_size().map(|size| (size + 2 * camera.filter_margin()) * camera.sqrt_samples);
+        self.output_extent = vk::Extent2D { width, height };
         let gbuffer_extent =
             vk::Extent3D { width: self.output_extent.width, height: self.output_extent.height, depth: 1 };
 
@@ -331,7 +318,7 @@ impl GeometryPass {
         self.depth_buffer = AllocatedImage::depth_buffer(vk_ctx.clone(), gbuffer_extent);
     }
 
-    pub fn render(&self, vk_ctx: VulkanContext) {
+    pub fn render(&self, vk_ctx: VulkanContext) -> Result<(), vk::Result> {
         let colour_attachments = self.gbuffers.create_colour_attachment_infos();
         let depth_attachment = vk::RenderingAttachmentInfoBuilder::new()
             .image_view(self.depth_buffer.view)
@@ -427,6 +414,23 @@ impl GeometryPass {
                     ]
                 }),
             );
-        });
+        })
+    }
+}
+
+impl CameraUniforms {
+    /// Transforms rasterising the scene as the camera sees it. Vulkan's viewport y axis points down, so the whole
+    /// projection, including the crop to the rendered region, is flipped vertically. The region is then shrunk to
+    /// leave room for the filter's margin around it.
+    pub fn new(camera: &Camera) -> Self {
+        let view = glm::look_at(&camera.position, &camera.look_at, &camera.up);
+        let [width, height] = camera.rendered_size().map(|size| size as f32);
+        let margin = 2.0 * camera.filter_margin() as f32;
+        let shrink = glm::scaling(&glm::vec3(width / (width + margin), -height / (height + margin), 1.0));
+        let proj = shrink * camera.projection_transform(0.001, 100.0);
+        Self {
+            view_transform:       eruptrace_vk::std140::mat4x4(&view),
+            projection_transform: eruptrace_vk::std140::mat4x4(&proj),
+        }
     }
 }
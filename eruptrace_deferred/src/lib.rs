@@ -5,9 +5,10 @@ pub mod geometry_pass;
 pub mod lighting_pass;
 pub mod shaders;
 
-use erupt::DeviceLoader;
+use erupt::{vk, DeviceLoader};
 use eruptrace_scene::{Camera, CameraUniform, Mesh as SceneMesh, RtSceneBuffers};
-use eruptrace_vk::{push_constants::RtPushConstants, AllocatedBuffer, AllocatedImage, VulkanContext};
+use eruptrace_vk::{push_constants::RtPushConstants, AllocatedBuffer, RenderTargets, VulkanContext};
+use nalgebra_glm as glm;
 
 use crate::{geometry_pass::GeometryPass, lighting_pass::LightingPass};
 
@@ -24,10 +25,8 @@ impl DeferredRayTracer {
         camera_buffer: &AllocatedBuffer<CameraUniform>,
         scene_buffers: &RtSceneBuffers,
     ) -> anyhow::Result<Self> {
-        let output_extent = camera.image_extent_2d();
         let geometry_pass = GeometryPass::new(vk_ctx.clone(), &camera, scene_meshes)?;
-        let lighting_pass =
-            LightingPass::new(vk_ctx, output_extent, &geometry_pass.gbuffers, camera_buffer, scene_buffers);
+        let lighting_pass = LightingPass::new(vk_ctx, &camera, &geometry_pass.gbuffers, camera_buffer, scene_buffers);
         Ok(Self { geometry_pass, lighting_pass })
     }
 
@@ -38,11 +37,25 @@ impl DeferredRayTracer {
 
     pub fn update_output(&mut self, vk_ctx: VulkanContext, camera: Camera) {
         self.geometry_pass.update_camera(vk_ctx.clone(), camera);
-        self.lighting_pass.update_output(&vk_ctx.device, camera.image_extent_2d(), &self.geometry_pass.gbuffers);
+        self.lighting_pass.update_output(&vk_ctx.device, &camera, &self.geometry_pass.gbuffers);
     }
 
-    pub fn render(&self, vk_ctx: VulkanContext, push_constants: &RtPushConstants, target: &AllocatedImage) {
-        self.geometry_pass.render(vk_ctx.clone());
-        self.lighting_pass.render(vk_ctx, push_constants, target);
+    pub fn update_mesh_transform(&mut self, mesh_index: usize, transform: glm::Mat4x4) {
+        self.geometry_pass.update_mesh_transform(mesh_index, transform);
+    }
+
+    /// Fills the G-buffers, which the tiles of the image are then shaded from.
+    pub fn render_gbuffers(&self, vk_ctx: VulkanContext) -> Result<(), vk::Result> {
+        self.geometry_pass.render(vk_ctx)
+    }
+
+    pub fn render_tile(
+        &mut self,
+        vk_ctx: VulkanContext,
+        push_constants: &RtPushConstants,
+        targets: &RenderTargets,
+        tile: vk::Rect2D,
+    ) -> Result<(), vk::Result> {
+        self.lighting_pass.render_tile(vk_ctx, push_constants, targets, tile)
     }
 }
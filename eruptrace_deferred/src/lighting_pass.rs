@@ -1,11 +1,10 @@
 use std::ffi::c_void;
 
 use erupt::{vk, DeviceLoader};
-use eruptrace_scene::{CameraUniform, RtSceneBuffers};
+use eruptrace_scene::{Camera, CameraUniform, RtSceneBuffers};
 use eruptrace_vk::{
     command,
     pipeline::{
-        ColorAttachmentInfo,
         DescriptorBindingCreateInfo,
         DescriptorSetCreateInfo,
         GraphicsPipelineCreateInfo,
@@ -15,7 +14,7 @@ use eruptrace_vk::{
     },
     push_constants::RtPushConstants,
     AllocatedBuffer,
-    AllocatedImage,
+    RenderTargets,
     VulkanContext,
 };
 use nalgebra_glm as glm;
@@ -23,7 +22,7 @@ use vk_mem_3_erupt as vma;
 
 use crate::{
     gbuffers::GBuffers,
-    shaders::{LIGHTING_FRAGMENT_SHADER, LIGHTING_VERTEX_SHADER},
+    shaders::{LIGHTING_FRAGMENT_SHADER, LIGHTING_VERTEX_SHADER, RESOLVE_FRAGMENT_SHADER},
 };
 
 #[repr(C)]
@@ -32,17 +31,33 @@ pub struct Vertex {
     pub position: glm::Vec2,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct PushConstants {
+    ray_tracing:   RtPushConstants,
+    /// G-buffer texel shaded into the first of the samples, which hold those of one tile at a time.
+    sample_origin: [u32; 2],
+    /// Pixels by which the G-buffer reaches past each side of the rendered region.
+    margin:        u32,
+}
+
+/// Shades every G-buffer texel once as a sample, then resolves the pixels from the samples their filter reaches.
 #[derive(Clone)]
 pub struct LightingPass {
-    vertex_buffer:     AllocatedBuffer<Vertex>,
-    output_extent:     vk::Extent2D,
-    graphics_pipeline: Pipeline,
+    vertex_buffer:    AllocatedBuffer<Vertex>,
+    output_extent:    vk::Extent2D,
+    sqrt_samples:     u32,
+    margin:           u32,
+    /// Shaded samples of the tile being rendered and of the margin around it.
+    samples:          RenderTargets,
+    shading_pipeline: Pipeline,
+    resolve_pipeline: Pipeline,
 }
 
 impl LightingPass {
     pub fn new(
         vk_ctx: VulkanContext,
-        output_extent: vk::Extent2D,
+        camera: &Camera,
         gbuffers: &GBuffers,
         camera_buffer: &AllocatedBuffer<CameraUniform>,
         scene_buffers: &RtSceneBuffers,
@@ -61,18 +76,21 @@ impl LightingPass {
             AllocatedBuffer::with_data(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferHost, &vertices)
         };
 
-        let graphics_pipeline = Pipeline::graphics(vk_ctx, GraphicsPipelineCreateInfo {
+        // Grown to fit the tiles once they get rendered.
+        let samples = RenderTargets::new(
+            vk_ctx.clone(),
+            vk::Extent2D { width: camera.sqrt_samples, height: camera.sqrt_samples },
+            true,
+        );
+
+        let shading_pipeline = Pipeline::graphics(vk_ctx.clone(), GraphicsPipelineCreateInfo {
             vertex_shader:           LIGHTING_VERTEX_SHADER,
             fragment_shader:         LIGHTING_FRAGMENT_SHADER,
-            color_attachment_infos:  vec![ColorAttachmentInfo {
-                format:           vk::Format::R8G8B8A8_UNORM,
-                color_write_mask: vk::ColorComponentFlags::all(),
-                blend_enable:     false,
-            }],
+            color_attachment_infos:  RenderTargets::color_attachment_infos(),
             colour_blending_info:    vk::PipelineColorBlendStateCreateInfoBuilder::new().logic_op_enable(false),
             push_constant_ranges:    vec![vk::PushConstantRangeBuilder::new()
                 .offset(0)
-                .size(std::mem::size_of::<RtPushConstants>() as u32)
+                .size(std::mem::size_of::<PushConstants>() as u32)
                 .stage_flags(vk::ShaderStageFlags::FRAGMENT)],
             input_assembly:          vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
                 .topology(vk::PrimitiveTopology::TRIANGLE_STRIP)
@@ -166,6 +184,105 @@ impl LightingPass {
                                 .buffer(scene_buffers.triangles_buffer.buffer)
                                 .range(vk::WHOLE_SIZE),
                         ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.bvh_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.bvh_indices_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.vertex_positions_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.vertex_normals_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.vertex_texcoords_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.triangle_indices_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.lights_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.emitters_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::image(
+                            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorImageInfoBuilder::new()
+                                .image_view(scene_buffers.environment_image.view)
+                                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+                            1,
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::UNIFORM_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.environment_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.environment_distribution_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.path_stats_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.triangle_motion_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
+                        DescriptorBindingCreateInfo::buffer(
+                            vk::DescriptorType::STORAGE_BUFFER,
+                            vk::ShaderStageFlags::FRAGMENT,
+                            vk::DescriptorBufferInfoBuilder::new()
+                                .buffer(scene_buffers.triangle_objects_buffer.buffer)
+                                .range(vk::WHOLE_SIZE),
+                        ),
                     ],
                 },
             ],
@@ -179,23 +296,82 @@ impl LightingPass {
             enable_depth_testing:    false,
         });
 
-        Self { vertex_buffer, output_extent, graphics_pipeline }
+        let resolve_pipeline = Pipeline::graphics(vk_ctx, GraphicsPipelineCreateInfo {
+            vertex_shader:           LIGHTING_VERTEX_SHADER,
+            fragment_shader:         RESOLVE_FRAGMENT_SHADER,
+            color_attachment_infos:  RenderTargets::color_attachment_infos(),
+            colour_blending_info:    vk::PipelineColorBlendStateCreateInfoBuilder::new().logic_op_enable(false),
+            push_constant_ranges:    vec![vk::PushConstantRangeBuilder::new()
+                .offset(0)
+                .size(std::mem::size_of::<PushConstants>() as u32)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)],
+            input_assembly:          vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
+                .topology(vk::PrimitiveTopology::TRIANGLE_STRIP)
+                .primitive_restart_enable(false),
+            vertex_input_bindings:   vec![vk::VertexInputBindingDescriptionBuilder::new()
+                .binding(0)
+                .input_rate(vk::VertexInputRate::VERTEX)
+                .stride(std::mem::size_of::<Vertex>() as u32)],
+            vertex_input_attributes: vec![
+                // position
+                vk::VertexInputAttributeDescriptionBuilder::new()
+                    .binding(0)
+                    .location(0)
+                    .format(vk::Format::R32G32_SFLOAT)
+                    .offset(0),
+            ],
+            rasterisation_state:     RasterisationStateInfo {
+                cull_mode:  vk::CullModeFlags::BACK,
+                front_face: vk::FrontFace::CLOCKWISE,
+            },
+            descriptor_sets_infos:   vec![
+                DescriptorSetCreateInfo {
+                    descriptor_infos: sample_image_infos(&samples)
+                        .into_iter()
+                        .map(|info| DescriptorBindingCreateInfo::storage_image(vk::ShaderStageFlags::FRAGMENT, info))
+                        .collect(),
+                },
+                DescriptorSetCreateInfo {
+                    descriptor_infos: vec![DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new().buffer(camera_buffer.buffer).range(vk::WHOLE_SIZE),
+                    )],
+                },
+            ],
+            sampler_infos:           vec![],
+            enable_depth_testing:    false,
+        });
+
+        Self {
+            vertex_buffer,
+            output_extent: camera.image_extent_2d(),
+            sqrt_samples: camera.sqrt_samples,
+            margin: camera.filter_margin(),
+            samples,
+            shading_pipeline,
+            resolve_pipeline,
+        }
     }
 
     pub fn destroy(&self, device: &DeviceLoader) {
         self.vertex_buffer.destroy();
-        self.graphics_pipeline.destroy(device);
+        self.samples.destroy(device);
+        self.shading_pipeline.destroy(device);
+        self.resolve_pipeline.destroy(device);
     }
 
-    pub fn update_output(&mut self, device: &DeviceLoader, extent: vk::Extent2D, gbuffers: &GBuffers) {
-        self.output_extent = extent;
+    pub fn update_output(&mut self, device: &DeviceLoader, camera: &Camera, gbuffers: &GBuffers) {
+        self.output_extent = camera.image_extent_2d();
+        self.sqrt_samples = camera.sqrt_samples;
+        self.margin = camera.filter_margin();
 
         let write = vk::WriteDescriptorSetBuilder::new()
-            .dst_set(self.graphics_pipeline.descriptor_sets[0])
+            .dst_set(self.shading_pipeline.descriptor_sets[0])
             .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
         let info = vk::DescriptorImageInfoBuilder::new()
             .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .sampler(self.graphics_pipeline.samplers[0]);
+            .sampler(self.shading_pipeline.samplers[0]);
         let image_infos = vec![
             info.image_view(gbuffers.out_positions.view),
             info.image_view(gbuffers.out_normals.view),
@@ -208,69 +384,151 @@ impl LightingPass {
         }
     }
 
-    pub fn render(&self, vk_ctx: VulkanContext, push_constants: &RtPushConstants, target: &AllocatedImage) {
+    /// Grows the samples to fit those of a tile of the given size along with its margin.
+    fn reserve_samples(&mut self, vk_ctx: VulkanContext, tile_extent: vk::Extent2D, with_aovs: bool) {
+        let extent = self.samples.colour.extent;
+        let width = (tile_extent.width + 2 * self.margin) * self.sqrt_samples;
+        let height = (tile_extent.height + 2 * self.margin) * self.sqrt_samples;
+        if extent.width >= width && extent.height >= height && self.samples.aovs.is_some() == with_aovs {
+            return;
+        }
+        self.samples.destroy(&vk_ctx.device);
+        self.samples = RenderTargets::new(
+            vk_ctx.clone(),
+            vk::Extent2D { width: width.max(extent.width), height: height.max(extent.height) },
+            with_aovs,
+        );
+
+        let image_infos = sample_image_infos(&self.samples);
+        let writes: Vec<_> = image_infos
+            .iter()
+            .enumerate()
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSetBuilder::new()
+                    .dst_set(self.resolve_pipeline.descriptor_sets[0])
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(std::slice::from_ref(info))
+            })
+            .collect();
+        unsafe {
+            vk_ctx.device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    /// Shades one tile of the image into `targets`, which must have been prepared with
+    /// [`eruptrace_vk::tiles::begin_tiled_render`].
+    pub fn render_tile(
+        &mut self,
+        vk_ctx: VulkanContext,
+        push_constants: &RtPushConstants,
+        targets: &RenderTargets,
+        tile: vk::Rect2D,
+    ) -> Result<(), vk::Result> {
+        self.reserve_samples(vk_ctx.clone(), tile.extent, targets.aovs.is_some());
+        let push_constants = PushConstants {
+            ray_tracing:   *push_constants,
+            sample_origin: [tile.offset.x as u32 * self.sqrt_samples, tile.offset.y as u32 * self.sqrt_samples],
+            margin:        self.margin,
+        };
+        let samples_extent = vk::Extent2D {
+            width:  (tile.extent.width + 2 * self.margin) * self.sqrt_samples,
+            height: (tile.extent.height + 2 * self.margin) * self.sqrt_samples,
+        };
+
         command::immediate_submit(vk_ctx, |device, command_buffer| unsafe {
-            command::set_scissor_and_viewport(device, command_buffer, self.output_extent);
+            let draw = |pipeline: &Pipeline| {
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline);
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pipeline.layout,
+                    0,
+                    &pipeline.descriptor_sets,
+                    &[],
+                );
+                device.cmd_push_constants(
+                    command_buffer,
+                    pipeline.layout,
+                    vk::ShaderStageFlags::FRAGMENT,
+                    0,
+                    std::mem::size_of::<PushConstants>() as u32,
+                    &push_constants as *const PushConstants as *const c_void,
+                );
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buffer], &[0]);
+                device.cmd_draw(command_buffer, 4, 1, 0, 0);
+            };
+            let samples_barrier = |barrier: vk::ImageMemoryBarrier2Builder| {
+                let barriers: Vec<_> = self
+                    .samples
+                    .images()
+                    .into_iter()
+                    .map(|sample| barrier.image(sample.image).subresource_range(sample.subresource_range))
+                    .collect();
+                device.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfoBuilder::new().image_memory_barriers(&barriers),
+                );
+            };
+            let barrier = vk::ImageMemoryBarrier2Builder::new()
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
 
-            device.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfoBuilder::new().image_memory_barriers(&[vk::ImageMemoryBarrier2Builder::new()
-                    .src_stage_mask(vk::PipelineStageFlags2::NONE)
+            // Each texel of the tile's part of the G-buffer is shaded once.
+            samples_barrier(
+                barrier
+                    .src_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
                     .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-                    .src_access_mask(vk::AccessFlags2::NONE)
+                    .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_READ)
                     .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
                     .old_layout(vk::ImageLayout::UNDEFINED)
-                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .image(target.image)
-                    .subresource_range(target.subresource_range)]),
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
             );
-
+            command::set_scissor_and_viewport(device, command_buffer, samples_extent);
             device.cmd_begin_rendering(
                 command_buffer,
                 &vk::RenderingInfoBuilder::new()
-                    .color_attachments(&[vk::RenderingAttachmentInfoBuilder::new()
-                        .image_view(target.view)
-                        .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                        .clear_value(vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } })
-                        .load_op(vk::AttachmentLoadOp::CLEAR)
-                        .store_op(vk::AttachmentStoreOp::STORE)
-                        .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)])
+                    .color_attachments(&self.samples.create_colour_attachment_infos())
                     .layer_count(1)
-                    .render_area(vk::Rect2D { offset: Default::default(), extent: self.output_extent }),
+                    .render_area(vk::Rect2D { offset: Default::default(), extent: samples_extent }),
             );
-            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.graphics_pipeline.pipeline);
-            device.cmd_bind_descriptor_sets(
-                command_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                self.graphics_pipeline.layout,
-                0,
-                &self.graphics_pipeline.descriptor_sets,
-                &[],
-            );
-            device.cmd_push_constants(
-                command_buffer,
-                self.graphics_pipeline.layout,
-                vk::ShaderStageFlags::FRAGMENT,
-                0,
-                std::mem::size_of::<RtPushConstants>() as u32,
-                push_constants as *const RtPushConstants as *const c_void,
-            );
-            device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buffer], &[0]);
-            device.cmd_draw(command_buffer, 4, 1, 0, 0);
+            draw(&self.shading_pipeline);
             device.cmd_end_rendering(command_buffer);
 
-            device.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfoBuilder::new().image_memory_barriers(&[vk::ImageMemoryBarrier2Builder::new()
+            // Then the pixels are resolved from the samples within their filter's radius.
+            samples_barrier(
+                barrier
                     .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
-                    .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE_KHR)
-                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                    .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                    .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_READ)
                     .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-                    .image(target.image)
-                    .subresource_range(target.subresource_range)]),
+                    .new_layout(vk::ImageLayout::GENERAL),
             );
-        });
+            command::set_tile_scissor_and_viewport(device, command_buffer, self.output_extent, tile);
+            device.cmd_begin_rendering(
+                command_buffer,
+                &vk::RenderingInfoBuilder::new()
+                    .color_attachments(&targets.create_colour_attachment_infos())
+                    .layer_count(1)
+                    .render_area(tile),
+            );
+            draw(&self.resolve_pipeline);
+            device.cmd_end_rendering(command_buffer);
+        })
     }
 }
+
+/// Samples read by the resolve pass, where the colours stand in for the AOVs which aren't rendered, and which it
+/// doesn't read either. The indirect light is worked out from the resolved colours instead.
+fn sample_image_infos(samples: &RenderTargets) -> Vec<vk::DescriptorImageInfoBuilder<'static>> {
+    let slots = samples.slots();
+    [0, 1, 2, 3, 4, 5, 7]
+        .into_iter()
+        .map(|slot| {
+            vk::DescriptorImageInfoBuilder::new()
+                .image_view(slots[slot].unwrap_or(&samples.colour).view)
+                .image_layout(vk::ImageLayout::GENERAL)
+        })
+        .collect()
+}
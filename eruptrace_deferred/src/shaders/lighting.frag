@@ -3,6 +3,13 @@
 #include <structs.glsl>
 
 layout(location = 0) out vec4 fragColour;
+layout(location = 1) out vec4 fragAlbedo;
+layout(location = 2) out vec4 fragNormalDepth;
+layout(location = 3) out vec4 fragPosition;
+layout(location = 4) out vec4 fragIds;
+layout(location = 5) out vec4 fragDirect;
+layout(location = 6) out vec4 fragIndirect;
+layout(location = 7) out vec4 fragEmission;
 
 layout(set = 0, binding = 0) uniform sampler2D inPositions;
 layout(set = 0, binding = 1) uniform sampler2D inNormals;
@@ -15,10 +22,26 @@ layout(set = 1, binding = 2) uniform CameraUniform {
     vec4 horizontal;
     vec4 vertical;
     vec4 bottomLeft;
+    vec4 lensHorizontal;
+    vec4 lensVertical;
+    vec4 forward;
     vec2 imgSize;
     vec2 imgSizeInv;
+    vec2 regionOffset;
+    vec2 regionScale;
     uint sqrtSamples;
     uint maxReflections;
+    float lensRadius;
+    uint blades;
+    float bladeRotation;
+    uint projection;
+    float verticalFov;
+    float shutterOpen;
+    float shutterClose;
+    uint pixelFilter;
+    float filterRadius;
+    // Factor of the light reaching the camera given by its exposure settings.
+    float exposure;
 } camera;
 layout(set = 1, binding = 3, std140) readonly buffer BIH {
     BihNode bihNodes[];
@@ -29,57 +52,144 @@ layout(set = 1, binding = 4, std140) readonly buffer MaterialData {
 layout(set = 1, binding = 5, std140) readonly buffer TriangleData {
     Triangle triangles[];
 };
+layout(set = 1, binding = 6, std140) readonly buffer BVH {
+    BvhNode bvhNodes[];
+};
+layout(set = 1, binding = 7, std430) readonly buffer BVHIndices {
+    uint bvhTriangleIndices[];
+};
+layout(set = 1, binding = 8, std430) readonly buffer VertexPositions {
+    float vertexPositions[];
+};
+layout(set = 1, binding = 9, std430) readonly buffer VertexNormals {
+    uint vertexNormals[];
+};
+layout(set = 1, binding = 10, std430) readonly buffer VertexTexcoords {
+    uint vertexTexcoords[];
+};
+layout(set = 1, binding = 11, std430) readonly buffer TriangleIndices {
+    uvec4 triangleIndices[];
+};
+layout(set = 1, binding = 12, std140) readonly buffer LightData {
+    Light lights[];
+};
+layout(set = 1, binding = 13, std430) readonly buffer EmitterData {
+    Emitter emitters[];
+};
+layout(set = 1, binding = 14) uniform sampler2D environmentMap;
+layout(set = 1, binding = 15) uniform EnvironmentUniform {
+    vec4 sunDirection;
+    vec4 sunRadiance;
+    float intensity;
+    float rotation;
+    uint width;
+    uint height;
+    float sunCosRadius;
+} environment;
+layout(set = 1, binding = 16, std430) readonly buffer EnvironmentDistribution {
+    float environmentCdf[];
+};
+layout(set = 1, binding = 17, std430) buffer PathStats {
+    uint pathLengthCounts[];
+};
+layout(set = 1, binding = 18, std430) readonly buffer TriangleMotion {
+    vec4 triangleMotion[];
+};
+layout(set = 1, binding = 19, std430) readonly buffer TriangleObjects {
+    uint triangleObjects[];
+};
 
 layout(push_constant) uniform Constants {
     uint nTriangles;
     uint flags;
-    uint drawBihLevel;
+    uint drawBihNode;
+    uint nLights;
+    uint nEmitters;
+    float emitterPower;
+    uint rrMinBounces;
+    uint cropRegion[4];
+    float adaptiveThreshold;
+    uint maxSamples;
+    uint samplePass;
+    // G-buffer texel shaded into the first of the samples, which hold those of one tile at a time.
+    uvec2 sampleOrigin;
+    // Pixels by which the G-buffer reaches past each side of the rendered region.
+    uint margin;
 };
 
+#include <camera.glsl>
 #include <ray_tracing.glsl>
 
-void main() {
-    vec2 imgSize = camera.imgSize * camera.sqrtSamples;
-    vec2 imgSizeInv = 1.f / imgSize;
-    float sqrtSamplesInv = 1.f / camera.sqrtSamples;
-    vec4 finalColor = vec4(0.f);
-    for (uint y = 0; y < camera.sqrtSamples; ++y) {
-        for (uint x = 0; x < camera.sqrtSamples; ++x) {
-            float u = (gl_FragCoord.x + (x * sqrtSamplesInv)) * camera.imgSizeInv.x;
-            float v = (camera.imgSize.y - gl_FragCoord.y - (y * sqrtSamplesInv)) * camera.imgSizeInv.y;
-            vec4 samplePosition = camera.bottomLeft + (u * camera.horizontal) + (v * camera.vertical);
+// Radiance arriving along the camera ray through the given point of the rendered region, in pixels from its top left
+// corner, shaded from the G-buffer texel at `gUV` when the ray can be.
+vec4 shadeSample(vec2 pixelCoords, vec2 gUV, float seed) {
+    float u = pixelCoords.x * camera.imgSizeInv.x;
+    float v = (camera.imgSize.y - pixelCoords.y) * camera.imgSizeInv.y;
+    resetPathRecords();
+    Ray ray;
+    if (!cameraRay(vec2(u, v), seed, ray)) {
+        return vec4(0.f, 0.f, 0.f, isBackgroundTransparent() ? 0.f : 1.f);
+    }
+    if (camera.lensRadius > 0.f || camera.projection > PROJECTION_ORTHOGRAPHIC || (flags & FLAG_MOTION_BLUR) != 0) {
+        // The G-buffer is rasterised through a pinhole with a linear projection at a single instant, so rays
+        // starting elsewhere on the lens, going through other projections or seeing moving meshes are traced.
+        return trace(ray, vec4(1.f), 0.f, 0);
+    }
+    vec3 rayDirection = ray.direction;
+
+    vec4 position = texture(inPositions, gUV);
+    bool hitOccured = position.w == 1.f;
+    if (hitOccured) {
+        // Geometry
+        vec4 material = texture(inMaterials, gUV);
+        vec3 normal = texture(inNormals, gUV).xyz;
+        float dotRayNorm = dot(rayDirection, normal);
 
-            vec3 rayDirection = (samplePosition - camera.position).xyz;
+        Hit hit;
+        hit.position = position.xyz;
+        hit.normal = normal;
+        // The G-buffer doesn't keep the geometric normals, so the interpolated ones have to do.
+        hit.geometricNormal = dotRayNorm < 0.f ? normal : -normal;
+        hit.texCoords = material.xy;
+        hit.incidental = rayDirection;
+        hit.materialIndex = uint(material.z);
+        hit.bFrontFace = dotRayNorm < 0.f;
+        hit.time = ray.time;
 
-            vec2 gUV = ((gl_FragCoord.xy * camera.sqrtSamples) + vec2(x, y)) * imgSizeInv;
-            vec4 position = texture(inPositions, gUV);
-            bool hitOccured = position.w == 1.f;
-            if (hitOccured) {
-                // Geometry
-                vec4 material = texture(inMaterials, gUV);
-                vec3 normal = texture(inNormals, gUV).xyz;
-                float dotRayNorm = dot(rayDirection, normal);
+        recordFirstHit(hit, distance(hit.position, ray.origin), uint(material.w));
+        return shade(hit);
+    } else {
+        // Environment
+        recordPathLength(1);
+        if (isBackgroundTransparent()) {
+            return vec4(0.f);
+        }
+        vec3 rayDir = normalize(rayDirection);
+        vec3 environmentLight = sampleEnvironment(rayDir) + sampleSunDisc(rayDir);
+        recordLight(environmentLight, 0);
+        return vec4(environmentLight, 1.f);
+    }
+}
 
-                Hit hit;
-                hit.position = position.xyz;
-                hit.normal = normal;
-                hit.texCoords = material.xy;
-                hit.incidental = rayDirection;
-                hit.materialIndex = uint(material.z);
-                hit.bFrontFace = dotRayNorm < 0.f;
+// Shades the fragment's G-buffer texel as one sample, which the resolve pass weights into every pixel whose filter
+// reaches it.
+void main() {
+    vec2 texelCentre = vec2(sampleOrigin) + gl_FragCoord.xy;
+    vec2 pixelCoords = (texelCentre / float(camera.sqrtSamples)) - float(margin);
+    // Each sample of a pixel gets its own random numbers.
+    randomCoords = imagePixelCoords(pixelCoords);
 
-                Scattering initialScattering;
-                if (scatter(hit, initialScattering)) {
-                    finalColor += initialScattering.color * trace(initialScattering.newRay);
-                } else {
-                    finalColor += initialScattering.color;
-                }
-            } else {
-                // Sky
-                vec3 rayDir = normalize(rayDirection);
-                finalColor += sampleTexture(mappingOnUnitSphere(rayDir), 0);
-            }
+    // Only the pixels within the crop region get resolved.
+    if ((flags & FLAG_CROP) != 0) {
+        vec2 reachMin = vec2(cropRegion[0], cropRegion[1]) + 0.5f - camera.filterRadius;
+        vec2 reachMax = vec2(cropRegion[0] + cropRegion[2], cropRegion[1] + cropRegion[3]) - 0.5f + camera.filterRadius;
+        if (any(lessThan(pixelCoords, reachMin)) || any(greaterThan(pixelCoords, reachMax))) {
+            writeOutputs(emptyPixelSums());
+            return;
         }
     }
-    fragColour = sqrt(finalColor / float(camera.sqrtSamples * camera.sqrtSamples));
+
+    PixelSums sums = emptyPixelSums();
+    addSample(sums, shadeSample(pixelCoords, texelCentre / vec2(textureSize(inPositions, 0)), 0.25f), 1.f);
+    writeOutputs(sums);
 }
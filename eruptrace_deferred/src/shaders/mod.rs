@@ -2,3 +2,4 @@ pub static MESH_VERTEX_SHADER: &[u8] = include_bytes!("mesh_vert.spv");
 pub static MESH_FRAGMENT_SHADER: &[u8] = include_bytes!("mesh_frag.spv");
 pub static LIGHTING_VERTEX_SHADER: &[u8] = include_bytes!("lighting_vert.spv");
 pub static LIGHTING_FRAGMENT_SHADER: &[u8] = include_bytes!("lighting_frag.spv");
+pub static RESOLVE_FRAGMENT_SHADER: &[u8] = include_bytes!("resolve_frag.spv");
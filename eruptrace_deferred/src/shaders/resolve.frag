@@ -0,0 +1,154 @@
+#version 450
+
+layout(location = 0) out vec4 fragColour;
+layout(location = 1) out vec4 fragAlbedo;
+layout(location = 2) out vec4 fragNormalDepth;
+layout(location = 3) out vec4 fragPosition;
+layout(location = 4) out vec4 fragIds;
+layout(location = 5) out vec4 fragDirect;
+layout(location = 6) out vec4 fragIndirect;
+layout(location = 7) out vec4 fragEmission;
+
+// Samples shaded by the lighting pass, which already include the exposure.
+layout(set = 0, binding = 0, rgba32f) uniform readonly image2D sampleColours;
+layout(set = 0, binding = 1, rgba32f) uniform readonly image2D sampleAlbedos;
+layout(set = 0, binding = 2, rgba32f) uniform readonly image2D sampleNormalDepths;
+layout(set = 0, binding = 3, rgba32f) uniform readonly image2D samplePositions;
+layout(set = 0, binding = 4, rgba32f) uniform readonly image2D sampleIds;
+layout(set = 0, binding = 5, rgba32f) uniform readonly image2D sampleDirect;
+layout(set = 0, binding = 6, rgba32f) uniform readonly image2D sampleEmission;
+
+layout(set = 1, binding = 0) uniform CameraUniform {
+    vec4 position;
+    vec4 horizontal;
+    vec4 vertical;
+    vec4 bottomLeft;
+    vec4 lensHorizontal;
+    vec4 lensVertical;
+    vec4 forward;
+    vec2 imgSize;
+    vec2 imgSizeInv;
+    vec2 regionOffset;
+    vec2 regionScale;
+    uint sqrtSamples;
+    uint maxReflections;
+    float lensRadius;
+    uint blades;
+    float bladeRotation;
+    uint projection;
+    float verticalFov;
+    float shutterOpen;
+    float shutterClose;
+    uint pixelFilter;
+    float filterRadius;
+    // Factor of the light reaching the camera given by its exposure settings.
+    float exposure;
+} camera;
+
+layout(push_constant) uniform Constants {
+    uint nTriangles;
+    uint flags;
+    uint drawBihNode;
+    uint nLights;
+    uint nEmitters;
+    float emitterPower;
+    uint rrMinBounces;
+    uint cropRegion[4];
+    float adaptiveThreshold;
+    uint maxSamples;
+    uint samplePass;
+    // G-buffer texel shaded into the first of the samples, which hold those of one tile at a time.
+    uvec2 sampleOrigin;
+    // Pixels by which the G-buffer reaches past each side of the rendered region.
+    uint margin;
+};
+
+#include <filter.glsl>
+
+// Same as in ray_tracing.glsl, which needs the scene's bindings.
+const uint FLAG_CROP = 1 << 8;
+const uint FLAG_AOVS = 1 << 11;
+
+void writeEmptyOutputs() {
+    fragColour = vec4(0.f);
+    fragAlbedo = vec4(0.f);
+    fragNormalDepth = vec4(0.f);
+    fragPosition = vec4(0.f);
+    fragIds = vec4(-1.f, -1.f, 0.f, 0.f);
+    fragDirect = vec4(0.f);
+    fragIndirect = vec4(0.f);
+    fragEmission = vec4(0.f);
+}
+
+// Weighted average of every sample whose G-buffer texel's centre lies within the reconstruction filter's radius of
+// the pixel's centre, the same way as the pure renderer's random samples, written out like `writeOutputs`.
+void main() {
+    uvec2 pixel = uvec2(gl_FragCoord.xy);
+    uvec2 cropMin = uvec2(cropRegion[0], cropRegion[1]);
+    uvec2 cropMax = cropMin + uvec2(cropRegion[2], cropRegion[3]);
+    if ((flags & FLAG_CROP) != 0 && (any(lessThan(pixel, cropMin)) || any(greaterThanEqual(pixel, cropMax)))) {
+        writeEmptyOutputs();
+        return;
+    }
+
+    float samplesPerPixel = float(camera.sqrtSamples);
+    vec2 centre = gl_FragCoord.xy + float(margin);
+    ivec2 origin = ivec2(sampleOrigin);
+    ivec2 first = max(ivec2(ceil(((centre - camera.filterRadius) * samplesPerPixel) - 0.5f)), origin);
+    ivec2 last = min(ivec2(floor(((centre + camera.filterRadius) * samplesPerPixel) - 0.5f)),
+                     origin + imageSize(sampleColours) - 1);
+
+    vec4 colour = vec4(0.f);
+    vec3 albedo = vec3(0.f);
+    vec4 normalDepth = vec4(0.f);
+    vec3 position = vec3(0.f);
+    vec3 direct = vec3(0.f);
+    vec3 emission = vec3(0.f);
+    vec4 ids = vec4(-1.f, -1.f, 0.f, 0.f);
+    float weightSum = 0.f;
+    float maxWeight = -FLOAT_MAX;
+    for (int y = first.y; y <= last.y; ++y) {
+        for (int x = first.x; x <= last.x; ++x) {
+            float weight = filterWeight(((vec2(x, y) + 0.5f) / samplesPerPixel) - centre);
+            if (weight == 0.f) {
+                continue;
+            }
+            ivec2 at = ivec2(x, y) - origin;
+            colour += weight * imageLoad(sampleColours, at);
+            albedo += weight * imageLoad(sampleAlbedos, at).rgb;
+            normalDepth += weight * imageLoad(sampleNormalDepths, at);
+            weightSum += weight;
+            if ((flags & FLAG_AOVS) != 0) {
+                position += weight * imageLoad(samplePositions, at).xyz;
+                direct += weight * imageLoad(sampleDirect, at).rgb;
+                emission += weight * imageLoad(sampleEmission, at).rgb;
+                if (weight > maxWeight) {
+                    maxWeight = weight;
+                    ids = imageLoad(sampleIds, at);
+                }
+            }
+        }
+    }
+
+    if (weightSum == 0.f) {
+        writeEmptyOutputs();
+        return;
+    }
+    colour /= weightSum;
+    fragColour = vec4(max(colour.rgb, 0.f), clamp(colour.a, 0.f, 1.f));
+    fragAlbedo = vec4(max(albedo / weightSum, 0.f), 1.f);
+    // Normals of differently facing surfaces get shorter when averaged.
+    normalDepth /= weightSum;
+    float normalLength = length(normalDepth.xyz);
+    fragNormalDepth = vec4(normalLength > 0.f ? normalDepth.xyz / normalLength : vec3(0.f), max(normalDepth.w, 0.f));
+    if ((flags & FLAG_AOVS) != 0) {
+        direct /= weightSum;
+        emission /= weightSum;
+        fragPosition = vec4(position / weightSum, 1.f);
+        fragIds = ids;
+        fragDirect = vec4(max(direct, 0.f), 1.f);
+        // Whatever isn't emission or direct light, so that the three passes add up to the colours.
+        fragIndirect = vec4(max(colour.rgb - emission - direct, 0.f), 1.f);
+        fragEmission = vec4(max(emission, 0.f), 1.f);
+    }
+}
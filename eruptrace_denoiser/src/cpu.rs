@@ -0,0 +1,214 @@
+use crate::DenoiserSettings;
+
+/// B3 spline, from the centre tap outwards.
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Albedo below which the lighting is no longer separated from the texture.
+const MIN_ALBEDO: f32 = 1e-3;
+
+/// Filters the colours of an image `width` pixels wide the same way as the compute shader, guided by the albedos and
+/// normals with depths laid out like in the render targets.
+pub fn denoise(
+    width: usize,
+    colour: &[[f32; 4]],
+    albedo: &[[f32; 4]],
+    normal_depth: &[[f32; 4]],
+    settings: &DenoiserSettings,
+) -> Vec<[f32; 4]> {
+    assert_eq!(colour.len(), albedo.len());
+    assert_eq!(colour.len(), normal_depth.len());
+    if settings.iterations == 0 || width == 0 {
+        return colour.to_vec();
+    }
+    let height = colour.len() / width;
+
+    // Only the lighting is blurred, not the textures.
+    let albedo_factor = |i: usize, channel: usize| albedo[i][channel].max(MIN_ALBEDO);
+    let mut illumination: Vec<_> = (0..colour.len())
+        .map(|i| {
+            let [r, g, b, a] = colour[i];
+            [r / albedo_factor(i, 0), g / albedo_factor(i, 1), b / albedo_factor(i, 2), a]
+        })
+        .collect();
+
+    for iteration in 0..settings.iterations {
+        let step = 1_isize << iteration;
+        illumination = (0..colour.len())
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let mut sum = [0.0; 4];
+                let mut weight_sum = 0.0;
+                for dy in -2_isize..=2 {
+                    for dx in -2_isize..=2 {
+                        let (tap_x, tap_y) = (x + step * dx, y + step * dy);
+                        if tap_x < 0 || tap_y < 0 || tap_x >= width as isize || tap_y >= height as isize {
+                            continue;
+                        }
+                        let tap = tap_y as usize * width + tap_x as usize;
+                        let weight = KERNEL[dx.unsigned_abs()]
+                            * KERNEL[dy.unsigned_abs()]
+                            * edge_weight(
+                                illumination[i],
+                                normal_depth[i],
+                                illumination[tap],
+                                normal_depth[tap],
+                                iteration,
+                                settings,
+                            );
+                        for (s, t) in sum.iter_mut().zip(illumination[tap]) {
+                            *s += weight * t;
+                        }
+                        weight_sum += weight;
+                    }
+                }
+                // The alpha is the pixel's coverage rather than noise, so it is kept as it is.
+                match weight_sum > 0.0 {
+                    true => [sum[0] / weight_sum, sum[1] / weight_sum, sum[2] / weight_sum, illumination[i][3]],
+                    false => illumination[i],
+                }
+            })
+            .collect();
+    }
+
+    illumination
+        .into_iter()
+        .enumerate()
+        .map(|(i, [r, g, b, a])| [r * albedo_factor(i, 0), g * albedo_factor(i, 1), b * albedo_factor(i, 2), a])
+        .collect()
+}
+
+/// How much the tap is alike the filtered pixel. Pixels where nothing has been hit are only alike each other.
+fn edge_weight(
+    centre: [f32; 4],
+    centre_normal_depth: [f32; 4],
+    tap: [f32; 4],
+    tap_normal_depth: [f32; 4],
+    iteration: u32,
+    settings: &DenoiserSettings,
+) -> f32 {
+    let centre_hit = centre_normal_depth[3] > 0.0;
+    if centre_hit != (tap_normal_depth[3] > 0.0) {
+        return 0.0;
+    }
+
+    // The colours get smoother with each iteration, so they are compared ever more strictly.
+    let colour_distance: f32 = (0..3).map(|c| (centre[c] - tap[c]).powi(2)).sum();
+    let colour_sigma = settings.sigma_colour * settings.sigma_colour / (1 << iteration) as f32;
+    let mut weight = (-colour_distance / colour_sigma).exp();
+    if centre_hit {
+        let cos_normals: f32 = (0..3).map(|c| centre_normal_depth[c] * tap_normal_depth[c]).sum();
+        weight *= cos_normals.max(0.0).powf(settings.sigma_normal);
+        let depth_difference = (centre_normal_depth[3] - tap_normal_depth[3]).abs();
+        weight *= (-depth_difference / (settings.sigma_depth * centre_normal_depth[3])).exp();
+    }
+    weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 32;
+
+    /// Deterministic pseudo-random values between -1 and 1.
+    fn noise(i: usize) -> f32 {
+        ((i as f32 * 12.9898).sin() * 43758.547).fract()
+    }
+
+    fn variance(values: impl Iterator<Item = f32> + Clone) -> f32 {
+        let count = values.clone().count() as f32;
+        let mean = values.clone().sum::<f32>() / count;
+        values.map(|v| (v - mean).powi(2)).sum::<f32>() / count
+    }
+
+    /// A plane facing the camera at the given depth, seen by every pixel.
+    fn plane(depth: f32) -> Vec<[f32; 4]> {
+        vec![[0.0, 0.0, 1.0, depth]; SIZE * SIZE]
+    }
+
+    fn assert_close(actual: &[[f32; 4]], expected: &[[f32; 4]], tolerance: f32) {
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            for c in 0..4 {
+                assert!((a[c] - e[c]).abs() <= tolerance, "pixel {i} is {a:?}, expected {e:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn no_iterations_keep_the_image() {
+        let colour: Vec<_> = (0..SIZE * SIZE).map(|i| [noise(i).abs(), 0.5, 0.25, 1.0]).collect();
+        let settings = DenoiserSettings { iterations: 0, ..Default::default() };
+        let denoised = denoise(SIZE, &colour, &vec![[1.0; 4]; SIZE * SIZE], &plane(1.0), &settings);
+        assert_eq!(denoised, colour);
+    }
+
+    #[test]
+    fn uniform_image_is_unchanged() {
+        let colour = vec![[0.3, 0.6, 0.9, 1.0]; SIZE * SIZE];
+        let albedo = vec![[0.5, 0.5, 0.5, 1.0]; SIZE * SIZE];
+        let denoised = denoise(SIZE, &colour, &albedo, &plane(2.0), &DenoiserSettings::default());
+        assert_close(&denoised, &colour, 1e-5);
+    }
+
+    #[test]
+    fn noise_on_a_flat_surface_is_reduced() {
+        let colour: Vec<_> = (0..SIZE * SIZE).map(|i| [0.5 + 0.2 * noise(i), 0.5, 0.5, 1.0]).collect();
+        let albedo = vec![[1.0; 4]; SIZE * SIZE];
+        let denoised = denoise(SIZE, &colour, &albedo, &plane(1.0), &DenoiserSettings::default());
+
+        let before = variance(colour.iter().map(|c| c[0]));
+        let after = variance(denoised.iter().map(|c| c[0]));
+        assert!(after < before / 10.0, "variance went from {before} to {after}");
+    }
+
+    #[test]
+    fn textures_stay_sharp() {
+        // Evenly lit checkerboard, which is all albedo and no noise.
+        let albedo: Vec<_> = (0..SIZE * SIZE)
+            .map(|i| match ((i % SIZE) / 4 + (i / SIZE) / 4) % 2 {
+                0 => [0.1, 0.1, 0.1, 1.0],
+                _ => [0.9, 0.9, 0.9, 1.0],
+            })
+            .collect();
+        let colour = albedo.clone();
+        let denoised = denoise(SIZE, &colour, &albedo, &plane(1.0), &DenoiserSettings::default());
+        assert_close(&denoised, &colour, 1e-5);
+    }
+
+    #[test]
+    fn depth_edges_are_kept() {
+        // Two surfaces of different brightness, one behind the other, meeting in the middle of the image.
+        let near = |i: usize| i % SIZE < SIZE / 2;
+        let colour: Vec<_> =
+            (0..SIZE * SIZE).map(|i| if near(i) { [0.2, 0.2, 0.2, 1.0] } else { [0.8, 0.8, 0.8, 1.0] }).collect();
+        let normal_depth: Vec<_> =
+            (0..SIZE * SIZE).map(|i| if near(i) { [0.0, 0.0, 1.0, 1.0] } else { [0.0, 0.0, 1.0, 2.0] }).collect();
+        let albedo = vec![[1.0; 4]; SIZE * SIZE];
+        let denoised = denoise(SIZE, &colour, &albedo, &normal_depth, &DenoiserSettings::default());
+        assert_close(&denoised, &colour, 1e-3);
+    }
+
+    #[test]
+    fn background_is_not_mixed_with_geometry() {
+        let hit = |i: usize| (i / SIZE) < SIZE / 2;
+        let colour: Vec<_> =
+            (0..SIZE * SIZE).map(|i| if hit(i) { [0.5, 0.5, 0.5, 1.0] } else { [0.6, 0.7, 1.0, 1.0] }).collect();
+        let normal_depth: Vec<_> =
+            (0..SIZE * SIZE).map(|i| if hit(i) { [0.0, 1.0, 0.0, 3.0] } else { [0.0; 4] }).collect();
+        let albedo: Vec<_> = (0..SIZE * SIZE).map(|i| if hit(i) { [1.0; 4] } else { [0.0; 4] }).collect();
+        let denoised = denoise(SIZE, &colour, &albedo, &normal_depth, &DenoiserSettings::default());
+        assert_close(&denoised, &colour, 1e-5);
+    }
+
+    #[test]
+    fn coverage_is_kept() {
+        // A surface with a transparent background, with the pixels along the edge partially covered.
+        let alpha = |i: usize| ((i % SIZE) as f32 / 4.0).clamp(0.0, 1.0);
+        let colour: Vec<_> = (0..SIZE * SIZE).map(|i| [0.5 + 0.2 * noise(i), 0.5, 0.5, alpha(i)]).collect();
+        let albedo = vec![[1.0; 4]; SIZE * SIZE];
+        let denoised = denoise(SIZE, &colour, &albedo, &plane(1.0), &DenoiserSettings::default());
+        for (d, c) in denoised.iter().zip(&colour) {
+            assert_eq!(d[3], c[3]);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+pub mod cpu;
+pub mod shaders;
+
+use std::ffi::c_void;
+
+use erupt::{vk, DeviceLoader};
+use eruptrace_vk::{
+    command,
+    pipeline::{ComputePipelineCreateInfo, DescriptorBindingCreateInfo, DescriptorSetCreateInfo, Pipeline},
+    push_constants::DenoiserPushConstants,
+    AllocatedImage,
+    RenderTargets,
+    VulkanContext,
+};
+use itertools::Itertools;
+
+use crate::shaders::ATROUS_COMPUTE_SHADER;
+
+/// Width and height of the compute shader's work groups.
+const WORK_GROUP_SIZE: u32 = 8;
+
+/// Parameters of the edge-avoiding à-trous wavelet filter. The sigmas control how quickly the weights of its taps fall
+/// off as they get less alike the filtered pixel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DenoiserSettings {
+    /// Number of passes of the filter, each of which spreads its taps twice as far apart as the previous one.
+    pub iterations:   u32,
+    /// Difference of the lighting, with the albedo divided out of the colours.
+    pub sigma_colour: f32,
+    /// Exponent of the cosine of the angle between the normals.
+    pub sigma_normal: f32,
+    /// Difference of the depths relative to the filtered pixel's depth.
+    pub sigma_depth:  f32,
+}
+
+impl Default for DenoiserSettings {
+    fn default() -> Self {
+        Self { iterations: 5, sigma_colour: 1.0, sigma_normal: 128.0, sigma_depth: 0.1 }
+    }
+}
+
+/// Blurs the noise out of the rendered colours while keeping the edges given by the other render targets.
+pub struct Denoiser {
+    /// Bound to the pipeline until the first images are denoised.
+    placeholder_image: AllocatedImage,
+    compute_pipeline:  Pipeline,
+}
+
+impl Denoiser {
+    pub fn new(vk_ctx: VulkanContext) -> Self {
+        let placeholder_image = storage_image(vk_ctx.clone(), vk::Extent3D { width: 1, height: 1, depth: 1 });
+
+        let storage_image_binding = || {
+            DescriptorBindingCreateInfo::storage_image(
+                vk::ShaderStageFlags::COMPUTE,
+                vk::DescriptorImageInfoBuilder::new()
+                    .image_view(placeholder_image.view)
+                    .image_layout(vk::ImageLayout::GENERAL),
+            )
+        };
+        let compute_pipeline = Pipeline::compute(vk_ctx, ComputePipelineCreateInfo {
+            compute_shader:        ATROUS_COMPUTE_SHADER,
+            push_constant_ranges:  vec![vk::PushConstantRangeBuilder::new()
+                .offset(0)
+                .size(std::mem::size_of::<DenoiserPushConstants>() as u32)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)],
+            descriptor_sets_infos: vec![DescriptorSetCreateInfo {
+                descriptor_infos: vec![
+                    // colour
+                    storage_image_binding(),
+                    // scratch
+                    storage_image_binding(),
+                    // albedo
+                    storage_image_binding(),
+                    // normal and depth
+                    storage_image_binding(),
+                ],
+            }],
+            sampler_infos:         vec![],
+        });
+
+        Self { placeholder_image, compute_pipeline }
+    }
+
+    pub fn destroy(&self, device: &DeviceLoader) {
+        self.placeholder_image.destroy(device);
+        self.compute_pipeline.destroy(device);
+    }
+
+    /// Filters the colour target within `area`, leaving the rest of it as it is. The targets must be ready to be copied
+    /// from, as left by [`eruptrace_vk::tiles::finish_tiled_render`], and are left that way.
+    pub fn denoise(
+        &self,
+        vk_ctx: VulkanContext,
+        targets: &RenderTargets,
+        area: vk::Rect2D,
+        settings: &DenoiserSettings,
+    ) -> Result<(), vk::Result> {
+        if settings.iterations == 0 {
+            return Ok(());
+        }
+
+        let scratch_image = storage_image(vk_ctx.clone(), targets.colour.extent);
+        self.bind_images(&vk_ctx.device, targets, &scratch_image);
+
+        let result = command::immediate_submit(vk_ctx.clone(), |device, command_buffer| unsafe {
+            let layout_barrier = |image: &AllocatedImage, old_layout, new_layout| {
+                vk::ImageMemoryBarrier2Builder::new()
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                    .dst_access_mask(vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE)
+                    .old_layout(old_layout)
+                    .new_layout(new_layout)
+                    .image(image.image)
+                    .subresource_range(image.subresource_range)
+            };
+
+            let barriers = targets
+                .images()
+                .into_iter()
+                .map(|target| layout_barrier(target, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::GENERAL))
+                .chain([layout_barrier(&scratch_image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL)])
+                .collect_vec();
+            device.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfoBuilder::new().image_memory_barriers(&barriers),
+            );
+
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.compute_pipeline.pipeline);
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_pipeline.layout,
+                0,
+                &self.compute_pipeline.descriptor_sets,
+                &[],
+            );
+            for iteration in 0..settings.iterations {
+                let push_constants = DenoiserPushConstants {
+                    area: [area.offset.x as u32, area.offset.y as u32, area.extent.width, area.extent.height],
+                    iteration,
+                    iterations: settings.iterations,
+                    sigma_colour: settings.sigma_colour,
+                    sigma_normal: settings.sigma_normal,
+                    sigma_depth: settings.sigma_depth,
+                };
+                device.cmd_push_constants(
+                    command_buffer,
+                    self.compute_pipeline.layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    std::mem::size_of::<DenoiserPushConstants>() as u32,
+                    &push_constants as *const DenoiserPushConstants as *const c_void,
+                );
+                device.cmd_dispatch(
+                    command_buffer,
+                    area.extent.width.div_ceil(WORK_GROUP_SIZE),
+                    area.extent.height.div_ceil(WORK_GROUP_SIZE),
+                    1,
+                );
+
+                // Each iteration filters the result of the previous one.
+                device.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfoBuilder::new().memory_barriers(&[vk::MemoryBarrier2Builder::new()
+                        .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                        .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER | vk::PipelineStageFlags2::COPY)
+                        .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                        .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::TRANSFER_READ)]),
+                );
+            }
+
+            if settings.iterations % 2 == 1 {
+                // The iterations alternate between writing into the scratch image and into the colour target, so an
+                // odd number of them leaves the result in the scratch image.
+                let subresource = vk::ImageSubresourceLayersBuilder::new()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1)
+                    .build();
+                let offset = vk::Offset3D { x: area.offset.x, y: area.offset.y, z: 0 };
+                device.cmd_copy_image2(
+                    command_buffer,
+                    &vk::CopyImageInfo2Builder::new()
+                        .src_image(scratch_image.image)
+                        .src_image_layout(vk::ImageLayout::GENERAL)
+                        .dst_image(targets.colour.image)
+                        .dst_image_layout(vk::ImageLayout::GENERAL)
+                        .regions(&[vk::ImageCopy2Builder::new()
+                            .src_subresource(subresource)
+                            .src_offset(offset)
+                            .dst_subresource(subresource)
+                            .dst_offset(offset)
+                            .extent(vk::Extent3D {
+                                width:  area.extent.width,
+                                height: area.extent.height,
+                                depth:  1,
+                            })]),
+                );
+            }
+
+            let barriers = targets
+                .images()
+                .into_iter()
+                .map(|target| layout_barrier(target, vk::ImageLayout::GENERAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL))
+                .collect_vec();
+            device.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfoBuilder::new().image_memory_barriers(&barriers),
+            );
+        });
+
+        scratch_image.destroy(&vk_ctx.device);
+        result
+    }
+
+    fn bind_images(&self, device: &DeviceLoader, targets: &RenderTargets, scratch_image: &AllocatedImage) {
+        let info = vk::DescriptorImageInfoBuilder::new().image_layout(vk::ImageLayout::GENERAL);
+        let image_infos = vec![
+            info.image_view(targets.colour.view),
+            info.image_view(scratch_image.view),
+            info.image_view(targets.albedo.view),
+            info.image_view(targets.normal_depth.view),
+        ];
+        let write = vk::WriteDescriptorSetBuilder::new()
+            .dst_set(self.compute_pipeline.descriptor_sets[0])
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .image_info(&image_infos);
+        unsafe {
+            device.update_descriptor_sets(&[write], &[]);
+        }
+    }
+}
+
+fn storage_image(vk_ctx: VulkanContext, extent: vk::Extent3D) -> AllocatedImage {
+    let image_info = vk::ImageCreateInfoBuilder::new()
+        .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC)
+        .format(RenderTargets::FORMAT)
+        .extent(extent)
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlagBits::_1)
+        .image_type(vk::ImageType::_2D);
+
+    let range = vk::ImageSubresourceRangeBuilder::new()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .base_array_layer(0)
+        .level_count(1)
+        .layer_count(1)
+        .build();
+
+    AllocatedImage::new(vk_ctx, image_info, None, vk::ImageViewType::_2D, range)
+}
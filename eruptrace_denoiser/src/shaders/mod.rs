@@ -0,0 +1 @@
+pub static ATROUS_COMPUTE_SHADER: &[u8] = include_bytes!("atrous_comp.spv");
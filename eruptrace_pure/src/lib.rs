@@ -9,7 +9,6 @@ use eruptrace_scene::{CameraUniform, RtSceneBuffers};
 use eruptrace_vk::{
     command,
     pipeline::{
-        ColorAttachmentInfo,
         DescriptorBindingCreateInfo,
         DescriptorSetCreateInfo,
         GraphicsPipelineCreateInfo,
@@ -19,7 +18,7 @@ use eruptrace_vk::{
     },
     push_constants::RtPushConstants,
     AllocatedBuffer,
-    AllocatedImage,
+    RenderTargets,
     VulkanContext,
 };
 use nalgebra_glm as glm;
@@ -35,9 +34,12 @@ pub struct Vertex {
 
 #[derive(Clone)]
 pub struct PureRayTracer {
-    vertex_buffer:     AllocatedBuffer<Vertex>,
-    output_extent:     vk::Extent2D,
-    graphics_pipeline: Pipeline,
+    vertex_buffer:      AllocatedBuffer<Vertex>,
+    output_extent:      vk::Extent2D,
+    /// Weighted sums, mean and variance of each pixel's samples for adaptive sampling, five entries per pixel, or eight
+    /// when the AOVs are rendered.
+    pixel_stats_buffer: AllocatedBuffer<[f32; 4]>,
+    graphics_pipeline:  Pipeline,
 }
 
 impl PureRayTracer {
@@ -61,14 +63,13 @@ impl PureRayTracer {
             AllocatedBuffer::with_data(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferHost, &vertices)
         };
 
+        // Only allocated at full size once adaptive sampling is used.
+        let pixel_stats_buffer = pixel_stats_buffer(&vk_ctx, vk::Extent2D { width: 1, height: 1 }, false);
+
         let graphics_pipeline = Pipeline::graphics(vk_ctx, GraphicsPipelineCreateInfo {
             vertex_shader:           VERTEX_SHADER,
             fragment_shader:         FRAGMENT_SHADER,
-            color_attachment_infos:  vec![ColorAttachmentInfo {
-                format:           vk::Format::R8G8B8A8_UNORM,
-                color_write_mask: vk::ColorComponentFlags::all(),
-                blend_enable:     false,
-            }],
+            color_attachment_infos:  RenderTargets::color_attachment_infos(),
             colour_blending_info:    vk::PipelineColorBlendStateCreateInfoBuilder::new().logic_op_enable(false),
             push_constant_ranges:    vec![vk::PushConstantRangeBuilder::new()
                 .offset(0)
@@ -137,6 +138,110 @@ impl PureRayTracer {
                             .buffer(scene_buffers.triangles_buffer.buffer)
                             .range(vk::WHOLE_SIZE),
                     ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.bvh_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.bvh_indices_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.vertex_positions_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.vertex_normals_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.vertex_texcoords_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.triangle_indices_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.lights_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.emitters_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::image(
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorImageInfoBuilder::new()
+                            .image_view(scene_buffers.environment_image.view)
+                            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+                        0,
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::UNIFORM_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.environment_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.environment_distribution_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.path_stats_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.triangle_motion_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new()
+                            .buffer(scene_buffers.triangle_objects_buffer.buffer)
+                            .range(vk::WHOLE_SIZE),
+                    ),
+                    DescriptorBindingCreateInfo::buffer(
+                        vk::DescriptorType::STORAGE_BUFFER,
+                        vk::ShaderStageFlags::FRAGMENT,
+                        vk::DescriptorBufferInfoBuilder::new().buffer(pixel_stats_buffer.buffer).range(vk::WHOLE_SIZE),
+                    ),
                 ],
             }],
             sampler_infos:           vec![SamplerCreateInfo {
@@ -146,11 +251,12 @@ impl PureRayTracer {
             enable_depth_testing:    false,
         });
 
-        Self { vertex_buffer, output_extent, graphics_pipeline }
+        Self { vertex_buffer, output_extent, pixel_stats_buffer, graphics_pipeline }
     }
 
     pub fn destroy(&self, device: &DeviceLoader) {
         self.vertex_buffer.destroy();
+        self.pixel_stats_buffer.destroy();
         self.graphics_pipeline.destroy(device);
     }
 
@@ -158,35 +264,54 @@ impl PureRayTracer {
         self.output_extent = extent;
     }
 
-    pub fn render(&self, vk_ctx: VulkanContext, push_constants: &RtPushConstants, target: &AllocatedImage) {
+    /// Grows the per-pixel sample statistics to fit the output image, which adaptive sampling needs.
+    pub fn reserve_pixel_stats(&mut self, vk_ctx: VulkanContext, with_aovs: bool) {
+        if self.pixel_stats_buffer.size >= pixel_stats_size(self.output_extent, with_aovs) {
+            return;
+        }
+        self.pixel_stats_buffer.destroy();
+        self.pixel_stats_buffer = pixel_stats_buffer(&vk_ctx, self.output_extent, with_aovs);
+
+        let buffer_info =
+            vk::DescriptorBufferInfoBuilder::new().buffer(self.pixel_stats_buffer.buffer).range(vk::WHOLE_SIZE);
+        let write = vk::WriteDescriptorSetBuilder::new()
+            .dst_set(self.graphics_pipeline.descriptor_sets[0])
+            .dst_binding(20)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(std::slice::from_ref(&buffer_info));
+        unsafe {
+            vk_ctx.device.update_descriptor_sets(&[write], &[]);
+        }
+    }
+
+    /// Renders one tile of the image into `targets`, which must have been prepared with
+    /// [`eruptrace_vk::tiles::begin_tiled_render`].
+    pub fn render_tile(
+        &self,
+        vk_ctx: VulkanContext,
+        push_constants: &RtPushConstants,
+        targets: &RenderTargets,
+        tile: vk::Rect2D,
+    ) -> Result<(), vk::Result> {
         command::immediate_submit(vk_ctx, |device, command_buffer| unsafe {
-            command::set_scissor_and_viewport(device, command_buffer, self.output_extent);
+            command::set_tile_scissor_and_viewport(device, command_buffer, self.output_extent, tile);
 
+            // Adaptive sampling continues from the statistics written by the previous pass over the tile.
             device.cmd_pipeline_barrier2(
                 command_buffer,
-                &vk::DependencyInfoBuilder::new().image_memory_barriers(&[vk::ImageMemoryBarrier2Builder::new()
-                    .src_stage_mask(vk::PipelineStageFlags2::NONE)
-                    .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-                    .src_access_mask(vk::AccessFlags2::NONE)
-                    .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
-                    .old_layout(vk::ImageLayout::UNDEFINED)
-                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .image(target.image)
-                    .subresource_range(target.subresource_range)]),
+                &vk::DependencyInfoBuilder::new().memory_barriers(&[vk::MemoryBarrier2Builder::new()
+                    .src_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                    .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                    .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                    .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_READ)]),
             );
 
             device.cmd_begin_rendering(
                 command_buffer,
                 &vk::RenderingInfoBuilder::new()
-                    .color_attachments(&[vk::RenderingAttachmentInfoBuilder::new()
-                        .image_view(target.view)
-                        .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                        .clear_value(vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } })
-                        .load_op(vk::AttachmentLoadOp::CLEAR)
-                        .store_op(vk::AttachmentStoreOp::STORE)
-                        .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)])
+                    .color_attachments(&targets.create_colour_attachment_infos())
                     .layer_count(1)
-                    .render_area(vk::Rect2D { offset: Default::default(), extent: self.output_extent }),
+                    .render_area(tile),
             );
             device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.graphics_pipeline.pipeline);
             device.cmd_bind_descriptor_sets(
@@ -208,19 +333,19 @@ impl PureRayTracer {
             device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buffer], &[0]);
             device.cmd_draw(command_buffer, 4, 1, 0, 0);
             device.cmd_end_rendering(command_buffer);
-
-            device.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfoBuilder::new().image_memory_barriers(&[vk::ImageMemoryBarrier2Builder::new()
-                    .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
-                    .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE_KHR)
-                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
-                    .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
-                    .image(target.image)
-                    .subresource_range(target.subresource_range)]),
-            );
-        });
+        })
     }
 }
+
+fn pixel_stats_size(extent: vk::Extent2D, with_aovs: bool) -> vk::DeviceSize {
+    let entries = if with_aovs { 8 } else { 5 };
+    (entries * extent.width * extent.height) as vk::DeviceSize * std::mem::size_of::<[f32; 4]>() as vk::DeviceSize
+}
+
+fn pixel_stats_buffer(vk_ctx: &VulkanContext, extent: vk::Extent2D, with_aovs: bool) -> AllocatedBuffer<[f32; 4]> {
+    let buffer_info = vk::BufferCreateInfoBuilder::new()
+        .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .size(pixel_stats_size(extent, with_aovs))
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    AllocatedBuffer::new(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferDevice)
+}
@@ -3,6 +3,13 @@
 #include <structs.glsl>
 
 layout(location = 0) out vec4 fragColour;
+layout(location = 1) out vec4 fragAlbedo;
+layout(location = 2) out vec4 fragNormalDepth;
+layout(location = 3) out vec4 fragPosition;
+layout(location = 4) out vec4 fragIds;
+layout(location = 5) out vec4 fragDirect;
+layout(location = 6) out vec4 fragIndirect;
+layout(location = 7) out vec4 fragEmission;
 
 layout(set = 0, binding = 0) uniform sampler2DArray textures;
 layout(set = 0, binding = 1) uniform sampler2DArray normalMaps;
@@ -11,10 +18,26 @@ layout(set = 0, binding = 2) uniform CameraUniform {
     vec4 horizontal;
     vec4 vertical;
     vec4 bottomLeft;
+    vec4 lensHorizontal;
+    vec4 lensVertical;
+    vec4 forward;
     vec2 imgSize;
     vec2 imgSizeInv;
+    vec2 regionOffset;
+    vec2 regionScale;
     uint sqrtSamples;
     uint maxReflections;
+    float lensRadius;
+    uint blades;
+    float bladeRotation;
+    uint projection;
+    float verticalFov;
+    float shutterOpen;
+    float shutterClose;
+    uint pixelFilter;
+    float filterRadius;
+    // Factor of the light reaching the camera given by its exposure settings.
+    float exposure;
 } camera;
 layout(set = 0, binding = 3, std140) readonly buffer BIH {
     BihNode bihNodes[];
@@ -25,27 +48,187 @@ layout(set = 0, binding = 4, std140) readonly buffer MaterialData {
 layout(set = 0, binding = 5, std140) readonly buffer TriangleData {
     Triangle triangles[];
 };
+layout(set = 0, binding = 6, std140) readonly buffer BVH {
+    BvhNode bvhNodes[];
+};
+layout(set = 0, binding = 7, std430) readonly buffer BVHIndices {
+    uint bvhTriangleIndices[];
+};
+layout(set = 0, binding = 8, std430) readonly buffer VertexPositions {
+    float vertexPositions[];
+};
+layout(set = 0, binding = 9, std430) readonly buffer VertexNormals {
+    uint vertexNormals[];
+};
+layout(set = 0, binding = 10, std430) readonly buffer VertexTexcoords {
+    uint vertexTexcoords[];
+};
+layout(set = 0, binding = 11, std430) readonly buffer TriangleIndices {
+    uvec4 triangleIndices[];
+};
+layout(set = 0, binding = 12, std140) readonly buffer LightData {
+    Light lights[];
+};
+layout(set = 0, binding = 13, std430) readonly buffer EmitterData {
+    Emitter emitters[];
+};
+layout(set = 0, binding = 14) uniform sampler2D environmentMap;
+layout(set = 0, binding = 15) uniform EnvironmentUniform {
+    vec4 sunDirection;
+    vec4 sunRadiance;
+    float intensity;
+    float rotation;
+    uint width;
+    uint height;
+    float sunCosRadius;
+} environment;
+layout(set = 0, binding = 16, std430) readonly buffer EnvironmentDistribution {
+    float environmentCdf[];
+};
+layout(set = 0, binding = 17, std430) buffer PathStats {
+    uint pathLengthCounts[];
+};
+layout(set = 0, binding = 18, std430) readonly buffer TriangleMotion {
+    vec4 triangleMotion[];
+};
+layout(set = 0, binding = 19, std430) readonly buffer TriangleObjects {
+    uint triangleObjects[];
+};
+layout(set = 0, binding = 20, std430) buffer PixelStats {
+    // Five entries per pixel: the filter-weighted sum of its samples, their unweighted mean with the number of samples
+    // in the last component, the sum of their squared differences from the mean with the sum of weights, and the
+    // filter-weighted sums of their first hits' albedos and of their normals with depths. Rendering the AOVs adds three
+    // more for the sums of positions, emission and direct light, with the greatest weight, the material index and the
+    // object ID in their last components.
+    vec4 pixelStats[];
+};
 
 layout(push_constant) uniform Constants {
     uint nTriangles;
     uint flags;
-    uint drawBihLevel;
+    uint drawBihNode;
+    uint nLights;
+    uint nEmitters;
+    float emitterPower;
+    uint rrMinBounces;
+    uint cropRegion[4];
+    float adaptiveThreshold;
+    uint maxSamples;
+    uint samplePass;
 };
 
+#include <camera.glsl>
 #include <ray_tracing.glsl>
 
-void main() {
+// Radiance arriving along the `i`-th camera ray spread over the reconstruction filter's footprint around the pixel,
+// and the filter's weight of it.
+vec4 pixelSample(uint i, out float weight) {
+    vec2 offset = ((2.f * vec2(rand(i), rand(i + 0.5f))) - 1.f) * camera.filterRadius;
+    weight = filterWeight(offset);
+    vec2 pixelCoords = gl_FragCoord.xy + offset;
+    resetPathRecords();
+    float u = pixelCoords.x * camera.imgSizeInv.x;
+    float v = (camera.imgSize.y - pixelCoords.y) * camera.imgSizeInv.y;
     Ray ray;
-    ray.origin = camera.position.xyz;
-    vec4 pixelColor = vec4(0.f);
+    if (cameraRay(vec2(u, v), i + 0.25f, ray)) {
+        return trace(ray, vec4(1.f), 0.f, 0);
+    }
+    return vec4(0.f, 0.f, 0.f, isBackgroundTransparent() ? 0.f : 1.f);
+}
+
+// Standard error of the pixel's mean luminance relative to the luminance itself, which is floored so that pixels dark
+// once exposed don't take the maximum number of samples. Covariance between the channels is ignored.
+float relativeError(vec3 mean, vec3 squaredDiffs, uint count) {
+    const vec3 luminanceWeights = vec3(0.2126f, 0.7152f, 0.0722f);
+    float variance = dot(squaredDiffs, luminanceWeights) / float(count - 1);
+    return sqrt(variance / float(count)) / max(dot(mean, luminanceWeights), 0.01f / camera.exposure);
+}
+
+void loadPixelStats(uint pixel, out PixelSums sums, out vec4 mean, out vec3 squaredDiffs) {
+    sums = emptyPixelSums();
+    sums.colour = pixelStats[pixel];
+    mean = pixelStats[pixel + 1];
+    squaredDiffs = pixelStats[pixel + 2].rgb;
+    sums.weight = pixelStats[pixel + 2].w;
+    sums.albedo = pixelStats[pixel + 3].rgb;
+    sums.normalDepth = pixelStats[pixel + 4];
+    if ((flags & FLAG_AOVS) != 0) {
+        sums.position = pixelStats[pixel + 5].xyz;
+        sums.maxWeight = pixelStats[pixel + 5].w;
+        sums.emission = pixelStats[pixel + 6].rgb;
+        sums.materialIndex = floatBitsToUint(pixelStats[pixel + 6].w);
+        sums.direct = pixelStats[pixel + 7].rgb;
+        sums.objectIndex = floatBitsToUint(pixelStats[pixel + 7].w);
+    }
+}
+
+void storePixelStats(uint pixel, in PixelSums sums, vec4 mean, vec3 squaredDiffs) {
+    pixelStats[pixel] = sums.colour;
+    pixelStats[pixel + 1] = mean;
+    pixelStats[pixel + 2] = vec4(squaredDiffs, sums.weight);
+    pixelStats[pixel + 3] = vec4(sums.albedo, 0.f);
+    pixelStats[pixel + 4] = sums.normalDepth;
+    if ((flags & FLAG_AOVS) != 0) {
+        pixelStats[pixel + 5] = vec4(sums.position, sums.maxWeight);
+        pixelStats[pixel + 6] = vec4(sums.emission, uintBitsToFloat(sums.materialIndex));
+        pixelStats[pixel + 7] = vec4(sums.direct, uintBitsToFloat(sums.objectIndex));
+    }
+}
+
+// Colour ramp going from blue through green to red as `t` goes from 0 to 1.
+vec3 heatmap(float t) {
+    return clamp(1.5f - abs(4.f * t - vec3(3.f, 2.f, 1.f)), 0.f, 1.f);
+}
+
+void main() {
+    randomCoords = imagePixelCoords(gl_FragCoord.xy);
+    if (isCroppedOut(gl_FragCoord.xy)) {
+        writeOutputs(emptyPixelSums());
+        return;
+    }
+
     uint samples = camera.sqrtSamples * camera.sqrtSamples;
-    for (int i = 0; i < samples; ++i) {
-        float u = (gl_FragCoord.x + rand(i)) * camera.imgSizeInv.x;
-        float v = (camera.imgSize.y - gl_FragCoord.y + rand(i + 0.5f)) * camera.imgSizeInv.y;
-        vec4 samplePosition = camera.bottomLeft + (u * camera.horizontal) + (v * camera.vertical);
-        ray.direction = (samplePosition - camera.position).xyz;
-        ray.invDirection = 1.f / ray.direction;
-        pixelColor += trace(ray);
-    }
-    fragColour = sqrt(pixelColor / float(samples));
+    if ((flags & FLAG_ADAPTIVE_SAMPLING) == 0) {
+        PixelSums sums = emptyPixelSums();
+        for (uint i = 0; i < samples; ++i) {
+            float weight;
+            vec4 color = pixelSample(i, weight);
+            addSample(sums, color, weight);
+        }
+        writeOutputs(sums);
+        return;
+    }
+
+    // Every pixel is sampled in the first pass, while later passes only add samples to pixels whose mean is still
+    // too uncertain. The error is estimated from the unweighted mean and variance, updated with Welford's algorithm.
+    uint stride = (flags & FLAG_AOVS) != 0 ? 8 : 5;
+    uint pixel = stride * (uint(gl_FragCoord.y) * uint(camera.imgSize.x) + uint(gl_FragCoord.x));
+    PixelSums sums = emptyPixelSums();
+    vec4 mean = vec4(0.f);
+    vec3 squaredDiffs = vec3(0.f);
+    if (samplePass > 0) {
+        loadPixelStats(pixel, sums, mean, squaredDiffs);
+    }
+    uint count = uint(mean.w);
+    if (count < maxSamples && (count < 2 || relativeError(mean.rgb, squaredDiffs, count) > adaptiveThreshold)) {
+        uint end = min(count + samples, maxSamples);
+        for (uint i = count; i < end; ++i) {
+            float weight;
+            vec4 color = pixelSample(i, weight);
+            addSample(sums, color, weight);
+            vec3 delta = color.rgb - mean.rgb;
+            mean.rgb += delta / float(i + 1);
+            squaredDiffs += delta * (color.rgb - mean.rgb);
+        }
+        count = end;
+        mean.w = float(count);
+        storePixelStats(pixel, sums, mean, squaredDiffs);
+    }
+
+    writeOutputs(sums);
+    if ((flags & FLAG_SAMPLE_HEATMAP) != 0) {
+        // Squared to come out as these colours once the image's gamma is applied.
+        vec3 heat = heatmap(float(count) / float(maxSamples));
+        fragColour = vec4(heat * heat, 1.f);
+    }
 }
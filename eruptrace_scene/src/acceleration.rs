@@ -0,0 +1,12 @@
+use crate::Triangle;
+
+/// A spatial hierarchy over the scene's triangles that the tracers can traverse on the GPU.
+pub trait AccelerationStructure: Sized {
+    type NodeUniform: Copy;
+
+    /// Builds the hierarchy. Implementations may reorder `triangles`, so any structure built over the same
+    /// slice beforehand has to be built again.
+    fn build(triangles: &mut [Triangle]) -> Self;
+
+    fn node_uniforms(&self) -> Vec<Self::NodeUniform>;
+}
@@ -0,0 +1,214 @@
+use std::ops::{Add, Mul, RangeInclusive, Sub};
+
+use nalgebra_glm as glm;
+use serde_json as json;
+
+use crate::{json::to_vec3, Camera, MeshTransform};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    /// Catmull-Rom spline passing through the keyframes.
+    Cubic,
+}
+
+impl From<&str> for Interpolation {
+    fn from(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "linear" => Self::Linear,
+            "cubic" => Self::Cubic,
+            _ => panic!("Invalid interpolation '{s}'."),
+        }
+    }
+}
+
+/// Values of a single property at the frames of its keyframes, held before the first and after the last one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track<T> {
+    pub keys:          Vec<(f32, T)>,
+    pub interpolation: Interpolation,
+}
+
+impl<T> Track<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    /// Collects the values of the keyframes which set the property, or returns `None` if none of them do.
+    fn from_keyframes(
+        keyframes: &[(f32, &json::Value)],
+        value: impl Fn(&json::Value) -> Option<T>,
+        interpolation: Interpolation,
+    ) -> Option<Self> {
+        let keys: Vec<_> = keyframes.iter().filter_map(|&(frame, key)| value(key).map(|v| (frame, v))).collect();
+        (!keys.is_empty()).then_some(Self { keys, interpolation })
+    }
+
+    pub fn sample(&self, frame: f32) -> T {
+        let last = self.keys.len() - 1;
+        let next = self.keys.partition_point(|&(f, _)| f <= frame);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        if next > last {
+            return self.keys[last].1;
+        }
+
+        let (i, j) = (next - 1, next);
+        let ((f0, p0), (f1, p1)) = (self.keys[i], self.keys[j]);
+        let s = (frame - f0) / (f1 - f0);
+        match self.interpolation {
+            Interpolation::Linear => p0 + (p1 - p0) * s,
+            Interpolation::Cubic => {
+                // Tangents from the neighbouring keyframes, scaled to the length of this segment so that uneven
+                // spacing of the keyframes doesn't cause overshooting.
+                let tangent = |k: usize| {
+                    let (a, b) = (k.saturating_sub(1), (k + 1).min(last));
+                    (self.keys[b].1 - self.keys[a].1) * ((f1 - f0) / (self.keys[b].0 - self.keys[a].0))
+                };
+                let (s2, s3) = (s * s, s * s * s);
+                p0 * (2.0 * s3 - 3.0 * s2 + 1.0)
+                    + tangent(i) * (s3 - 2.0 * s2 + s)
+                    + p1 * (3.0 * s2 - 2.0 * s3)
+                    + tangent(j) * (s3 - s2)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraAnimation {
+    pub position: Option<Track<glm::Vec3>>,
+    pub look_at:  Option<Track<glm::Vec3>>,
+    /// Vertical field of view, in degrees.
+    pub fov:      Option<Track<f32>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshAnimation {
+    pub mesh_index: usize,
+    /// Transform given in the mesh's description, providing the properties that aren't animated.
+    pub base:       MeshTransform,
+    pub position:   Option<Track<glm::Vec3>>,
+    pub rotation:   Option<Track<glm::Vec3>>,
+    pub scale:      Option<Track<glm::Vec3>>,
+}
+
+/// Keyframes of the camera and the meshes' transforms over a range of frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Animation {
+    pub frame_start: u32,
+    /// Last frame to render, inclusive.
+    pub frame_end:   u32,
+    pub fps:         f32,
+    pub camera:      CameraAnimation,
+    pub meshes:      Vec<MeshAnimation>,
+}
+
+impl Animation {
+    /// Reads the animation, where keyframes are placed at a `frame` or at a `time` in seconds. `meshes` are the
+    /// descriptions of the scene's meshes, which animated meshes refer to by their index, and `mesh_indices` maps
+    /// each description onto its loaded mesh. Tracks of meshes that failed to load are dropped.
+    pub fn from_json(
+        object: &json::Value,
+        meshes: &[json::Value],
+        mesh_indices: &[Option<usize>],
+    ) -> anyhow::Result<Self> {
+        let fps = object["fps"].as_f64().unwrap_or(24.0) as f32;
+        let interpolation = Interpolation::from(object["interpolation"].as_str().unwrap_or("linear"));
+
+        let as_f32 = |value: &json::Value| value.as_f64().map(|v| v as f32);
+
+        let mut last_keyframe = 0.0_f32;
+
+        let camera = {
+            let (keys, interpolation) = keyframes(&object["camera"], fps, interpolation)?;
+            last_keyframe = keys.iter().fold(last_keyframe, |last, &(frame, _)| last.max(frame));
+            CameraAnimation {
+                position: Track::from_keyframes(&keys, |k| to_vec3(&k["position"]), interpolation),
+                look_at:  Track::from_keyframes(&keys, |k| to_vec3(&k["look_at"]), interpolation),
+                fov:      Track::from_keyframes(&keys, |k| as_f32(&k["fov"]), interpolation),
+            }
+        };
+
+        let mut mesh_animations = vec![];
+        for track in object["meshes"].as_array().map_or(&vec![], |v| v) {
+            let description_index =
+                track["mesh"].as_u64().ok_or_else(|| anyhow::anyhow!("Missing animated mesh index."))? as usize;
+            let mesh = meshes
+                .get(description_index)
+                .ok_or_else(|| anyhow::anyhow!("Animated mesh index {} is out of range.", description_index))?;
+            let (keys, interpolation) = keyframes(track, fps, interpolation)?;
+            last_keyframe = keys.iter().fold(last_keyframe, |last, &(frame, _)| last.max(frame));
+            let Some(mesh_index) = mesh_indices.get(description_index).copied().flatten() else {
+                continue;
+            };
+            mesh_animations.push(MeshAnimation {
+                mesh_index,
+                base: MeshTransform::from_json(&mesh["transform"]),
+                position: Track::from_keyframes(&keys, |k| to_vec3(&k["position"]), interpolation),
+                rotation: Track::from_keyframes(&keys, |k| to_vec3(&k["rotation"]), interpolation),
+                scale: Track::from_keyframes(&keys, |k| to_vec3(&k["scale"]), interpolation),
+            });
+        }
+
+        let frame_start = object["frame_start"].as_u64().unwrap_or(0) as u32;
+        let frame_end = object["frame_end"].as_u64().map_or(last_keyframe.ceil() as u32, |f| f as u32);
+        if frame_end < frame_start {
+            anyhow::bail!("Animation ends at frame {} before it starts at frame {}.", frame_end, frame_start);
+        }
+
+        Ok(Self { frame_start, frame_end, fps, camera, meshes: mesh_animations })
+    }
+
+    pub fn frames(&self) -> RangeInclusive<u32> {
+        self.frame_start..=self.frame_end
+    }
+
+    pub fn apply_to_camera(&self, frame: f32, camera: &mut Camera) {
+        if let Some(track) = &self.camera.position {
+            camera.position = track.sample(frame);
+        }
+        if let Some(track) = &self.camera.look_at {
+            camera.look_at = track.sample(frame);
+        }
+        if let Some(track) = &self.camera.fov {
+            camera.vertical_fov = track.sample(frame);
+        }
+    }
+
+    /// Transforms of the animated meshes at the given frame, along with their indices.
+    pub fn mesh_transforms(&self, frame: f32) -> Vec<(usize, glm::Mat4x4)> {
+        self.meshes
+            .iter()
+            .map(|mesh| {
+                let sample = |track: &Option<Track<glm::Vec3>>, base| track.as_ref().map_or(base, |t| t.sample(frame));
+                let transform = MeshTransform {
+                    position: sample(&mesh.position, mesh.base.position),
+                    rotation: sample(&mesh.rotation, mesh.base.rotation),
+                    scale:    sample(&mesh.scale, mesh.base.scale),
+                };
+                (mesh.mesh_index, transform.matrix())
+            })
+            .collect()
+    }
+}
+
+/// Keyframes of a track sorted by their frames, and the track's interpolation if it overrides the animation's.
+fn keyframes(
+    track: &json::Value,
+    fps: f32,
+    interpolation: Interpolation,
+) -> anyhow::Result<(Vec<(f32, &json::Value)>, Interpolation)> {
+    let mut keyframes = vec![];
+    for key in track["keyframes"].as_array().into_iter().flatten() {
+        let frame = match (key["frame"].as_f64(), key["time"].as_f64()) {
+            (Some(frame), _) => frame as f32,
+            (None, Some(time)) => time as f32 * fps,
+            (None, None) => anyhow::bail!("Missing 'frame' or 'time' of a keyframe."),
+        };
+        keyframes.push((frame, key));
+    }
+    keyframes.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    let interpolation = track["interpolation"].as_str().map_or(interpolation, Interpolation::from);
+    Ok((keyframes, interpolation))
+}
@@ -1,24 +1,36 @@
 #![allow(non_local_definitions)]
 
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::Path,
+};
+
 use nalgebra_glm as glm;
+use serde_json as js;
 use std140::repr_std140;
 
-use crate::Triangle;
+use crate::{AccelerationStructure, Triangle};
 
-#[derive(Copy, Clone, Debug)]
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct BoundingBox {
     pub min: glm::Vec3,
     pub max: glm::Vec3,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BihNodeData {
     Branch { clip_left: f32, clip_right: f32, child_left: usize, child_right: usize },
     Leaf { triangle_index: usize, count: usize },
 }
 
 #[repr(u32)]
-#[derive(Copy, Clone, Debug, enumn::N)]
+#[derive(Copy, Clone, Debug, PartialEq, enumn::N)]
 pub enum BihNodeType {
     X    = 0,
     Y    = 1,
@@ -26,7 +38,7 @@ pub enum BihNodeType {
     Leaf = 3,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct BihNode {
     pub ty:   BihNodeType,
     pub data: BihNodeData,
@@ -43,7 +55,24 @@ pub struct BihNodeUniform {
 }
 
 #[derive(Clone, Debug)]
-pub struct Bih(pub Vec<BihNode>);
+pub struct Bih {
+    pub nodes:          Vec<BihNode>,
+    pub bounds:         BoundingBox,
+    /// SAH cost of the hierarchy right after it was last built, used to tell how much refitting degraded it.
+    pub built_sah_cost: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BihStats {
+    pub node_count:          usize,
+    pub leaf_count:          usize,
+    pub empty_leaf_count:    usize,
+    /// Number of nodes at each depth, starting from the root.
+    pub depth_histogram:     Vec<usize>,
+    /// Number of leaves holding each amount of triangles, starting from empty leaves.
+    pub leaf_size_histogram: Vec<usize>,
+    pub sah_cost:            f32,
+}
 
 enum Split {
     Leaf,
@@ -58,9 +87,26 @@ enum Split {
 }
 
 impl BoundingBox {
+    pub fn empty() -> Self {
+        Self { min: glm::vec3(f32::MAX, f32::MAX, f32::MAX), max: glm::vec3(f32::MIN, f32::MIN, f32::MIN) }
+    }
+
     pub fn centre(&self) -> glm::Vec3 {
         (self.min + self.max) * 0.5
     }
+
+    pub fn union(self, other: Self) -> Self {
+        Self { min: glm::min2(&self.min, &other.min), max: glm::max2(&self.max, &other.max) }
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size: glm::Vec3 = self.max - self.min;
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            0.0
+        } else {
+            2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+        }
+    }
 }
 
 impl Default for BihNode {
@@ -72,8 +118,8 @@ impl Default for BihNode {
 impl Bih {
     pub fn new(triangles: &mut [Triangle]) -> Self {
         let mut nodes = vec![BihNode::default()];
+        let bounds = calculate_bounds(triangles);
         if !triangles.is_empty() {
-            let bounds = calculate_bounds(triangles);
             nodes.reserve(2 * triangles.len());
             make_hierarchy(triangles, 0, bounds, 0, &mut nodes);
             nodes.shrink_to_fit();
@@ -88,7 +134,267 @@ impl Bih {
         );
         // dbg!(&nodes);
 
-        Self(nodes)
+        let mut bih = Self { nodes, bounds, built_sah_cost: 0.0 };
+        bih.built_sah_cost = bih.stats().sah_cost;
+        bih
+    }
+
+    /// Recomputes the bounds and clip planes bottom-up after the triangles have moved, keeping the structure of
+    /// the tree.
+    pub fn refit(&mut self, triangles: &[Triangle]) {
+        // Children are always stored after their parents, so iterating backwards visits them first.
+        let mut subtree_bounds = vec![BoundingBox::empty(); self.nodes.len()];
+        for index in (0..self.nodes.len()).rev() {
+            match self.nodes[index].data {
+                BihNodeData::Leaf { triangle_index, count } => {
+                    subtree_bounds[index] = calculate_bounds(&triangles[triangle_index..triangle_index + count]);
+                }
+                BihNodeData::Branch { child_left, child_right, .. } => {
+                    let axis = self.nodes[index].ty as usize;
+                    let (left_bounds, right_bounds) = (subtree_bounds[child_left], subtree_bounds[child_right]);
+                    self.nodes[index].data = BihNodeData::Branch {
+                        clip_left: left_bounds.max[axis],
+                        clip_right: right_bounds.min[axis],
+                        child_left,
+                        child_right,
+                    };
+                    subtree_bounds[index] = left_bounds.union(right_bounds);
+                }
+            }
+        }
+        self.bounds = subtree_bounds[0];
+    }
+
+    /// Range of triangles covered by the subtree under `index`.
+    pub fn triangle_range(&self, index: usize) -> Range<usize> {
+        let mut first = index;
+        while let BihNodeData::Branch { child_left, .. } = self.nodes[first].data {
+            first = child_left;
+        }
+        let mut last = index;
+        while let BihNodeData::Branch { child_right, .. } = self.nodes[last].data {
+            last = child_right;
+        }
+        match (self.nodes[first].data, self.nodes[last].data) {
+            (
+                BihNodeData::Leaf { triangle_index: start, .. },
+                BihNodeData::Leaf { triangle_index: last_start, count: last_count },
+            ) => start..last_start + last_count,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Deepest node whose subtree covers all the given triangles.
+    pub fn enclosing_node(&self, triangles: Range<usize>) -> usize {
+        let contains = |index| {
+            let range = self.triangle_range(index);
+            range.start <= triangles.start && triangles.end <= range.end
+        };
+        let mut current = 0;
+        while let BihNodeData::Branch { child_left, child_right, .. } = self.nodes[current].data {
+            if contains(child_left) {
+                current = child_left;
+            } else if contains(child_right) {
+                current = child_right;
+            } else {
+                break;
+            }
+        }
+        current
+    }
+
+    /// Builds the subtree under `index` again from scratch. The triangles it covers get reordered, the rest of the
+    /// tree keeps its shape, but nodes stored after the subtree may be shifted.
+    pub fn rebuild_subtree(&mut self, triangles: &mut [Triangle], index: usize) {
+        let BihNodeData::Branch { child_left: first_child, .. } = self.nodes[index].data else {
+            // Leaves have no clip planes, so refitting is as good as rebuilding them.
+            return;
+        };
+
+        // Descendants of a node are stored contiguously, right after its children.
+        let nodes_end = {
+            let mut end = first_child + 2;
+            let mut stack = vec![first_child, first_child + 1];
+            while let Some(current) = stack.pop() {
+                if let BihNodeData::Branch { child_left, child_right, .. } = self.nodes[current].data {
+                    end = end.max(child_right + 1);
+                    stack.extend([child_left, child_right]);
+                }
+            }
+            end
+        };
+
+        let triangle_range = self.triangle_range(index);
+        let triangles_part = &mut triangles[triangle_range.clone()];
+        let mut sub_nodes = vec![BihNode::default()];
+        make_hierarchy(triangles_part, triangle_range.start, calculate_bounds(triangles_part), 0, &mut sub_nodes);
+
+        let shift = |child: &mut usize, from: usize, to: usize| *child = *child - from + to;
+        for node in sub_nodes.iter_mut() {
+            if let BihNodeData::Branch { child_left, child_right, .. } = &mut node.data {
+                shift(child_left, 1, first_child);
+                shift(child_right, 1, first_child);
+            }
+        }
+        let new_end = first_child + sub_nodes.len() - 1;
+        for node in self.nodes.iter_mut() {
+            if let BihNodeData::Branch { child_left, child_right, .. } = &mut node.data {
+                if *child_left >= nodes_end {
+                    shift(child_left, nodes_end, new_end);
+                    shift(child_right, nodes_end, new_end);
+                }
+            }
+        }
+
+        self.nodes[index] = sub_nodes[0];
+        self.nodes.splice(first_child..nodes_end, sub_nodes.into_iter().skip(1));
+    }
+
+    /// Bounding box and depth of every node, in the same order as `nodes`.
+    pub fn node_bounds(&self) -> Vec<(BoundingBox, usize)> {
+        let mut node_bounds = vec![(BoundingBox::empty(), 0); self.nodes.len()];
+        let mut stack = vec![(0, self.bounds, 0)];
+        while let Some((index, bounds, depth)) = stack.pop() {
+            node_bounds[index] = (bounds, depth);
+            if let BihNodeData::Branch { clip_left, clip_right, child_left, child_right } = self.nodes[index].data {
+                let axis = self.nodes[index].ty as usize;
+                let mut left_bounds = bounds;
+                left_bounds.max[axis] = clip_left;
+                let mut right_bounds = bounds;
+                right_bounds.min[axis] = clip_right;
+                stack.push((child_left, left_bounds, depth + 1));
+                stack.push((child_right, right_bounds, depth + 1));
+            }
+        }
+        node_bounds
+    }
+
+    pub fn stats(&self) -> BihStats {
+        let mut stats = BihStats { node_count: self.nodes.len(), ..Default::default() };
+        let root_area = self.bounds.surface_area().max(f32::EPSILON);
+
+        for (node, (bounds, depth)) in self.nodes.iter().zip(self.node_bounds()) {
+            if stats.depth_histogram.len() <= depth {
+                stats.depth_histogram.resize(depth + 1, 0);
+            }
+            stats.depth_histogram[depth] += 1;
+
+            let area_ratio = bounds.surface_area() / root_area;
+            match node.data {
+                BihNodeData::Branch { .. } => stats.sah_cost += TRAVERSAL_COST * area_ratio,
+                BihNodeData::Leaf { count, .. } => {
+                    stats.leaf_count += 1;
+                    if count == 0 {
+                        stats.empty_leaf_count += 1;
+                    }
+                    if stats.leaf_size_histogram.len() <= count {
+                        stats.leaf_size_histogram.resize(count + 1, 0);
+                    }
+                    stats.leaf_size_histogram[count] += 1;
+                    stats.sah_cost += INTERSECTION_COST * count as f32 * area_ratio;
+                }
+            }
+        }
+
+        stats
+    }
+
+    /// Writes the node boxes to a file, as a wireframe if the extension is `obj` or as a list of boxes if it is
+    /// `json`.
+    pub fn export(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("obj") => self.write_obj(&mut writer)?,
+            Some("json") => self.write_json(&mut writer)?,
+            _ => anyhow::bail!("Unsupported BIH export format: {}", path.display()),
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write_obj(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        const EDGES: [(usize, usize); 12] =
+            [(1, 2), (2, 4), (4, 3), (3, 1), (5, 6), (6, 8), (8, 7), (7, 5), (1, 5), (2, 6), (3, 7), (4, 8)];
+
+        for (index, (node, (bounds, depth))) in self.nodes.iter().zip(self.node_bounds()).enumerate() {
+            let kind = match node.data {
+                BihNodeData::Branch { .. } => "branch",
+                BihNodeData::Leaf { .. } => "leaf",
+            };
+            writeln!(writer, "o node_{}_{}_depth_{}", index, kind, depth)?;
+            for corner in 0..8 {
+                let x = if corner & 1 == 0 { bounds.min.x } else { bounds.max.x };
+                let y = if corner & 2 == 0 { bounds.min.y } else { bounds.max.y };
+                let z = if corner & 4 == 0 { bounds.min.z } else { bounds.max.z };
+                writeln!(writer, "v {} {} {}", x, y, z)?;
+            }
+            let first_vertex = index * 8;
+            for (a, b) in EDGES {
+                writeln!(writer, "l {} {}", first_vertex + a, first_vertex + b)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        let nodes = self
+            .nodes
+            .iter()
+            .zip(self.node_bounds())
+            .map(|(node, (bounds, depth))| {
+                let mut json = js::json!({
+                    "depth": depth,
+                    "min": [bounds.min.x, bounds.min.y, bounds.min.z],
+                    "max": [bounds.max.x, bounds.max.y, bounds.max.z],
+                });
+                match node.data {
+                    BihNodeData::Branch { clip_left, clip_right, child_left, child_right } => {
+                        json["type"] = format!("{:?}", node.ty).into();
+                        json["clip_left"] = clip_left.into();
+                        json["clip_right"] = clip_right.into();
+                        json["children"] = js::json!([child_left, child_right]);
+                    }
+                    BihNodeData::Leaf { triangle_index, count } => {
+                        json["type"] = "Leaf".into();
+                        json["triangle_index"] = triangle_index.into();
+                        json["count"] = count.into();
+                    }
+                }
+                json
+            })
+            .collect::<Vec<_>>();
+        js::to_writer_pretty(writer, &js::json!({ "nodes": nodes }))?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for BihStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Nodes: {}", self.node_count)?;
+        writeln!(f, "Leaves: {} ({} empty)", self.leaf_count, self.empty_leaf_count)?;
+        writeln!(f, "SAH cost: {:.3}", self.sah_cost)?;
+        writeln!(f, "Depth histogram:")?;
+        for (depth, count) in self.depth_histogram.iter().enumerate() {
+            writeln!(f, "  {:>3}: {}", depth, count)?;
+        }
+        writeln!(f, "Leaf size histogram:")?;
+        for (size, count) in self.leaf_size_histogram.iter().enumerate().filter(|(_, &c)| c > 0) {
+            writeln!(f, "  {:>3}: {}", size, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl AccelerationStructure for Bih {
+    type NodeUniform = BihNodeUniform;
+
+    fn build(triangles: &mut [Triangle]) -> Self {
+        Self::new(triangles)
+    }
+
+    fn node_uniforms(&self) -> Vec<BihNodeUniform> {
+        self.nodes.iter().copied().map(BihNode::into_uniform).collect()
     }
 }
 
@@ -114,21 +420,7 @@ impl BihNode {
 }
 
 fn calculate_bounds(triangles: &[Triangle]) -> BoundingBox {
-    triangles.iter().map(Triangle::bounds).fold(
-        BoundingBox { min: glm::vec3(f32::MAX, f32::MAX, f32::MAX), max: glm::vec3(f32::MIN, f32::MIN, f32::MIN) },
-        |s_bounds, t_bounds| BoundingBox {
-            min: glm::vec3(
-                s_bounds.min.x.min(t_bounds.min.x),
-                s_bounds.min.y.min(t_bounds.min.y),
-                s_bounds.min.z.min(t_bounds.min.z),
-            ),
-            max: glm::vec3(
-                s_bounds.max.x.max(t_bounds.max.x),
-                s_bounds.max.y.max(t_bounds.max.y),
-                s_bounds.max.z.max(t_bounds.max.z),
-            ),
-        },
-    )
+    triangles.iter().map(Triangle::bounds).fold(BoundingBox::empty(), BoundingBox::union)
 }
 
 fn make_hierarchy(
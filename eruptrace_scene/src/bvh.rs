@@ -0,0 +1,333 @@
+#![allow(non_local_definitions)]
+
+use itertools::Itertools;
+use nalgebra_glm as glm;
+use std140::repr_std140;
+
+use crate::{AccelerationStructure, BoundingBox, Triangle};
+
+const BIN_COUNT: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+/// Deepest level a node can sit at. A traversal holds at most one entry per level plus the two children of the
+/// deepest node, so this keeps it within the 64 entry stack of `hitShapeBvh` in `ray_tracing.glsl`.
+const MAX_DEPTH: usize = 62;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BvhNodeData {
+    Branch { child_left: usize, child_right: usize },
+    Leaf { first_index: usize, count: usize },
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug)]
+pub enum BvhNodeType {
+    Branch = 0,
+    Leaf   = 1,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BvhNode {
+    pub bounds: BoundingBox,
+    pub data:   BvhNodeData,
+}
+
+#[repr_std140]
+#[derive(Copy, Clone, Debug)]
+pub struct BvhNodeUniform {
+    pub node_type:   std140::uint,
+    pub child_left:  std140::uint,
+    pub child_right: std140::uint,
+    pub bounds_min:  std140::vec3,
+    pub bounds_max:  std140::vec3,
+}
+
+/// Bounding volume hierarchy built with the binned surface area heuristic.
+///
+/// The triangles are not reordered. Instead, leaves point into `triangle_indices`, which maps the BVH order
+/// onto the scene's triangle array, so the BVH can live next to a BIH built over the same triangles.
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    pub nodes:            Vec<BvhNode>,
+    pub triangle_indices: Vec<u32>,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: BoundingBox,
+    count:  usize,
+}
+
+struct BinnedSplit {
+    axis:     usize,
+    position: f32,
+    cost:     f32,
+}
+
+impl Default for BvhNode {
+    fn default() -> Self {
+        BvhNode { bounds: BoundingBox::empty(), data: BvhNodeData::Leaf { first_index: 0, count: 0 } }
+    }
+}
+
+impl Default for Bin {
+    fn default() -> Self {
+        Bin { bounds: BoundingBox::empty(), count: 0 }
+    }
+}
+
+impl Bvh {
+    pub fn new(triangles: &[Triangle]) -> Self {
+        let mut triangle_indices = (0..triangles.len() as u32).collect_vec();
+        let mut nodes = vec![BvhNode::default()];
+        if !triangles.is_empty() {
+            let bounds = triangles.iter().map(Triangle::bounds).collect_vec();
+            nodes.reserve(2 * triangles.len());
+            make_hierarchy(&bounds, &mut triangle_indices, 0, 0, 0, &mut nodes);
+            nodes.shrink_to_fit();
+        }
+
+        assert_eq!(
+            triangles.len(),
+            nodes.iter().fold(0, |c, n| match n.data {
+                BvhNodeData::Branch { .. } => c,
+                BvhNodeData::Leaf { count, .. } => c + count,
+            })
+        );
+
+        Self { nodes, triangle_indices }
+    }
+
+    /// Recomputes the node bounds bottom-up after the triangles have moved, keeping the structure of the tree.
+    pub fn refit(&mut self, triangles: &[Triangle]) {
+        // Children are always stored after their parents, so iterating backwards visits them first.
+        for index in (0..self.nodes.len()).rev() {
+            self.nodes[index].bounds = match self.nodes[index].data {
+                BvhNodeData::Leaf { first_index, count } => self.triangle_indices[first_index..first_index + count]
+                    .iter()
+                    .map(|&i| triangles[i as usize].bounds())
+                    .fold(BoundingBox::empty(), BoundingBox::union),
+                BvhNodeData::Branch { child_left, child_right } => {
+                    self.nodes[child_left].bounds.union(self.nodes[child_right].bounds)
+                }
+            };
+        }
+    }
+
+    /// Points the leaves at new triangle indices after the triangles have been reordered, where `new_index` maps
+    /// an old index onto the new one.
+    pub fn remap_triangles(&mut self, new_index: impl Fn(usize) -> usize) {
+        for index in self.triangle_indices.iter_mut() {
+            *index = new_index(*index as usize) as u32;
+        }
+    }
+}
+
+impl AccelerationStructure for Bvh {
+    type NodeUniform = BvhNodeUniform;
+
+    fn build(triangles: &mut [Triangle]) -> Self {
+        Self::new(triangles)
+    }
+
+    fn node_uniforms(&self) -> Vec<BvhNodeUniform> {
+        self.nodes.iter().copied().map(BvhNode::into_uniform).collect()
+    }
+}
+
+impl BvhNode {
+    pub fn into_uniform(self) -> BvhNodeUniform {
+        let (node_type, child_left, child_right) = match self.data {
+            BvhNodeData::Branch { child_left, child_right } => (BvhNodeType::Branch, child_left, child_right),
+            BvhNodeData::Leaf { first_index, count } => (BvhNodeType::Leaf, first_index, count),
+        };
+        BvhNodeUniform {
+            node_type:   std140::uint(node_type as u32),
+            child_left:  std140::uint(child_left as u32),
+            child_right: std140::uint(child_right as u32),
+            bounds_min:  eruptrace_vk::std140::vec3(&self.bounds.min),
+            bounds_max:  eruptrace_vk::std140::vec3(&self.bounds.max),
+        }
+    }
+}
+
+fn make_hierarchy(
+    triangle_bounds: &[BoundingBox],
+    indices_part: &mut [u32],
+    indices_offset: usize,
+    current: usize,
+    depth: usize,
+    out_nodes: &mut Vec<BvhNode>,
+) {
+    let bounds =
+        indices_part.iter().map(|&i| triangle_bounds[i as usize]).fold(BoundingBox::empty(), BoundingBox::union);
+    out_nodes[current].bounds = bounds;
+    out_nodes[current].data = BvhNodeData::Leaf { first_index: indices_offset, count: indices_part.len() };
+
+    if indices_part.len() <= 1 || depth >= MAX_DEPTH {
+        return;
+    }
+
+    let Some(split) = find_split(triangle_bounds, indices_part, bounds) else {
+        return;
+    };
+    let leaf_cost = INTERSECTION_COST * indices_part.len() as f32;
+    if split.cost >= leaf_cost && indices_part.len() <= MAX_LEAF_SIZE {
+        return;
+    }
+
+    let middle = indices_part
+        .iter_mut()
+        .partition_in_place(|i| triangle_bounds[*i as usize].centre()[split.axis] < split.position);
+    if !(1..indices_part.len()).contains(&middle) {
+        return;
+    }
+
+    out_nodes.push(BvhNode::default());
+    out_nodes.push(BvhNode::default());
+
+    let child_left = out_nodes.len() - 2;
+    let child_right = out_nodes.len() - 1;
+    out_nodes[current].data = BvhNodeData::Branch { child_left, child_right };
+
+    let (left_part, right_part) = indices_part.split_at_mut(middle);
+    make_hierarchy(triangle_bounds, left_part, indices_offset, child_left, depth + 1, out_nodes);
+    make_hierarchy(triangle_bounds, right_part, indices_offset + middle, child_right, depth + 1, out_nodes);
+}
+
+fn find_split(triangle_bounds: &[BoundingBox], indices_part: &[u32], bounds: BoundingBox) -> Option<BinnedSplit> {
+    let centre_bounds = indices_part
+        .iter()
+        .map(|&i| triangle_bounds[i as usize].centre())
+        .fold(BoundingBox::empty(), |b, c| BoundingBox { min: glm::min2(&b.min, &c), max: glm::max2(&b.max, &c) });
+    let parent_area = bounds.surface_area().max(f32::EPSILON);
+
+    let mut best: Option<BinnedSplit> = None;
+    for axis in 0..3 {
+        let extent = centre_bounds.max[axis] - centre_bounds.min[axis];
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let scale = BIN_COUNT as f32 / extent;
+        let mut bins = [Bin::default(); BIN_COUNT];
+        for &i in indices_part {
+            let triangle = triangle_bounds[i as usize];
+            let bin_index = (((triangle.centre()[axis] - centre_bounds.min[axis]) * scale) as usize).min(BIN_COUNT - 1);
+            bins[bin_index].bounds = bins[bin_index].bounds.union(triangle);
+            bins[bin_index].count += 1;
+        }
+
+        // Sweep from both sides to get the area and triangle count on each side of every bin boundary.
+        let mut left_areas = [0.0; BIN_COUNT - 1];
+        let mut left_counts = [0; BIN_COUNT - 1];
+        let mut left = Bin::default();
+        for (i, bin) in bins.iter().enumerate().take(BIN_COUNT - 1) {
+            left.bounds = left.bounds.union(bin.bounds);
+            left.count += bin.count;
+            left_areas[i] = left.bounds.surface_area();
+            left_counts[i] = left.count;
+        }
+
+        let mut right = Bin::default();
+        for i in (1..BIN_COUNT).rev() {
+            right.bounds = right.bounds.union(bins[i].bounds);
+            right.count += bins[i].count;
+            if left_counts[i - 1] == 0 || right.count == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + INTERSECTION_COST
+                    * (left_areas[i - 1] * left_counts[i - 1] as f32
+                        + right.bounds.surface_area() * right.count as f32)
+                    / parent_area;
+            if best.as_ref().is_none_or(|b| cost < b.cost) {
+                best = Some(BinnedSplit { axis, position: centre_bounds.min[axis] + i as f32 / scale, cost });
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A grid of small triangles scattered over a few layers, so the builder has to split along every axis.
+    fn grid(size: usize) -> Vec<Triangle> {
+        (0..size * size * 3)
+            .map(|i| {
+                let corner = glm::vec3((i % size) as f32, ((i / size) % size) as f32, (i / (size * size)) as f32);
+                Triangle::with_positions(corner, corner + glm::vec3(0.5, 0.0, 0.1), corner + glm::vec3(0.0, 0.5, 0.2))
+            })
+            .collect()
+    }
+
+    fn contains(outer: &BoundingBox, inner: &BoundingBox) -> bool {
+        (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && inner.max[axis] <= outer.max[axis])
+    }
+
+    fn depths(bvh: &Bvh) -> Vec<usize> {
+        let mut depths = vec![0; bvh.nodes.len()];
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            if let BvhNodeData::Branch { child_left, child_right } = node.data {
+                depths[child_left] = depths[index] + 1;
+                depths[child_right] = depths[index] + 1;
+            }
+        }
+        depths
+    }
+
+    #[test]
+    fn leaves_cover_every_triangle_once() {
+        let triangles = grid(12);
+        let bvh = Bvh::new(&triangles);
+
+        let mut seen = vec![0; triangles.len()];
+        for node in &bvh.nodes {
+            if let BvhNodeData::Leaf { first_index, count } = node.data {
+                for &i in &bvh.triangle_indices[first_index..first_index + count] {
+                    seen[i as usize] += 1;
+                }
+            }
+        }
+        assert!(seen.iter().all(|&c| c == 1), "triangle counts: {seen:?}");
+    }
+
+    #[test]
+    fn children_lie_inside_their_parent() {
+        let triangles = grid(12);
+        let bvh = Bvh::new(&triangles);
+
+        for node in &bvh.nodes {
+            match node.data {
+                BvhNodeData::Branch { child_left, child_right } => {
+                    assert!(contains(&node.bounds, &bvh.nodes[child_left].bounds));
+                    assert!(contains(&node.bounds, &bvh.nodes[child_right].bounds));
+                }
+                BvhNodeData::Leaf { first_index, count } => {
+                    for &i in &bvh.triangle_indices[first_index..first_index + count] {
+                        assert!(contains(&node.bounds, &triangles[i as usize].bounds()));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn depth_stays_within_the_traversal_stack() {
+        // Parallel triangles packed so closely that every split costs the same, which makes the builder peel a
+        // single triangle off at each level.
+        let triangles = (0..100)
+            .map(|i| {
+                let corner = glm::vec3(2f32.powi(i - 125), 0.0, 0.0);
+                Triangle::with_positions(corner, corner + glm::vec3(0.0, 1.0, 0.0), corner + glm::vec3(0.0, 0.0, 1.0))
+            })
+            .collect_vec();
+        let bvh = Bvh::new(&triangles);
+
+        assert!(depths(&bvh).into_iter().all(|d| d <= MAX_DEPTH));
+    }
+}
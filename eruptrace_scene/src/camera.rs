@@ -11,6 +11,62 @@ use vk_mem_3_erupt as vma;
 
 use crate::to_vec3;
 
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Projection {
+    Perspective     = 0,
+    /// Parallel rays covering `ortho_height` vertically.
+    Orthographic    = 1,
+    /// Full 360° by 180° panorama.
+    Equirectangular = 2,
+    /// Equidistant fisheye, where the distance from the image's centre is proportional to the angle from the view
+    /// direction. `vertical_fov` covers the image's height and can be up to 360°.
+    Fisheye         = 3,
+}
+
+/// Reconstruction filter weighting the samples around a pixel by their offsets from its centre, shared by both
+/// renderers.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFilter {
+    Box               = 0,
+    Tent              = 1,
+    Gaussian          = 2,
+    BlackmanHarris    = 3,
+    /// Mitchell-Netravali cubic with B = C = 1/3, which has negative lobes sharpening the image.
+    MitchellNetravali = 4,
+}
+
+/// How much of the light reaching the camera makes it into the image, for scenes lit in physical units.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Exposure {
+    /// Leaves the light as it is.
+    None,
+    /// Exposure value at ISO 100, where each step up halves the brightness.
+    Ev100(f32),
+    /// Sensitivity, shutter speed in seconds and f-number. Cameras with a lens take the f-number from its aperture.
+    Manual { iso: f32, shutter_speed: f32, f_stop: f32 },
+}
+
+/// Rectangle of the image, in pixels from its top left corner.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImageRegion {
+    pub x:      u32,
+    pub y:      u32,
+    pub width:  u32,
+    pub height: u32,
+}
+
+impl ImageRegion {
+    /// Part of the region that also lies within `other`, or `None` if they don't overlap.
+    pub fn intersection(&self, other: &ImageRegion) -> Option<ImageRegion> {
+        let (x, y) = (self.x.max(other.x), self.y.max(other.y));
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        (right > x && bottom > y).then_some(ImageRegion { x, y, width: right - x, height: bottom - y })
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Camera {
     pub position:        glm::Vec3,
@@ -18,8 +74,28 @@ pub struct Camera {
     pub up:              glm::Vec3,
     pub vertical_fov:    f32,
     pub img_size:        [u32; 2],
+    /// Part of the image to render, or `None` for all of it.
+    pub region:          Option<ImageRegion>,
     pub sqrt_samples:    u32,
     pub max_reflections: u32,
+    pub projection:      Projection,
+    pub ortho_height:    f32,
+    /// Diameter of the lens. Zero makes a pinhole camera with everything in focus.
+    pub aperture:        f32,
+    /// Distance along the view direction at which objects are in focus.
+    pub focus_distance:  f32,
+    /// Number of blades of the diaphragm shaping the bokeh, or zero for a round one.
+    pub blades:          u32,
+    /// Rotation of the diaphragm's blades, in radians.
+    pub blade_rotation:  f32,
+    /// Times at which the shutter opens and closes, where 0 and 1 are the times at which moving meshes have their
+    /// start and end transforms.
+    pub shutter_open:    f32,
+    pub shutter_close:   f32,
+    pub filter:          PixelFilter,
+    /// Distance from a pixel's centre, in pixels, beyond which samples don't contribute to it.
+    pub filter_radius:   f32,
+    pub exposure:        Exposure,
 }
 
 #[repr_std140]
@@ -29,10 +105,69 @@ pub struct CameraUniform {
     horizontal:          std140::vec4,
     vertical:            std140::vec4,
     bottom_left:         std140::vec4,
+    lens_horizontal:     std140::vec4,
+    lens_vertical:       std140::vec4,
+    forward:             std140::vec4,
     img_size:            std140::vec2,
     img_size_inv:        std140::vec2,
+    region_offset:       std140::vec2,
+    region_scale:        std140::vec2,
     pub sqrt_samples:    std140::uint,
     pub max_reflections: std140::uint,
+    lens_radius:         std140::float,
+    blades:              std140::uint,
+    blade_rotation:      std140::float,
+    projection:          std140::uint,
+    vertical_fov:        std140::float,
+    shutter_open:        std140::float,
+    shutter_close:       std140::float,
+    pixel_filter:        std140::uint,
+    filter_radius:       std140::float,
+    exposure:            std140::float,
+}
+
+impl From<&str> for Projection {
+    fn from(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "perspective" => Self::Perspective,
+            "orthographic" => Self::Orthographic,
+            "equirectangular" => Self::Equirectangular,
+            "fisheye" => Self::Fisheye,
+            _ => panic!("Invalid projection '{s}'."),
+        }
+    }
+}
+
+impl From<&str> for PixelFilter {
+    fn from(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "box" => Self::Box,
+            "tent" | "triangle" => Self::Tent,
+            "gaussian" => Self::Gaussian,
+            "blackman-harris" | "blackman_harris" => Self::BlackmanHarris,
+            "mitchell-netravali" | "mitchell_netravali" | "mitchell" => Self::MitchellNetravali,
+            _ => panic!("Invalid pixel filter '{s}'."),
+        }
+    }
+}
+
+impl PixelFilter {
+    /// Radius used when the camera doesn't specify one. The box filter's covers exactly one pixel.
+    pub fn default_radius(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Tent => 1.0,
+            Self::Gaussian => 1.5,
+            Self::BlackmanHarris | Self::MitchellNetravali => 2.0,
+        }
+    }
+}
+
+impl Projection {
+    /// Whether the projection can be expressed as a matrix, which the deferred renderer needs to rasterise the scene.
+    pub fn is_linear(self) -> bool {
+        matches!(self, Self::Perspective | Self::Orthographic)
+    }
 }
 
 impl Camera {
@@ -41,18 +176,112 @@ impl Camera {
         let look_at = to_vec3(&object["look_at"]).unwrap();
         let up = to_vec3(&object["up"]).unwrap();
         let vertical_fov = object["fov"].as_f64().unwrap_or(90.0) as f32;
+        let img_size = image_size_from_json(&object)?;
         let sqrt_samples = object["sqrt_samples"].as_u64().unwrap_or(1) as u32;
         let max_reflections = object["max_reflections"].as_u64().unwrap_or(1) as u32;
+        let focus_distance = object["focus_distance"].as_f64().map_or(glm::distance(&position, &look_at), |d| d as f32);
+        let aperture = match object["f_stop"].as_f64() {
+            Some(f_stop) => focal_length(vertical_fov) / f_stop as f32,
+            None => object["aperture"].as_f64().unwrap_or(0.0) as f32,
+        };
+        let projection = Projection::from(object["projection"].as_str().unwrap_or("perspective"));
+        let ortho_height = object["ortho_height"].as_f64().unwrap_or(2.0) as f32;
+        let blades = object["bokeh"]["blades"].as_u64().unwrap_or(0) as u32;
+        let blade_rotation = (object["bokeh"]["rotation"].as_f64().unwrap_or(0.0) as f32).to_radians();
+        let shutter_open = object["shutter"]["open"].as_f64().unwrap_or(0.0) as f32;
+        let shutter_close = object["shutter"]["close"].as_f64().unwrap_or(1.0) as f32;
+        let filter = PixelFilter::from(object["filter"]["type"].as_str().unwrap_or("box"));
+        let filter_radius = object["filter"]["radius"].as_f64().map_or(filter.default_radius(), |r| r as f32);
+        let exposure = exposure_from_json(&object)?;
+
+        Ok(Camera {
+            position,
+            look_at,
+            up,
+            img_size,
+            region: None,
+            vertical_fov,
+            sqrt_samples,
+            max_reflections,
+            projection,
+            ortho_height,
+            aperture,
+            focus_distance,
+            blades,
+            blade_rotation,
+            shutter_open,
+            shutter_close,
+            filter,
+            filter_radius,
+            exposure,
+        })
+    }
+
+    /// Focal length of the lens, in the same units as the scene.
+    pub fn focal_length(&self) -> f32 {
+        focal_length(self.vertical_fov)
+    }
+
+    /// f-number of the lens, or `None` for a pinhole camera.
+    pub fn f_stop(&self) -> Option<f32> {
+        (self.aperture > 0.0).then(|| self.focal_length() / self.aperture)
+    }
 
-        Ok(Camera { position, look_at, up, img_size: [512, 512], vertical_fov, sqrt_samples, max_reflections })
+    pub fn set_f_stop(&mut self, f_stop: f32) {
+        self.aperture = self.focal_length() / f_stop;
+    }
+
+    /// Exposure value at ISO 100 given by the exposure settings, or `None` if the light is left as it is.
+    pub fn ev100(&self) -> Option<f32> {
+        match self.exposure {
+            Exposure::None => None,
+            Exposure::Ev100(ev) => Some(ev),
+            Exposure::Manual { iso, shutter_speed, f_stop } => {
+                let f_stop = self.f_stop().unwrap_or(f_stop);
+                Some((f_stop * f_stop / shutter_speed * 100.0 / iso).log2())
+            }
+        }
+    }
+
+    /// Factor of the light reaching the camera applied before the image is tone mapped, which maps the luminance
+    /// saturating the sensor to one, following Lagarde and de Rousiers' "Moving Frostbite to Physically Based
+    /// Rendering".
+    pub fn exposure_multiplier(&self) -> f32 {
+        self.ev100().map_or(1.0, |ev100| 1.0 / (1.2 * ev100.exp2()))
+    }
+
+    /// Size of the rendered part of the image.
+    pub fn rendered_size(&self) -> [u32; 2] {
+        self.region.map_or(self.img_size, |r| [r.width, r.height])
+    }
+
+    /// Pixels past each side of the rendered part of the image whose samples the reconstruction filter still reaches
+    /// from the pixels within it.
+    pub fn filter_margin(&self) -> u32 {
+        (self.filter_radius - 0.5).max(0.0).ceil() as u32
     }
 
     pub fn image_extent_2d(&self) -> vk::Extent2D {
-        vk::Extent2D { width: self.img_size[0], height: self.img_size[1] }
+        let [width, height] = self.rendered_size();
+        vk::Extent2D { width, height }
     }
 
     pub fn image_extent_3d(&self) -> vk::Extent3D {
-        vk::Extent3D { width: self.img_size[0], height: self.img_size[1], depth: 1 }
+        let [width, height] = self.rendered_size();
+        vk::Extent3D { width, height, depth: 1 }
+    }
+
+    /// Bottom left corner and size of the rendered region relative to the whole image, with the y axis pointing up
+    /// like in `cameraRay` in the shaders.
+    fn region_bounds(&self) -> (glm::Vec2, glm::Vec2) {
+        let [width, height] = self.img_size.map(|d| d as f32);
+        match self.region {
+            Some(r) => (
+                glm::vec2(r.x as f32 / width, (height - (r.y + r.height) as f32) / height),
+                glm::vec2(r.width as f32 / width, r.height as f32 / height),
+            ),
+            None => (glm::vec2(0.0, 0.0), glm::vec2(1.0, 1.0)),
+        }
     }
 
     pub fn aspect(&self) -> f32 {
@@ -60,32 +289,80 @@ impl Camera {
     }
 
     pub fn into_uniform(self) -> CameraUniform {
-        let img_size = glm::vec2(self.img_size[0] as f32, self.img_size[1] as f32);
+        let [width, height] = self.rendered_size();
+        let img_size = glm::vec2(width as f32, height as f32);
+        let (region_offset, region_scale) = self.region_bounds();
         let aspect = self.aspect();
         let half_height = (self.vertical_fov.to_radians() * 0.5).tan();
         let half_width = aspect * half_height;
-        let focus_distance = glm::distance(&self.position, &self.look_at);
+        let focus_distance = self.focus_distance;
 
         let w = (self.position - self.look_at).normalize();
         let u = self.up.cross(&w).normalize();
         let v = w.cross(&u);
 
-        let bottom_left = self.position
-            - (half_width * focus_distance * u)
-            - (half_height * focus_distance * v)
-            - (focus_distance * w);
-        let horizontal = 2.0 * half_width * focus_distance * u;
-        let vertical = 2.0 * half_height * focus_distance * v;
+        // Orthographic rays start on the image plane, the others go through it. Panoramic projections only use the
+        // plane's orientation.
+        let (bottom_left, horizontal, vertical) = match self.projection {
+            Projection::Orthographic => {
+                let (width, height) = (aspect * self.ortho_height, self.ortho_height);
+                (self.position - (0.5 * width * u) - (0.5 * height * v), width * u, height * v)
+            }
+            _ => (
+                self.position
+                    - (half_width * focus_distance * u)
+                    - (half_height * focus_distance * v)
+                    - (focus_distance * w),
+                2.0 * half_width * focus_distance * u,
+                2.0 * half_height * focus_distance * v,
+            ),
+        };
+        let lens_radius = 0.5 * self.aperture;
+        let lens_horizontal = lens_radius * u;
+        let lens_vertical = lens_radius * v;
 
         CameraUniform {
             position:        std140::vec4(self.position.x, self.position.y, self.position.z, 0.0),
             horizontal:      std140::vec4(horizontal.x, horizontal.y, horizontal.z, 0.0),
             vertical:        std140::vec4(vertical.x, vertical.y, vertical.z, 0.0),
             bottom_left:     std140::vec4(bottom_left.x, bottom_left.y, bottom_left.z, 0.0),
+            lens_horizontal: std140::vec4(lens_horizontal.x, lens_horizontal.y, lens_horizontal.z, 0.0),
+            lens_vertical:   std140::vec4(lens_vertical.x, lens_vertical.y, lens_vertical.z, 0.0),
+            forward:         std140::vec4(-w.x, -w.y, -w.z, 0.0),
             img_size:        std140::vec2(img_size.x, img_size.y),
             img_size_inv:    std140::vec2(1.0 / img_size.x, 1.0 / img_size.y),
+            region_offset:   std140::vec2(region_offset.x, region_offset.y),
+            region_scale:    std140::vec2(region_scale.x, region_scale.y),
             sqrt_samples:    std140::uint(self.sqrt_samples),
             max_reflections: std140::uint(self.max_reflections),
+            lens_radius:     std140::float(lens_radius),
+            blades:          std140::uint(self.blades),
+            blade_rotation:  std140::float(self.blade_rotation),
+            projection:      std140::uint(self.projection as u32),
+            vertical_fov:    std140::float(self.vertical_fov.to_radians()),
+            shutter_open:    std140::float(self.shutter_open),
+            shutter_close:   std140::float(self.shutter_close),
+            pixel_filter:    std140::uint(self.filter as u32),
+            filter_radius:   std140::float(self.filter_radius),
+            exposure:        std140::float(self.exposure_multiplier()),
+        }
+    }
+
+    /// Matrix rasterising the scene the same way as the ray tracers see it, for linear projections. Only the
+    /// rendered region is mapped onto the viewport, and depths go from 0 to 1 as Vulkan expects.
+    pub fn projection_transform(&self, near: f32, far: f32) -> glm::Mat4 {
+        let (offset, scale) = self.region_bounds();
+        let crop = glm::translation(&glm::vec3(
+            (1.0 - 2.0 * offset.x) / scale.x - 1.0,
+            (1.0 - 2.0 * offset.y) / scale.y - 1.0,
+            0.0,
+        )) * glm::scaling(&glm::vec3(1.0 / scale.x, 1.0 / scale.y, 1.0));
+        crop * match self.projection {
+            Projection::Orthographic => {
+                let (half_width, half_height) = (0.5 * self.aspect() * self.ortho_height, 0.5 * self.ortho_height);
+                glm::ortho_rh_zo(-half_width, half_width, -half_height, half_height, near, far)
+            }
+            _ => glm::perspective_rh_zo(self.aspect(), self.vertical_fov.to_radians(), near, far),
         }
     }
 }
@@ -98,3 +375,151 @@ impl CameraUniform {
         AllocatedBuffer::with_data(allocator, &buffer_info, vma::MemoryUsage::AutoPreferHost, &[self])
     }
 }
+
+/// Focal length of a lens with the given vertical field of view in degrees, assuming the scene is in metres and the
+/// camera has a full-frame sensor, 24 mm high.
+fn focal_length(vertical_fov: f32) -> f32 {
+    0.012 / (vertical_fov.to_radians() * 0.5).tan()
+}
+
+/// Reads the exposure either from `ev`, or from `iso`, `shutter_speed` and `f_stop`, which defaults to the lens's.
+/// The shutter speed is either a number of seconds, or a string of seconds like "0.5" or "1/125".
+fn exposure_from_json(object: &js::Value) -> anyhow::Result<Exposure> {
+    let exposure = &object["exposure"];
+    if exposure.is_null() {
+        return Ok(Exposure::None);
+    }
+    if let Some(ev) = exposure["ev"].as_f64().map(|ev| ev as f32) {
+        if !ev.is_finite() {
+            anyhow::bail!("Invalid exposure value {}.", ev);
+        }
+        return Ok(Exposure::Ev100(ev));
+    }
+    let shutter_speed = match &exposure["shutter_speed"] {
+        js::Value::String(s) => {
+            let invalid = || anyhow::anyhow!("Invalid shutter speed '{s}', expected seconds or 1/<speed>.");
+            let parse = |n: &str| n.trim().parse::<f32>().map_err(|_| invalid());
+            match s.split_once('/') {
+                Some((numerator, denominator)) => parse(numerator)? / parse(denominator)?,
+                None => parse(s)?,
+            }
+        }
+        value => value.as_f64().unwrap_or(1.0 / 125.0) as f32,
+    };
+    let iso = exposure["iso"].as_f64().unwrap_or(100.0) as f32;
+    let f_stop = exposure["f_stop"].as_f64().or(object["f_stop"].as_f64()).unwrap_or(16.0) as f32;
+    for (name, value) in [("ISO", iso), ("shutter speed", shutter_speed), ("f-number", f_stop)] {
+        if !(value.is_finite() && value > 0.0) {
+            anyhow::bail!("Invalid {} {}, expected a positive number.", name, value);
+        }
+    }
+    Ok(Exposure::Manual { iso, shutter_speed, f_stop })
+}
+
+/// Reads the image's size from `width` and `height`, or from one of them and `aspect`, where `resolution` can stand
+/// for the height. The aspect ratio is either a number or a string like "16:9", and sets the width of a default
+/// height of 512 pixels when neither is given.
+fn image_size_from_json(object: &js::Value) -> anyhow::Result<[u32; 2]> {
+    let dimension = |name: &str| object[name].as_u64().map(|d| d as u32);
+    let aspect = match &object["aspect"] {
+        js::Value::Null => None,
+        js::Value::Number(n) => Some(n.as_f64().unwrap() as f32),
+        js::Value::String(ratio) => {
+            let (width, height) =
+                ratio.split_once(':').ok_or_else(|| anyhow::anyhow!("Invalid aspect ratio '{}'.", ratio))?;
+            Some(width.trim().parse::<f32>()? / height.trim().parse::<f32>()?)
+        }
+        _ => anyhow::bail!("Invalid aspect ratio {}.", object["aspect"]),
+    };
+    if aspect.is_some_and(|aspect| !aspect.is_finite() || aspect <= 0.0) {
+        anyhow::bail!("Invalid aspect ratio {}.", object["aspect"]);
+    }
+
+    let size = match (dimension("width"), dimension("height").or(dimension("resolution"))) {
+        (Some(width), Some(height)) => [width, height],
+        (None, height) => {
+            let height = height.unwrap_or(512);
+            [(height as f32 * aspect.unwrap_or(1.0)).round() as u32, height]
+        }
+        (Some(width), None) => [width, (width as f32 / aspect.unwrap_or(1.0)).round() as u32],
+    };
+    if size.contains(&0) {
+        anyhow::bail!("Invalid image size {}x{}.", size[0], size[1]);
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exposure(exposure: js::Value) -> anyhow::Result<Exposure> {
+        exposure_from_json(&js::json!({ "exposure": exposure }))
+    }
+
+    fn camera(exposure: js::Value) -> Camera {
+        Camera::from_json(js::json!({
+            "position": [0.0, 0.0, 5.0],
+            "look_at": [0.0, 0.0, 0.0],
+            "up": [0.0, 1.0, 0.0],
+            "exposure": exposure,
+        }))
+        .unwrap()
+    }
+
+    fn shutter_speed(value: &str) -> anyhow::Result<f32> {
+        match exposure(js::json!({ "shutter_speed": value }))? {
+            Exposure::Manual { shutter_speed, .. } => Ok(shutter_speed),
+            other => panic!("{other:?} isn't a manual exposure"),
+        }
+    }
+
+    #[test]
+    fn shutter_speed_strings_are_parsed() {
+        assert!((shutter_speed("1/125").unwrap() - 0.008).abs() < 1e-7);
+        assert_eq!(shutter_speed("0.5").unwrap(), 0.5);
+        assert_eq!(shutter_speed(" 1 / 4 ").unwrap(), 0.25);
+        assert!(shutter_speed("fast").is_err());
+        assert!(shutter_speed("1/0").is_err());
+    }
+
+    #[test]
+    fn non_positive_settings_are_rejected() {
+        assert!(exposure(js::json!({ "iso": 0.0 })).is_err());
+        assert!(exposure(js::json!({ "iso": -100.0 })).is_err());
+        assert!(exposure(js::json!({ "shutter_speed": 0.0 })).is_err());
+        assert!(exposure(js::json!({ "shutter_speed": "-1/125" })).is_err());
+        assert!(exposure(js::json!({ "f_stop": 0.0 })).is_err());
+        assert!(exposure(js::json!({ "iso": 100.0, "shutter_speed": 0.01, "f_stop": 8.0 })).is_ok());
+    }
+
+    #[test]
+    fn sunny_sixteen_exposure_value() {
+        let camera = camera(js::json!({ "iso": 100.0, "shutter_speed": "1/125", "f_stop": 16.0 }));
+        let ev100 = camera.ev100().unwrap();
+        assert!((ev100 - 14.966).abs() < 0.01, "EV100 is {ev100}");
+        assert!((camera.exposure_multiplier() - 1.0 / (1.2 * ev100.exp2())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn exposure_multiplier() {
+        assert_eq!(camera(js::Value::Null).exposure_multiplier(), 1.0);
+        assert!((camera(js::json!({ "ev": 0.0 })).exposure_multiplier() - 1.0 / 1.2).abs() < 1e-6);
+        let halved = camera(js::json!({ "ev": 1.0 })).exposure_multiplier();
+        assert!((halved - 0.5 / 1.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn image_size_with_aspect() {
+        let size = |object: js::Value| image_size_from_json(&object);
+        assert_eq!(size(js::json!({})).unwrap(), [512, 512]);
+        assert_eq!(size(js::json!({ "aspect": "16:9" })).unwrap(), [910, 512]);
+        assert_eq!(size(js::json!({ "resolution": 1080, "aspect": "16:9" })).unwrap(), [1920, 1080]);
+        assert_eq!(size(js::json!({ "width": 800, "aspect": 2.0 })).unwrap(), [800, 400]);
+        assert_eq!(size(js::json!({ "width": 800, "height": 600, "aspect": 2.0 })).unwrap(), [800, 600]);
+        assert!(size(js::json!({ "aspect": "16:0" })).is_err());
+        assert!(size(js::json!({ "aspect": "0:9" })).is_err());
+        assert!(size(js::json!({ "height": 512, "aspect": -1.0 })).is_err());
+        assert!(size(js::json!({ "aspect": "wide" })).is_err());
+    }
+}
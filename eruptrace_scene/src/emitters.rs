@@ -0,0 +1,88 @@
+use crate::{Material, MaterialType, Triangle};
+
+/// Triangles with emitting materials, which the tracers sample directly with probabilities proportional to their
+/// power, picked in constant time using Vose's alias method.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmitterTable {
+    pub entries:     Vec<Emitter>,
+    pub total_power: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Emitter {
+    pub triangle_index: usize,
+    /// Area of the triangle multiplied by the intensity of its material.
+    pub power:          f32,
+    /// Probability of keeping this entry once it's picked, instead of taking `alias`.
+    pub probability:    f32,
+    pub alias:          usize,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct EmitterUniform {
+    pub triangle_index: u32,
+    pub alias:          u32,
+    pub probability:    f32,
+    pub power:          f32,
+}
+
+impl EmitterTable {
+    pub fn new(triangles: &[Triangle], materials: &[Material]) -> Self {
+        let mut entries: Vec<Emitter> = triangles
+            .iter()
+            .enumerate()
+            .filter_map(|(triangle_index, triangle)| {
+                let material = materials[triangle.material_index as usize];
+                match material.material_type {
+                    MaterialType::Emitting if material.parameter > 0.0 => Some(Emitter {
+                        triangle_index,
+                        power: triangle.area() * material.parameter,
+                        probability: 1.0,
+                        alias: 0,
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        let total_power = entries.iter().map(|e| e.power).sum::<f32>();
+        if total_power <= 0.0 {
+            return Self { entries, total_power: 0.0 };
+        }
+
+        // Split the entries into the ones with less and more than the average power, then let every underfull
+        // entry take the rest of its probability from an overfull one.
+        let n_entries = entries.len() as f32;
+        let mut scaled = entries.iter().map(|e| e.power * n_entries / total_power).collect::<Vec<_>>();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..entries.len()).partition(|&i| scaled[i] < 1.0);
+        while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+            small.pop();
+            entries[less].probability = scaled[less];
+            entries[less].alias = more;
+            scaled[more] -= 1.0 - scaled[less];
+            if scaled[more] < 1.0 {
+                large.pop();
+                small.push(more);
+            }
+        }
+        // Whatever is left over is only off from 1 by rounding errors.
+        for i in small.into_iter().chain(large) {
+            entries[i].probability = 1.0;
+            entries[i].alias = i;
+        }
+
+        Self { entries, total_power }
+    }
+
+    pub fn uniforms(&self) -> Vec<EmitterUniform> {
+        self.entries
+            .iter()
+            .map(|e| EmitterUniform {
+                triangle_index: e.triangle_index as u32,
+                alias:          e.alias as u32,
+                probability:    e.probability,
+                power:          e.power,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,136 @@
+#![allow(non_local_definitions)]
+
+use std::path::{Path, PathBuf};
+
+use image::Rgba32FImage;
+use serde_json as json;
+use std140::repr_std140;
+
+use crate::{layout::f32_to_f16_bits, Sky};
+
+/// Equirectangular map of the light coming from infinitely far away, seen by rays that don't hit anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Environment {
+    pub source:    EnvironmentSource,
+    pub intensity: f32,
+    /// Rotation around the y axis, in radians.
+    pub rotation:  f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EnvironmentSource {
+    Map(PathBuf),
+    /// Baked into a map whenever its settings change.
+    Sky(Sky),
+}
+
+#[repr_std140]
+#[derive(Copy, Clone, Debug)]
+pub struct EnvironmentUniform {
+    pub sun_direction:  std140::vec4,
+    pub sun_radiance:   std140::vec4,
+    pub intensity:      std140::float,
+    pub rotation:       std140::float,
+    pub width:          std140::uint,
+    pub height:         std140::uint,
+    pub sun_cos_radius: std140::float,
+}
+
+impl Environment {
+    pub fn new(path: PathBuf) -> Self {
+        Self { source: EnvironmentSource::Map(path), intensity: 1.0, rotation: 0.0 }
+    }
+
+    pub fn from_json<P: AsRef<Path>>(scene_path: P, object: &json::Value) -> anyhow::Result<Self> {
+        let intensity = object["intensity"].as_f64().unwrap_or(1.0) as f32;
+        if object["sky"].is_object() {
+            // The sun's direction is given in world space, so the sky can't be rotated.
+            return Ok(Self {
+                source: EnvironmentSource::Sky(Sky::from_json(&object["sky"])),
+                intensity,
+                rotation: 0.0,
+            });
+        }
+
+        let path = object["path"].as_str().ok_or_else(|| anyhow::anyhow!("Missing environment map path."))?;
+        let mut env_path = PathBuf::new();
+        env_path.push(&scene_path);
+        env_path.push(path);
+        Ok(Self {
+            source: EnvironmentSource::Map(env_path),
+            intensity,
+            rotation: (object["rotation"].as_f64().unwrap_or(0.0) as f32).to_radians(),
+        })
+    }
+
+    pub fn sky(&self) -> Option<&Sky> {
+        match &self.source {
+            EnvironmentSource::Sky(sky) => Some(sky),
+            EnvironmentSource::Map(_) => None,
+        }
+    }
+
+    /// Loads the map as floats, or bakes the sky into one. LDR images are scaled to [0, 1], the same as textures.
+    pub fn load_image(&self) -> anyhow::Result<Rgba32FImage> {
+        match &self.source {
+            EnvironmentSource::Map(path) => Ok(image::open(path)?.into_rgba32f()),
+            EnvironmentSource::Sky(sky) => Ok(sky.bake()),
+        }
+    }
+
+    pub fn uniform(&self, image: &Rgba32FImage) -> EnvironmentUniform {
+        let sky = self.sky().copied().unwrap_or_default();
+        let (sun_direction, sun_radiance) = (sky.sun_direction, sky.sun_radiance());
+        EnvironmentUniform {
+            sun_direction:  std140::vec4(sun_direction.x, sun_direction.y, sun_direction.z, 0.0),
+            sun_radiance:   std140::vec4(sun_radiance.x, sun_radiance.y, sun_radiance.z, 0.0),
+            intensity:      std140::float(self.intensity),
+            rotation:       std140::float(self.rotation),
+            width:          std140::uint(image.width()),
+            height:         std140::uint(image.height()),
+            sun_cos_radius: std140::float(sky.sun_cos_radius()),
+        }
+    }
+}
+
+/// Texels of the map in `R16G16B16A16_SFLOAT` format.
+pub fn environment_texels(image: &Rgba32FImage) -> Vec<u8> {
+    image.as_raw().iter().flat_map(|&c| f32_to_f16_bits(c).to_ne_bytes()).collect()
+}
+
+/// Piecewise-constant distribution of directions proportional to the luminance of the map, for picking the
+/// directions from which the most light comes. It's laid out as the CDF of the rows' marginal distribution
+/// (`height + 1` values) followed by the CDFs of each row's conditional distribution (`width + 1` values each).
+///
+/// Texels are weighted by the sine of their polar angle, because rows closer to the poles cover less solid angle.
+pub fn environment_distribution(image: &Rgba32FImage) -> Vec<f32> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut marginal = vec![0.0; height + 1];
+    let mut conditional = vec![0.0; height * (width + 1)];
+
+    for (y, row_cdf) in conditional.chunks_mut(width + 1).enumerate() {
+        let sin_theta = (std::f32::consts::PI * (y as f32 + 0.5) / height as f32).sin();
+        for x in 0..width {
+            let [r, g, b, _] = image.get_pixel(x as u32, y as u32).0;
+            let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            row_cdf[x + 1] = row_cdf[x] + luminance.max(0.0) * sin_theta;
+        }
+        let row_sum = row_cdf[width];
+        marginal[y + 1] = marginal[y] + row_sum;
+        normalise_cdf(row_cdf);
+    }
+    normalise_cdf(&mut marginal);
+
+    marginal.extend(conditional);
+    marginal
+}
+
+/// Scales a running sum to end at 1, making it uniform if it sums to zero.
+fn normalise_cdf(cdf: &mut [f32]) {
+    let n = cdf.len() - 1;
+    let sum = cdf[n];
+    for (i, value) in cdf.iter_mut().enumerate() {
+        *value = if sum > 0.0 { *value / sum } else { i as f32 / n as f32 };
+    }
+    cdf[n] = 1.0;
+}
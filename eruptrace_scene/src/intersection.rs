@@ -0,0 +1,217 @@
+use nalgebra_glm as glm;
+
+use crate::Triangle;
+
+/// Offsets below this distance from the origin are done in floating point, since the spacing of floats near zero
+/// is too uneven to offset them by a number of ULPs.
+const ORIGIN: f32 = 1.0 / 32.0;
+const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+const INT_SCALE: f32 = 256.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TriangleHit {
+    pub distance:         f32,
+    /// Weights of the triangle's vertices at the hit point.
+    pub barycentric:      glm::Vec3,
+    /// Unit normal of the triangle's plane, facing against the ray.
+    pub geometric_normal: glm::Vec3,
+}
+
+impl Triangle {
+    /// Watertight ray-triangle intersection (Woop, Benthin, Wald 2013), matching `hitTriangle` in the shaders.
+    ///
+    /// Rays hitting an edge or a vertex shared by several triangles are guaranteed to hit at least one of them.
+    pub fn hit(&self, origin: &glm::Vec3, direction: &glm::Vec3, dist_min: f32, dist_max: f32) -> Option<TriangleHit> {
+        // Permute the axes so that the ray's largest direction component is along z.
+        let kz = direction.iamax();
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        // Shear the vertices into the ray's space, where the ray goes from the origin along +z.
+        let shear = glm::vec3(direction[kx] / direction[kz], direction[ky] / direction[kz], 1.0 / direction[kz]);
+        let [a, b, c] = self.positions.map(|p| p - origin);
+        let [ax, bx, cx] = [a, b, c].map(|v| v[kx] - shear.x * v[kz]);
+        let [ay, by, cy] = [a, b, c].map(|v| v[ky] - shear.y * v[kz]);
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        // Recompute the edge functions in double precision when they are too close to zero to tell which side
+        // of the edge the ray passes.
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+
+        let determinant = u + v + w;
+        if determinant == 0.0 {
+            return None;
+        }
+
+        let [az, bz, cz] = [a, b, c].map(|v| shear.z * v[kz]);
+        let scaled_distance = u * az + v * bz + w * cz;
+
+        // Compare the distance before dividing by the determinant, flipping the comparison when it is negative.
+        let sign = determinant.signum();
+        if sign * scaled_distance <= dist_min * determinant.abs()
+            || sign * scaled_distance > dist_max * determinant.abs()
+        {
+            return None;
+        }
+
+        let determinant_inv = 1.0 / determinant;
+        let normal = glm::normalize(&glm::cross(
+            &(self.positions[1] - self.positions[0]),
+            &(self.positions[2] - self.positions[0]),
+        ));
+        Some(TriangleHit {
+            distance:         scaled_distance * determinant_inv,
+            barycentric:      glm::vec3(u, v, w) * determinant_inv,
+            geometric_normal: if glm::dot(direction, &normal) < 0.0 { normal } else { -normal },
+        })
+    }
+}
+
+/// Moves a point on a surface off of it along the surface's normal (Wächter, Binder 2019), so that a ray starting
+/// there doesn't hit the same surface again. The offset grows with the magnitude of the point's coordinates.
+///
+/// `normal` should face the side the ray is going to leave to, which is the opposite side for refracted rays.
+pub fn offset_ray_origin(position: &glm::Vec3, normal: &glm::Vec3) -> glm::Vec3 {
+    glm::Vec3::from_fn(|i, _| {
+        let (p, n) = (position[i], normal[i]);
+        if p.abs() < ORIGIN {
+            p + FLOAT_SCALE * n
+        } else {
+            let offset = (INT_SCALE * n) as i32;
+            f32::from_bits((p.to_bits() as i32 + if p < 0.0 { -offset } else { offset }) as u32)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square made of two triangles sharing the diagonal from (-1, -1) to (1, 1), scaled and moved by the given
+    /// amounts.
+    fn square(scale: f32, offset: glm::Vec3) -> [Triangle; 2] {
+        let corner = |x: f32, y: f32| glm::vec3(x, y, 0.0) * scale + offset;
+        [
+            Triangle::with_positions(corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0)),
+            Triangle::with_positions(corner(-1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)),
+        ]
+    }
+
+    /// A fan of triangles around a shared vertex at `centre`.
+    fn fan(centre: glm::Vec3, count: usize) -> Vec<Triangle> {
+        let rim = |i: usize| {
+            let angle = i as f32 * std::f32::consts::TAU / count as f32;
+            centre + glm::vec3(angle.cos(), angle.sin(), 0.3 * (3.0 * angle).sin())
+        };
+        (0..count).map(|i| Triangle::with_positions(centre, rim(i), rim(i + 1))).collect()
+    }
+
+    fn hit_count(triangles: &[Triangle], origin: &glm::Vec3, direction: &glm::Vec3) -> usize {
+        triangles.iter().filter(|t| t.hit(origin, direction, 0.0, f32::MAX).is_some()).count()
+    }
+
+    #[test]
+    fn hits_inside_and_misses_outside() {
+        let [t, _] = square(1.0, glm::Vec3::zeros());
+        let direction = glm::vec3(0.0, 0.0, -1.0);
+
+        let hit = t.hit(&glm::vec3(0.5, -0.5, 2.0), &direction, 0.0, f32::MAX).unwrap();
+        assert!((hit.distance - 2.0).abs() < 1e-6);
+        assert!((hit.barycentric.sum() - 1.0).abs() < 1e-6);
+        assert_eq!(hit.geometric_normal, glm::vec3(0.0, 0.0, 1.0));
+
+        assert!(t.hit(&glm::vec3(-0.5, 0.5, 2.0), &direction, 0.0, f32::MAX).is_none());
+        assert!(t.hit(&glm::vec3(1.5, 0.0, 2.0), &direction, 0.0, f32::MAX).is_none());
+        assert!(t.hit(&glm::vec3(0.5, -0.5, 2.0), &direction, 0.0, 1.0).is_none());
+        assert!(t.hit(&glm::vec3(0.5, -0.5, 2.0), &-direction, 0.0, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn hits_from_behind() {
+        let [t, _] = square(1.0, glm::Vec3::zeros());
+        let hit = t.hit(&glm::vec3(0.5, -0.5, -2.0), &glm::vec3(0.0, 0.0, 1.0), 0.0, f32::MAX).unwrap();
+        assert!((hit.distance - 2.0).abs() < 1e-6);
+        assert_eq!(hit.geometric_normal, glm::vec3(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn shared_edge_is_watertight() {
+        for (scale, offset) in [
+            (1.0, glm::Vec3::zeros()),
+            (1e-4, glm::Vec3::zeros()),
+            (1e-3, glm::vec3(0.1, -0.2, 0.3)),
+            (1e3, glm::vec3(1e4, -3e4, 2e4)),
+        ] {
+            let square = square(scale, offset);
+            for i in 1..1000 {
+                // Points along the shared diagonal, excluding the outer corners, hit by slightly tilted rays.
+                let t = i as f32 / 1000.0 * 2.0 - 1.0;
+                let target = glm::vec3(t, t, 0.0) * scale + offset;
+                let direction = glm::vec3(0.1 * t, -0.37, -1.0);
+                let origin = target - direction * 10.0 * scale;
+                assert!(
+                    hit_count(&square, &origin, &direction) > 0,
+                    "ray through {:?} passed between the triangles (scale {})",
+                    target,
+                    scale,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shared_vertex_is_watertight() {
+        for centre in [glm::Vec3::zeros(), glm::vec3(0.3, 0.7, -0.1), glm::vec3(-1234.5, 678.9, 4321.0)] {
+            let triangles = fan(centre, 7);
+            for i in 0..100 {
+                let angle = i as f32 * 0.1;
+                let direction = glm::vec3(0.2 * angle.cos(), 0.2 * angle.sin(), -1.0);
+                let origin = centre - direction * 5.0;
+                assert!(hit_count(&triangles, &origin, &direction) > 0, "ray through vertex {:?} missed", centre);
+            }
+        }
+    }
+
+    #[test]
+    fn offset_origin_does_not_hit_the_surface_again() {
+        for (scale, offset) in [(1.0, glm::Vec3::zeros()), (1e-4, glm::Vec3::zeros()), (1e3, glm::vec3(5e4, 5e4, 5e4))]
+        {
+            let [t, _] = square(scale, offset);
+            let origin = glm::vec3(0.3, -0.6, 1.0) * scale + offset;
+            let direction = glm::vec3(0.01, 0.02, -1.0);
+            let hit = t.hit(&origin, &direction, 0.0, f32::MAX).unwrap();
+            let position = t.positions[0] * hit.barycentric.x
+                + t.positions[1] * hit.barycentric.y
+                + t.positions[2] * hit.barycentric.z;
+
+            // A grazing ray leaving the surface on the side it was hit from.
+            let reflected = glm::vec3(1.0, 1.0, 1e-3);
+            let above = offset_ray_origin(&position, &hit.geometric_normal);
+            assert!(t.hit(&above, &reflected, 0.0, f32::MAX).is_none());
+            for i in 0..3 {
+                // At most `INT_SCALE` ULPs of the larger coordinate, each of which is at most its magnitude times
+                // the machine epsilon.
+                let ulps = INT_SCALE * f32::EPSILON * position[i].abs().max(above[i].abs());
+                assert!((above[i] - position[i]).abs() <= FLOAT_SCALE.max(ulps));
+            }
+
+            // A ray passing through the surface.
+            let below = offset_ray_origin(&position, &-hit.geometric_normal);
+            assert!(t.hit(&below, &direction, 0.0, f32::MAX).is_none());
+            assert!(t.hit(&below, &-direction, 0.0, f32::MAX).is_some());
+        }
+    }
+}
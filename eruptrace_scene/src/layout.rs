@@ -0,0 +1,164 @@
+use std::{ops::Range, str::FromStr};
+
+use eruptrace_vk::push_constants::RtFlags;
+use itertools::Itertools;
+use nalgebra_glm as glm;
+
+use crate::Scene;
+
+/// How the triangles are laid out in the GPU buffers.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TriangleLayout {
+    /// Every triangle stores all three of its vertices in a `TriangleUniform`.
+    #[default]
+    Flat,
+    /// Vertices are shared between triangles, which only store vertex indices and a material index.
+    Indexed,
+    /// Like `Indexed`, with normals octahedral-encoded into two 16-bit values and texture coordinates stored in
+    /// half precision.
+    IndexedCompact,
+}
+
+impl TriangleLayout {
+    pub fn is_indexed(self) -> bool {
+        self != Self::Flat
+    }
+
+    pub fn is_compact(self) -> bool {
+        self == Self::IndexedCompact
+    }
+
+    pub fn rt_flags(self) -> RtFlags {
+        let mut flags = RtFlags::empty();
+        flags.set(RtFlags::INDEXED_TRIANGLES, self.is_indexed());
+        flags.set(RtFlags::COMPACT_VERTICES, self.is_compact());
+        flags
+    }
+
+    /// Number of 32-bit values taken by a single vertex normal.
+    pub fn normal_stride(self) -> usize {
+        if self.is_compact() {
+            1
+        } else {
+            3
+        }
+    }
+
+    /// Number of 32-bit values taken by a single vertex texture coordinate.
+    pub fn texcoord_stride(self) -> usize {
+        if self.is_compact() {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+impl FromStr for TriangleLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Self::Flat),
+            "indexed" => Ok(Self::Indexed),
+            "compact" => Ok(Self::IndexedCompact),
+            _ => anyhow::bail!("Unknown triangle layout '{}', expected 'flat', 'indexed' or 'compact'.", s),
+        }
+    }
+}
+
+impl Scene {
+    /// Index of the first vertex of each mesh in the shared vertex buffers, followed by the total vertex count.
+    pub fn vertex_offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.meshes.len() + 1);
+        offsets.push(0);
+        for mesh in self.meshes.iter() {
+            offsets.push(offsets[offsets.len() - 1] + mesh.positions.len());
+        }
+        offsets
+    }
+
+    pub fn vertex_positions(&self, mesh_index: usize) -> Vec<f32> {
+        self.meshes[mesh_index].transformed_positions().iter().flat_map(|p| [p.x, p.y, p.z]).collect()
+    }
+
+    pub fn vertex_normals(&self, mesh_index: usize, layout: TriangleLayout) -> Vec<u32> {
+        let normals = self.meshes[mesh_index].transformed_normals();
+        if layout.is_compact() {
+            normals.iter().map(encode_octahedral).collect()
+        } else {
+            normals.iter().flat_map(|n| [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]).collect()
+        }
+    }
+
+    pub fn vertex_texcoords(&self, mesh_index: usize, layout: TriangleLayout) -> Vec<u32> {
+        let texcoords = &self.meshes[mesh_index].texcoords;
+        if layout.is_compact() {
+            texcoords.iter().map(|t| pack_half_2x16(t.x, t.y)).collect()
+        } else {
+            texcoords.iter().flat_map(|t| [t.x.to_bits(), t.y.to_bits()]).collect()
+        }
+    }
+
+    /// Vertex indices of each triangle in the given range, with the material index in the last component.
+    pub fn triangle_indices(&self, range: Range<usize>) -> Vec<[u32; 4]> {
+        let vertex_offsets = self.vertex_offsets();
+        self.triangles[range]
+            .iter()
+            .map(|t| {
+                let mesh = &self.meshes[t.mesh_index];
+                let offset = vertex_offsets[t.mesh_index] as u32;
+                let (a, b, c) = mesh.indices[3 * t.mesh_triangle..3 * t.mesh_triangle + 3]
+                    .iter()
+                    .map(|i| i + offset)
+                    .collect_tuple()
+                    .unwrap();
+                [a, b, c, t.material_index]
+            })
+            .collect()
+    }
+}
+
+/// Packs a normal the same way `packSnorm2x16` would pack its octahedral encoding in GLSL.
+fn encode_octahedral(normal: &glm::Vec3) -> u32 {
+    let n = normal / (normal.x.abs() + normal.y.abs() + normal.z.abs()).max(f32::EPSILON);
+    let sign = |v: f32| if v >= 0.0 { 1.0 } else { -1.0 };
+    let (x, y) = if n.z >= 0.0 { (n.x, n.y) } else { ((1.0 - n.y.abs()) * sign(n.x), (1.0 - n.x.abs()) * sign(n.y)) };
+    let snorm = |v: f32| ((v.clamp(-1.0, 1.0) * 32767.0).round() as i16) as u16 as u32;
+    snorm(x) | (snorm(y) << 16)
+}
+
+/// Equivalent of GLSL's `packHalf2x16`.
+fn pack_half_2x16(x: f32, y: f32) -> u32 {
+    f32_to_f16_bits(x) as u32 | ((f32_to_f16_bits(y) as u32) << 16)
+}
+
+pub(crate) fn f32_to_f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // Infinity or NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        sign | 0x7c00
+    } else if half_exponent <= 0 {
+        if half_exponent < -10 {
+            return sign;
+        }
+        // Subnormal, round to nearest
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let rounded = (mantissa + (1 << (shift - 1))) >> shift;
+        sign | rounded as u16
+    } else {
+        // Rounding can carry into the exponent, which still gives the right result.
+        let rounded = ((half_exponent as u32) << 10 | (mantissa >> 13)) + ((mantissa >> 12) & 1);
+        sign | rounded.min(0x7c00) as u16
+    }
+}
@@ -1,27 +1,48 @@
 #![feature(iter_partition_in_place)]
 #![allow(clippy::no_effect)]
 
+pub mod acceleration;
+pub mod animation;
 pub mod bih;
+pub mod bvh;
 pub mod camera;
+pub mod emitters;
+pub mod environment;
+pub mod intersection;
 pub mod json;
+pub mod layout;
+pub mod lights;
 pub mod materials;
 pub mod mesh;
+pub mod path_stats;
+pub mod sky;
+pub mod update;
 
 use std::{
     fs,
+    ops::Range,
     path::{Path, PathBuf},
 };
 
+pub use acceleration::*;
+pub use animation::*;
 pub use bih::*;
+pub use bvh::*;
 pub use camera::*;
+pub use emitters::*;
+pub use environment::*;
 use erupt::{vk, DeviceLoader};
 use eruptrace_vk::{AllocatedBuffer, AllocatedImage, VulkanContext};
 use image::EncodableLayout;
 use itertools::Itertools;
+pub use layout::*;
+pub use lights::*;
 pub use materials::*;
 pub use mesh::*;
-use nalgebra_glm as glm;
+pub use path_stats::*;
 use serde_json as js;
+pub use sky::*;
+pub use update::*;
 use vk_mem_3_erupt as vma;
 
 use crate::json::to_vec3;
@@ -31,19 +52,48 @@ pub struct Scene {
     pub meshes:           Vec<Mesh>,
     pub triangles:        Vec<Triangle>,
     pub materials:        Vec<Material>,
+    pub lights:           Vec<Light>,
+    pub environment:      Environment,
     pub texture_paths:    Vec<PathBuf>,
     pub normal_map_paths: Vec<PathBuf>,
     pub bih:              Bih,
+    pub bvh:              Bvh,
+    pub emitters:         EmitterTable,
+    pub animation:        Option<Animation>,
 }
 
 #[derive(Clone)]
 pub struct RtSceneBuffers {
-    pub textures_image:    AllocatedImage,
-    pub normal_maps_image: AllocatedImage,
-    pub materials_buffer:  AllocatedBuffer<MaterialUniform>,
-    pub triangles_buffer:  AllocatedBuffer<TriangleUniform>,
-    pub bih_buffer:        AllocatedBuffer<BihNodeUniform>,
-    pub n_triangles:       u32,
+    pub textures_image:     AllocatedImage,
+    pub normal_maps_image:  AllocatedImage,
+    pub materials_buffer:   AllocatedBuffer<MaterialUniform>,
+    pub triangles_buffer:   AllocatedBuffer<TriangleUniform>,
+    pub bih_buffer:         AllocatedBuffer<BihNodeUniform>,
+    pub bvh_buffer:         AllocatedBuffer<BvhNodeUniform>,
+    pub bvh_indices_buffer: AllocatedBuffer<u32>,
+    pub lights_buffer:      AllocatedBuffer<LightUniform>,
+    pub emitters_buffer:    AllocatedBuffer<EmitterUniform>,
+    pub n_triangles:        u32,
+    pub n_lights:           u32,
+    pub n_emitters:         u32,
+
+    pub environment_image:               AllocatedImage,
+    pub environment_buffer:              AllocatedBuffer<EnvironmentUniform>,
+    pub environment_distribution_buffer: AllocatedBuffer<f32>,
+
+    pub path_stats_buffer: AllocatedBuffer<u32>,
+
+    /// Offsets of each triangle's vertices at the end of the shutter interval, three per triangle. Scenes without
+    /// moving meshes only get room for one.
+    pub triangle_motion_buffer:  AllocatedBuffer<[f32; 4]>,
+    /// Index of the mesh each triangle comes from, which the object ID pass is made of.
+    pub triangle_objects_buffer: AllocatedBuffer<u32>,
+
+    pub triangle_layout:         TriangleLayout,
+    pub vertex_positions_buffer: AllocatedBuffer<f32>,
+    pub vertex_normals_buffer:   AllocatedBuffer<u32>,
+    pub vertex_texcoords_buffer: AllocatedBuffer<u32>,
+    pub triangle_indices_buffer: AllocatedBuffer<[u32; 4]>,
 }
 
 impl Scene {
@@ -64,7 +114,8 @@ impl Scene {
             let file_contents = fs::read_to_string(desc_path)?;
             let scene_json: js::Value = js::from_str(&file_contents)?;
 
-            let get_paths = |res, first_name| {
+            // Puts `first_name` first, making it the default for materials that don't name one.
+            let get_paths = |res, first_name: Option<&str>| {
                 let obj = scene_json[res].as_object().unwrap();
                 let mut names: Vec<String> = obj.keys().map(|n| n.to_owned()).collect();
                 let mut paths: Vec<PathBuf> = obj
@@ -78,19 +129,23 @@ impl Scene {
                         tex_path
                     })
                     .collect();
-                if let Some(first_idx) = names.iter().position(|n| n == first_name) {
-                    unsafe {
-                        std::ptr::swap(&mut paths[0], &mut paths[first_idx]);
-                        std::ptr::swap(&mut names[0], &mut names[first_idx]);
+                if let Some(first_name) = first_name {
+                    if let Some(first_idx) = names.iter().position(|n| n == first_name) {
+                        unsafe {
+                            std::ptr::swap(&mut paths[0], &mut paths[first_idx]);
+                            std::ptr::swap(&mut names[0], &mut names[first_idx]);
+                        }
+                    } else {
+                        eprintln!("Missing '{}' in {}.", first_name, res);
                     }
-                } else {
-                    eprintln!("Missing 'sky' texture.");
                 }
                 (names, paths)
             };
 
-            let (texture_names, texture_paths) = get_paths("textures", "sky");
-            let (normal_map_names, normal_map_paths) = get_paths("normal_maps", "default");
+            // Only scenes without an environment use the sky texture, as their environment map.
+            let has_environment = scene_json["environment"].is_object();
+            let (texture_names, texture_paths) = get_paths("textures", (!has_environment).then_some("sky"));
+            let (normal_map_names, normal_map_paths) = get_paths("normal_maps", Some("default"));
 
             let (material_names, materials) = {
                 let obj = scene_json["materials"].as_object().unwrap();
@@ -103,65 +158,117 @@ impl Scene {
                 (names, materials)
             };
 
-            let meshes_and_triangles = scene_json["meshes"]
+            let lights = scene_json["lights"]
                 .as_array()
                 .map_or(&vec![], |v| v)
                 .iter()
-                .filter(|m| m.is_object())
-                .map(|m| Mesh::from_json(&scene_path, m, &material_names))
-                .filter_map(|m| match m {
-                    Ok(m) => {
-                        let t = m.triangles();
-                        Some((m, t))
-                    }
+                .filter(|l| l.is_object())
+                .map(Light::from_json)
+                .filter_map(|l| match l {
+                    Ok(l) => Some(l),
                     Err(e) => {
                         eprintln!("{}", e);
                         None
                     }
                 })
                 .collect_vec();
-            let mut meshes = Vec::with_capacity(meshes_and_triangles.len());
-            let mut triangles = Vec::with_capacity(meshes_and_triangles.len());
-            for (mesh, m_triangles) in meshes_and_triangles.into_iter() {
-                triangles.extend(m_triangles.into_iter().map(|t| Triangle {
-                    positions:      t.positions.map(|p| (mesh.transform * glm::vec4(p.x, p.y, p.z, 1.0)).xyz()),
-                    normals:        t.normals.map(|n| {
-                        let normal_transform = glm::transpose(&glm::inverse(&mesh.transform));
-                        (normal_transform * glm::vec4(n.x, n.y, n.z, 1.0)).xyz()
-                    }),
-                    texcoords:      t.texcoords,
-                    material_index: t.material_index,
-                }));
-                meshes.push(mesh);
-            }
 
-            let bih = Bih::new(&mut triangles);
+            let environment = if has_environment {
+                Environment::from_json(&scene_path, &scene_json["environment"])?
+            } else if texture_names.first().is_some_and(|n| n == "sky") {
+                Environment::new(texture_paths[0].clone())
+            } else {
+                anyhow::bail!("Missing environment or 'sky' texture.");
+            };
 
-            Self { meshes, triangles, materials, texture_paths, normal_map_paths, bih }
+            let mesh_descriptions = scene_json["meshes"].as_array().map(Vec::as_slice).unwrap_or_default();
+            let mut meshes = vec![];
+            // Index of each description's mesh in `meshes`, or none if it couldn't be loaded.
+            let mesh_indices = mesh_descriptions
+                .iter()
+                .map(|m| {
+                    if !m.is_object() {
+                        return None;
+                    }
+                    match Mesh::from_json(&scene_path, m, &material_names) {
+                        Ok(mesh) => {
+                            meshes.push(mesh);
+                            Some(meshes.len() - 1)
+                        }
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            None
+                        }
+                    }
+                })
+                .collect_vec();
+            let mut triangles =
+                meshes.iter().enumerate().flat_map(|(index, mesh)| mesh.transformed_triangles(index)).collect_vec();
+
+            // The BIH reorders the triangles, so it has to be built first.
+            let bih = Bih::build(&mut triangles);
+            let bvh = Bvh::build(&mut triangles);
+            let emitters = EmitterTable::new(&triangles, &materials);
+
+            let animation = match scene_json["animation"].is_object() {
+                true => Some(Animation::from_json(&scene_json["animation"], mesh_descriptions, &mesh_indices)?),
+                false => None,
+            };
+
+            Self {
+                meshes,
+                triangles,
+                materials,
+                lights,
+                environment,
+                texture_paths,
+                normal_map_paths,
+                bih,
+                bvh,
+                emitters,
+                animation,
+            }
         };
 
         Ok((camera, scene))
     }
 
-    pub fn create_buffers(self, vk_ctx: VulkanContext) -> RtSceneBuffers {
+    pub fn create_buffers(&self, vk_ctx: VulkanContext, triangle_layout: TriangleLayout) -> RtSceneBuffers {
         let n_textures = self.texture_paths.len();
         let n_normal_maps = self.normal_map_paths.len();
         let n_triangles = self.triangles.len() as u32;
         let textures = self
             .texture_paths
-            .into_iter()
+            .iter()
             .map(|path| image::open(path).unwrap().into_rgba8())
             .flat_map(|texture| Vec::from(texture.as_bytes()))
             .collect_vec();
         let normal_maps = self
             .normal_map_paths
-            .into_iter()
+            .iter()
             .map(|path| image::open(path).unwrap().into_rgba8())
             .flat_map(|texture| Vec::from(texture.as_bytes()))
             .collect_vec();
-        let materials = self.materials.into_iter().map(Material::into_uniform).collect_vec();
-        let triangles = self.triangles.into_iter().map(Triangle::into_uniform).collect_vec();
-        let bih = self.bih.0.into_iter().map(BihNode::into_uniform).collect_vec();
+        let materials = self.materials.iter().copied().map(Material::into_uniform).collect_vec();
+        let lights = self.light_uniforms();
+        let emitters = self.emitters.uniforms();
+        let environment_map = self.environment.load_image().unwrap();
+        let bih = self.bih.node_uniforms();
+        let (triangles, vertex_positions, vertex_normals, vertex_texcoords, triangle_indices) =
+            if triangle_layout.is_indexed() {
+                let meshes = 0..self.meshes.len();
+                (
+                    vec![],
+                    meshes.clone().flat_map(|i| self.vertex_positions(i)).collect_vec(),
+                    meshes.clone().flat_map(|i| self.vertex_normals(i, triangle_layout)).collect_vec(),
+                    meshes.flat_map(|i| self.vertex_texcoords(i, triangle_layout)).collect_vec(),
+                    self.triangle_indices(0..self.triangles.len()),
+                )
+            } else {
+                let triangles = self.triangles.iter().cloned().map(Triangle::into_uniform).collect_vec();
+                (triangles, vec![], vec![], vec![], vec![])
+            };
+        let bvh = self.bvh.node_uniforms();
 
         let image_extent = vk::Extent3D { width: 1024, height: 1024, depth: 1 };
 
@@ -194,29 +301,160 @@ impl Scene {
                 vma::MemoryUsage::AutoPreferHost,
                 &materials,
             ),
-            triangles_buffer: AllocatedBuffer::with_data(
+            triangles_buffer: storage_buffer(&vk_ctx, &triangles),
+            bih_buffer: {
+                // Leave room for partial rebuilds, which can produce up to one leaf per triangle.
+                let capacity = bih.len().max(2 * self.triangles.len()) * size_of::<BihNodeUniform>();
+                let buffer = AllocatedBuffer::new(
+                    vk_ctx.allocator.clone(),
+                    &buffer_info.size(capacity as vk::DeviceSize),
+                    vma::MemoryUsage::AutoPreferHost,
+                );
+                buffer.set_data(&bih);
+                buffer
+            },
+            bvh_buffer: AllocatedBuffer::with_data(
                 vk_ctx.allocator.clone(),
                 &buffer_info,
                 vma::MemoryUsage::AutoPreferHost,
-                &triangles,
+                &bvh,
             ),
-            bih_buffer: AllocatedBuffer::with_data(
-                vk_ctx.allocator,
+            bvh_indices_buffer: AllocatedBuffer::with_data(
+                vk_ctx.allocator.clone(),
                 &buffer_info,
                 vma::MemoryUsage::AutoPreferHost,
-                &bih,
+                &self.bvh.triangle_indices,
+            ),
+            lights_buffer: {
+                // Leave room for the sun, which can be turned on after loading the scene.
+                let capacity = (self.lights.len() + 1) * size_of::<LightUniform>();
+                let buffer = AllocatedBuffer::new(
+                    vk_ctx.allocator.clone(),
+                    &buffer_info.size(capacity as vk::DeviceSize),
+                    vma::MemoryUsage::AutoPreferHost,
+                );
+                buffer.set_data(&lights);
+                buffer
+            },
+            emitters_buffer: storage_buffer(&vk_ctx, &emitters),
+            environment_image: AllocatedImage::texture_with_data(
+                vk_ctx.clone(),
+                vk::Format::R16G16B16A16_SFLOAT,
+                vk::Extent3D { width: environment_map.width(), height: environment_map.height(), depth: 1 },
+                vk::ImageViewType::_2D,
+                1,
+                1,
+                &environment_texels(&environment_map),
             ),
+            environment_buffer: AllocatedBuffer::with_data(
+                vk_ctx.allocator.clone(),
+                &vk::BufferCreateInfoBuilder::new()
+                    .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                vma::MemoryUsage::AutoPreferHost,
+                &[self.environment.uniform(&environment_map)],
+            ),
+            environment_distribution_buffer: storage_buffer(&vk_ctx, &environment_distribution(&environment_map)),
             n_triangles,
+            n_lights: lights.len() as u32,
+            n_emitters: emitters.len() as u32,
+            triangle_layout,
+            vertex_positions_buffer: storage_buffer(&vk_ctx, &vertex_positions),
+            vertex_normals_buffer: storage_buffer(&vk_ctx, &vertex_normals),
+            vertex_texcoords_buffer: storage_buffer(&vk_ctx, &vertex_texcoords),
+            triangle_indices_buffer: storage_buffer(&vk_ctx, &triangle_indices),
+            path_stats_buffer: storage_buffer(&vk_ctx, &[0; PATH_LENGTH_BUCKETS]),
+            triangle_motion_buffer: match self.has_motion() {
+                true => storage_buffer(&vk_ctx, &self.triangle_motion(0..self.triangles.len())),
+                false => storage_buffer(&vk_ctx, &[]),
+            },
+            triangle_objects_buffer: storage_buffer(&vk_ctx, &self.triangle_objects(0..self.triangles.len())),
         }
     }
+
+    /// Whether any of the meshes moves while the camera's shutter is open.
+    pub fn has_motion(&self) -> bool {
+        self.meshes.iter().any(|mesh| mesh.transform_end.is_some())
+    }
+
+    /// Motion of the vertices of the given triangles, laid out for `triangleMotion` in the shaders.
+    pub fn triangle_motion(&self, triangles: Range<usize>) -> Vec<[f32; 4]> {
+        self.triangles[triangles].iter().flat_map(|t| t.motion.map(|m| [m.x, m.y, m.z, 0.0])).collect_vec()
+    }
+
+    /// Indices of the meshes the given triangles come from, laid out for `triangleObjects` in the shaders.
+    pub fn triangle_objects(&self, triangles: Range<usize>) -> Vec<u32> {
+        self.triangles[triangles].iter().map(|t| t.mesh_index as u32).collect_vec()
+    }
+
+    /// Uniforms of the scene's lights followed by the sky's sun, if it has one.
+    pub fn light_uniforms(&self) -> Vec<LightUniform> {
+        let sun = self.environment.sky().and_then(Sky::sun_light);
+        self.lights.iter().copied().chain(sun).map(Light::into_uniform).collect_vec()
+    }
 }
 
 impl RtSceneBuffers {
+    /// Clears the path length histogram before a render.
+    pub fn reset_path_stats(&self) {
+        self.path_stats_buffer.set_data(&[0; PATH_LENGTH_BUCKETS]);
+    }
+
+    pub fn path_stats(&self) -> PathStats {
+        PathStats { length_counts: self.path_stats_buffer.read_data() }
+    }
+
+    /// Uploads the scene's environment after its settings have changed, re-baking the sky if it has one. The map
+    /// must keep its size.
+    pub fn update_environment(&mut self, vk_ctx: VulkanContext, scene: &Scene) {
+        let environment_map = scene.environment.load_image().unwrap();
+        self.environment_buffer.set_data(&[scene.environment.uniform(&environment_map)]);
+        if scene.environment.sky().is_some() {
+            self.environment_image.set_data(vk_ctx, vk::Offset3D::default(), &environment_texels(&environment_map));
+            self.environment_distribution_buffer.set_data(&environment_distribution(&environment_map));
+        }
+
+        let lights = scene.light_uniforms();
+        self.lights_buffer.set_data(&lights);
+        self.n_lights = lights.len() as u32;
+    }
+
     pub fn destroy(&self, device: &DeviceLoader) {
         self.textures_image.destroy(device);
         self.normal_maps_image.destroy(device);
         self.materials_buffer.destroy();
         self.triangles_buffer.destroy();
         self.bih_buffer.destroy();
+        self.bvh_buffer.destroy();
+        self.bvh_indices_buffer.destroy();
+        self.lights_buffer.destroy();
+        self.emitters_buffer.destroy();
+        self.environment_image.destroy(device);
+        self.environment_buffer.destroy();
+        self.environment_distribution_buffer.destroy();
+        self.vertex_positions_buffer.destroy();
+        self.vertex_normals_buffer.destroy();
+        self.vertex_texcoords_buffer.destroy();
+        self.triangle_indices_buffer.destroy();
+        self.path_stats_buffer.destroy();
+        self.triangle_motion_buffer.destroy();
+        self.triangle_objects_buffer.destroy();
+    }
+}
+
+/// Creates a host-visible storage buffer. Vulkan doesn't allow empty buffers, so a buffer for data unused by
+/// the current triangle layout or for a scene without lights or emitters gets room for a single element.
+fn storage_buffer<T>(vk_ctx: &VulkanContext, data: &[T]) -> AllocatedBuffer<T> {
+    let buffer_info = vk::BufferCreateInfoBuilder::new()
+        .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    if data.is_empty() {
+        AllocatedBuffer::new(
+            vk_ctx.allocator.clone(),
+            &buffer_info.size(size_of::<T>() as vk::DeviceSize),
+            vma::MemoryUsage::AutoPreferHost,
+        )
+    } else {
+        AllocatedBuffer::with_data(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferHost, data)
     }
 }
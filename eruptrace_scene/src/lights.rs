@@ -0,0 +1,123 @@
+#![allow(non_local_definitions)]
+
+use nalgebra_glm as glm;
+use serde_json as json;
+use std140::repr_std140;
+
+use crate::json::to_vec3;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug)]
+pub enum LightType {
+    Point       = 0,
+    Spot        = 1,
+    Directional = 2,
+    Area        = 3,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    pub light_type: LightType,
+    pub color:      glm::Vec3,
+    pub intensity:  f32,
+    /// Position of point and spot lights, or one of the corners of an area light.
+    pub position:   glm::Vec3,
+    /// Direction in which spot and directional lights shine.
+    pub direction:  glm::Vec3,
+    /// Cosines of the angles between a spot light's direction and the edges of its fully lit cone and of the cone
+    /// outside which it doesn't shine.
+    pub cos_inner:  f32,
+    pub cos_outer:  f32,
+    /// Edges of an area light's parallelogram, starting from `position`. It shines towards `cross(edge_u, edge_v)`.
+    pub edge_u:     glm::Vec3,
+    pub edge_v:     glm::Vec3,
+}
+
+#[repr_std140]
+#[derive(Copy, Clone, Debug)]
+pub struct LightUniform {
+    pub light_type: std140::uint,
+    pub intensity:  std140::float,
+    pub cos_inner:  std140::float,
+    pub cos_outer:  std140::float,
+    pub color:      std140::vec4,
+    pub position:   std140::vec4,
+    pub direction:  std140::vec4,
+    pub edge_u:     std140::vec4,
+    pub edge_v:     std140::vec4,
+}
+
+impl TryFrom<&str> for LightType {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "point" => Ok(Self::Point),
+            "spot" => Ok(Self::Spot),
+            "directional" => Ok(Self::Directional),
+            "area" => Ok(Self::Area),
+            _ => anyhow::bail!("Invalid light type '{s}'."),
+        }
+    }
+}
+
+impl Light {
+    pub fn from_json(object: &json::Value) -> anyhow::Result<Self> {
+        let light_type = LightType::try_from(object["type"].as_str().unwrap_or_default())?;
+        let vector = |name: &str| {
+            to_vec3(&object[name]).ok_or_else(|| anyhow::anyhow!("Missing '{}' of a {:?} light.", name, light_type))
+        };
+        let angle = |name: &str, default: f32| (object[name].as_f64().unwrap_or(default as f64) as f32).to_radians();
+
+        let color = to_vec3(&object["color"]).unwrap_or_else(|| glm::vec3(1.0, 1.0, 1.0));
+        let intensity = object["intensity"].as_f64().unwrap_or(1.0) as f32;
+
+        let mut light = Light {
+            light_type,
+            color,
+            intensity,
+            position: glm::Vec3::zeros(),
+            direction: glm::Vec3::zeros(),
+            cos_inner: 1.0,
+            cos_outer: 1.0,
+            edge_u: glm::Vec3::zeros(),
+            edge_v: glm::Vec3::zeros(),
+        };
+        match light_type {
+            LightType::Point => {
+                light.position = vector("position")?;
+            }
+            LightType::Spot => {
+                light.position = vector("position")?;
+                light.direction = glm::normalize(&vector("direction")?);
+                let outer_angle = angle("outer_angle", 30.0);
+                light.cos_outer = outer_angle.cos();
+                light.cos_inner = angle("inner_angle", 0.0).min(outer_angle).cos();
+            }
+            LightType::Directional => {
+                light.direction = glm::normalize(&vector("direction")?);
+            }
+            LightType::Area => {
+                light.position = vector("position")?;
+                light.edge_u = vector("edge_u")?;
+                light.edge_v = vector("edge_v")?;
+            }
+        }
+
+        Ok(light)
+    }
+
+    pub fn into_uniform(self) -> LightUniform {
+        LightUniform {
+            light_type: std140::uint(self.light_type as u32),
+            intensity:  std140::float(self.intensity),
+            cos_inner:  std140::float(self.cos_inner),
+            cos_outer:  std140::float(self.cos_outer),
+            color:      std140::vec4(self.color.x, self.color.y, self.color.z, 1.0),
+            position:   std140::vec4(self.position.x, self.position.y, self.position.z, 1.0),
+            direction:  std140::vec4(self.direction.x, self.direction.y, self.direction.z, 0.0),
+            edge_u:     std140::vec4(self.edge_u.x, self.edge_u.y, self.edge_u.z, 0.0),
+            edge_v:     std140::vec4(self.edge_v.x, self.edge_v.y, self.edge_v.z, 0.0),
+        }
+    }
+}
@@ -6,10 +6,15 @@ use std140::repr_std140;
 #[repr(u32)]
 #[derive(Copy, Clone, Debug)]
 pub enum MaterialType {
-    Diffusive  = 0,
-    Reflective = 1,
-    Refractive = 2,
-    Emitting   = 3,
+    Diffusive     = 0,
+    Reflective    = 1,
+    Refractive    = 2,
+    Emitting      = 3,
+    /// Only shows the shadows and the light of the other objects falling onto it, to be composited over a photograph
+    /// of the surface it stands in for.
+    ShadowCatcher = 4,
+    /// Punches a transparent hole through the image wherever it is seen, and is black in reflections.
+    Holdout       = 5,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -22,6 +27,8 @@ pub struct Material {
     /// - Reflective: fuzz
     /// - Refractive: refractive index
     /// - Emitting: intensity
+    /// - ShadowCatcher: no function
+    /// - Holdout: no function
     pub parameter:        f32,
 }
 
@@ -41,6 +48,8 @@ impl From<&str> for MaterialType {
             "reflective" => Self::Reflective,
             "refractive" => Self::Refractive,
             "emitting" => Self::Emitting,
+            "shadow_catcher" => Self::ShadowCatcher,
+            "holdout" => Self::Holdout,
             _ => panic!("Invalid material type '{s}'."),
         }
     }
@@ -60,7 +69,7 @@ impl Material {
             normal_map_names.iter().position(|n| object["normal_map"] == *n).unwrap_or_default() as u32;
 
         let parameter = match material_type {
-            MaterialType::Diffusive => 1.0,
+            MaterialType::Diffusive | MaterialType::ShadowCatcher | MaterialType::Holdout => 1.0,
             MaterialType::Reflective => object["fuzz"].as_f64().unwrap_or(0.0) as f32,
             MaterialType::Refractive => object["index"].as_f64().unwrap_or(1.0) as f32,
             MaterialType::Emitting => object["intensity"].as_f64().unwrap_or(1.0) as f32,
@@ -23,6 +23,12 @@ pub struct Triangle {
     pub normals:        [glm::Vec3; 3],
     pub texcoords:      [glm::Vec2; 3],
     pub material_index: u32,
+    /// Index of the mesh the triangle comes from.
+    pub mesh_index:     usize,
+    /// Index of the triangle within its mesh.
+    pub mesh_triangle:  usize,
+    /// How far the vertices move from `positions` by the end of the camera's shutter interval.
+    pub motion:         [glm::Vec3; 3],
 }
 
 #[repr_std140]
@@ -34,6 +40,15 @@ pub struct TriangleUniform {
     pub material_index: std140::uint,
 }
 
+/// Placement of a mesh in the scene, as given in the scene description.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MeshTransform {
+    pub position: glm::Vec3,
+    /// Euler angles around the x, y and z axes, in degrees, applied in that order.
+    pub rotation: glm::Vec3,
+    pub scale:    glm::Vec3,
+}
+
 #[derive(Clone, Debug)]
 pub struct Mesh {
     pub positions:      Vec<glm::Vec3>,
@@ -41,9 +56,39 @@ pub struct Mesh {
     pub texcoords:      Vec<glm::Vec2>,
     pub indices:        Vec<u32>,
     pub transform:      glm::Mat4x4,
+    /// Transform at the end of the shutter interval, if the mesh moves while the shutter is open.
+    pub transform_end:  Option<glm::Mat4x4>,
     pub material_index: u32,
 }
 
+impl Default for MeshTransform {
+    fn default() -> Self {
+        Self { position: glm::Vec3::zeros(), rotation: glm::Vec3::zeros(), scale: glm::vec3(1.0, 1.0, 1.0) }
+    }
+}
+
+impl MeshTransform {
+    pub fn from_json(object: &js::Value) -> Self {
+        Self::from_json_or(object, Self::default())
+    }
+
+    /// Reads the transform, taking the parts it doesn't specify from `default`.
+    pub fn from_json_or(object: &js::Value, default: Self) -> Self {
+        Self {
+            position: to_vec3(&object["position"]).unwrap_or(default.position),
+            rotation: to_vec3(&object["rotation"]).unwrap_or(default.rotation),
+            scale:    to_vec3(&object["scale"]).unwrap_or(default.scale),
+        }
+    }
+
+    pub fn matrix(&self) -> glm::Mat4x4 {
+        let rot_x = glm::rotation(self.rotation.x.to_radians(), &glm::vec3(1.0, 0.0, 0.0));
+        let rot_y = glm::rotation(self.rotation.y.to_radians(), &glm::vec3(0.0, 1.0, 0.0));
+        let rot_z = glm::rotation(self.rotation.z.to_radians(), &glm::vec3(0.0, 0.0, 1.0));
+        glm::translation(&self.position) * rot_z * rot_y * rot_x * glm::scaling(&self.scale)
+    }
+}
+
 impl Mesh {
     pub fn from_json<P: AsRef<Path>>(
         scene_path: P,
@@ -84,49 +129,90 @@ impl Mesh {
             (positions, normals, texcoords, indices)
         };
 
-        let transform: glm::Mat4x4 = match object["transform"].as_object() {
-            Some(object) => {
-                let get_transform = |name, func: &dyn Fn(&glm::Vec3) -> glm::Mat4x4| {
-                    object.get(name).and_then(to_vec3).map(|vector| func(&vector)).unwrap_or_else(glm::identity)
-                };
-                let translation = get_transform("position", &glm::translation::<f32>);
-                let rotation = get_transform("rotation", &|vec| {
-                    let rot_x = glm::rotation(vec.x.to_radians(), &glm::vec3(1.0, 0.0, 0.0));
-                    let rot_y = glm::rotation(vec.y.to_radians(), &glm::vec3(0.0, 1.0, 0.0));
-                    let rot_z = glm::rotation(vec.z.to_radians(), &glm::vec3(0.0, 0.0, 1.0));
-                    rot_z * rot_y * rot_x
-                });
-                let scale = get_transform("scale", &glm::scaling::<f32>);
-                translation * rotation * scale
-            }
-            None => glm::identity(),
-        };
+        let transform = MeshTransform::from_json(&object["transform"]);
+        let transform_end = object["transform_end"]
+            .is_object()
+            .then(|| MeshTransform::from_json_or(&object["transform_end"], transform).matrix());
 
         let material_index = material_names.iter().position(|n| object["material"] == *n).unwrap_or_default() as u32;
 
-        Ok(Self { positions, normals, texcoords, indices, transform, material_index })
+        Ok(Self {
+            positions,
+            normals,
+            texcoords,
+            indices,
+            transform: transform.matrix(),
+            transform_end,
+            material_index,
+        })
     }
 
-    pub fn triangles(&self) -> Vec<Triangle> {
+    /// Triangles of the mesh in model space.
+    pub fn triangles(&self, mesh_index: usize) -> Vec<Triangle> {
         self.indices
             .iter()
             .tuples::<(_, _, _)>()
-            .map(|(&a, &b, &c)| Triangle {
-                positions:      [self.positions[a as usize], self.positions[b as usize], self.positions[c as usize]],
-                normals:        [self.normals[a as usize], self.normals[b as usize], self.normals[c as usize]],
-                texcoords:      [self.texcoords[a as usize], self.texcoords[b as usize], self.texcoords[c as usize]],
+            .enumerate()
+            .map(|(mesh_triangle, (&a, &b, &c))| Triangle {
+                positions: [self.positions[a as usize], self.positions[b as usize], self.positions[c as usize]],
+                normals: [self.normals[a as usize], self.normals[b as usize], self.normals[c as usize]],
+                texcoords: [self.texcoords[a as usize], self.texcoords[b as usize], self.texcoords[c as usize]],
                 material_index: self.material_index,
+                mesh_index,
+                mesh_triangle,
+                motion: [glm::Vec3::zeros(); 3],
             })
             .collect()
     }
+
+    /// Triangles of the mesh in world space.
+    pub fn transformed_triangles(&self, mesh_index: usize) -> Vec<Triangle> {
+        self.triangles(mesh_index)
+            .iter()
+            .map(|t| {
+                let mut triangle = t.transformed(&self.transform);
+                if let Some(transform_end) = &self.transform_end {
+                    let end = t.transformed(transform_end).positions;
+                    triangle.motion = [0, 1, 2].map(|i| end[i] - triangle.positions[i]);
+                }
+                triangle
+            })
+            .collect()
+    }
+
+    pub fn transformed_positions(&self) -> Vec<glm::Vec3> {
+        self.positions.iter().map(|p| (self.transform * glm::vec4(p.x, p.y, p.z, 1.0)).xyz()).collect()
+    }
+
+    pub fn transformed_normals(&self) -> Vec<glm::Vec3> {
+        let normal_transform = glm::transpose(&glm::inverse(&self.transform));
+        self.normals.iter().map(|n| (normal_transform * glm::vec4(n.x, n.y, n.z, 1.0)).xyz()).collect()
+    }
 }
 
 impl Triangle {
+    /// Bounds of the triangle over the whole shutter interval. Vertices move in straight lines, so bounding their
+    /// start and end positions is enough.
     pub fn bounds(&self) -> BoundingBox {
         let [a, b, c] = self.positions;
+        let [d, e, f] = [0, 1, 2].map(|i| self.positions[i] + self.motion[i]);
         BoundingBox {
-            min: glm::vec3(a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y), a.z.min(b.z).min(c.z)),
-            max: glm::vec3(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z)),
+            min: glm::min3(&a, &b, &c).inf(&glm::min3(&d, &e, &f)),
+            max: glm::max3(&a, &b, &c).sup(&glm::max3(&d, &e, &f)),
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        let [a, b, c] = self.positions;
+        0.5 * glm::cross(&(b - a), &(c - a)).norm()
+    }
+
+    pub fn transformed(&self, transform: &glm::Mat4x4) -> Self {
+        let normal_transform = glm::transpose(&glm::inverse(transform));
+        Self {
+            positions: self.positions.map(|p| (transform * glm::vec4(p.x, p.y, p.z, 1.0)).xyz()),
+            normals: self.normals.map(|n| (normal_transform * glm::vec4(n.x, n.y, n.z, 1.0)).xyz()),
+            ..self.clone()
         }
     }
 
@@ -151,3 +237,19 @@ impl Triangle {
         }
     }
 }
+
+#[cfg(test)]
+impl Triangle {
+    /// A still triangle with the given corners and nothing else set, for the tests.
+    pub(crate) fn with_positions(a: glm::Vec3, b: glm::Vec3, c: glm::Vec3) -> Self {
+        Self {
+            positions:      [a, b, c],
+            normals:        [glm::Vec3::zeros(); 3],
+            texcoords:      [glm::Vec2::zeros(); 3],
+            material_index: 0,
+            mesh_index:     0,
+            mesh_triangle:  0,
+            motion:         [glm::Vec3::zeros(); 3],
+        }
+    }
+}
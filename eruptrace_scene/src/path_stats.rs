@@ -0,0 +1,27 @@
+/// Number of buckets in the histogram of path lengths. The last one also counts all longer paths.
+pub const PATH_LENGTH_BUCKETS: usize = 64;
+
+/// Histogram of the number of rays traced along each path in a render, counted by the shaders.
+#[derive(Clone, Debug, Default)]
+pub struct PathStats {
+    pub length_counts: Vec<u32>,
+}
+
+impl PathStats {
+    pub fn path_count(&self) -> u64 {
+        self.length_counts.iter().map(|&count| count as u64).sum()
+    }
+
+    pub fn average_length(&self) -> f32 {
+        let total_length: u64 =
+            self.length_counts.iter().enumerate().map(|(length, &count)| length as u64 * count as u64).sum();
+        match self.path_count() {
+            0 => 0.0,
+            paths => (total_length as f64 / paths as f64) as f32,
+        }
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.length_counts.iter().rposition(|&count| count > 0).unwrap_or(0)
+    }
+}
@@ -0,0 +1,202 @@
+use std::f32::consts::PI;
+
+use image::Rgba32FImage;
+use nalgebra_glm as glm;
+use serde_json as json;
+
+use crate::{json::to_vec3, Light, LightType};
+
+/// Size of the environment map the sky is baked into.
+pub const SKY_MAP_WIDTH: u32 = 512;
+pub const SKY_MAP_HEIGHT: u32 = 256;
+
+/// Scales the model's luminance, given in kcd/m², to the range of the renderer's other light sources.
+const LUMINANCE_SCALE: f32 = 0.1;
+
+/// Coefficients of the Perez sky distribution for the luminance and the chromaticity, linear in the turbidity.
+const PEREZ_LUMINANCE: [[f32; 2]; 5] =
+    [[0.1787, -1.4630], [-0.3554, 0.4275], [-0.0227, 5.3251], [0.1206, -2.5771], [-0.0670, 0.3703]];
+const PEREZ_X: [[f32; 2]; 5] =
+    [[-0.0193, -0.2592], [-0.0665, 0.0008], [-0.0004, 0.2125], [-0.0641, -0.8989], [-0.0033, 0.0452]];
+const PEREZ_Y: [[f32; 2]; 5] =
+    [[-0.0167, -0.2608], [-0.0950, 0.0092], [-0.0079, 0.2102], [-0.0441, -1.6537], [-0.0109, 0.0529]];
+
+/// Coefficients of the zenith's chromaticity, cubic in the sun's zenith angle and quadratic in the turbidity.
+const ZENITH_X: [[f32; 4]; 3] =
+    [[0.00166, -0.00375, 0.00209, 0.0], [-0.02903, 0.06377, -0.03202, 0.00394], [0.11693, -0.21196, 0.06052, 0.25886]];
+const ZENITH_Y: [[f32; 4]; 3] =
+    [[0.00275, -0.00610, 0.00317, 0.0], [-0.04214, 0.08970, -0.04153, 0.00516], [0.15346, -0.26756, 0.06670, 0.26688]];
+
+/// Analytic daylight sky (Preetham, Shirley, Smits 1999), optionally lit by the sun as a directional light.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sky {
+    /// Unit vector pointing towards the sun.
+    pub sun_direction: glm::Vec3,
+    /// Haziness of the atmosphere, from about 2 for a clear sky to 10 for a hazy one.
+    pub turbidity:     f32,
+    /// Colour of the ground below the horizon, lit by the sky and the sun.
+    pub ground_albedo: glm::Vec3,
+    /// Irradiance from the sun onto a surface facing it, before atmospheric attenuation. Zero disables the sun.
+    pub sun_intensity: f32,
+    /// Angular diameter of the sun's disc, in radians.
+    pub sun_size:      f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            sun_direction: glm::normalize(&glm::vec3(0.0, 1.0, 1.0)),
+            turbidity:     3.0,
+            ground_albedo: glm::vec3(0.3, 0.3, 0.3),
+            sun_intensity: 0.0,
+            sun_size:      0.53_f32.to_radians(),
+        }
+    }
+}
+
+impl Sky {
+    pub fn from_json(object: &json::Value) -> Self {
+        let default = Self::default();
+        let sun = &object["sun"];
+        Self {
+            sun_direction: to_vec3(&object["sun_direction"]).map_or(default.sun_direction, |d| glm::normalize(&d)),
+            turbidity:     object["turbidity"].as_f64().map_or(default.turbidity, |t| t as f32),
+            ground_albedo: match &object["ground_albedo"] {
+                json::Value::Number(n) => glm::Vec3::repeat(n.as_f64().unwrap() as f32),
+                albedo => to_vec3(albedo).unwrap_or(default.ground_albedo),
+            },
+            sun_intensity: sun["intensity"].as_f64().map_or(if sun.is_object() { 1.0 } else { 0.0 }, |i| i as f32),
+            sun_size:      sun["size"].as_f64().map_or(default.sun_size, |s| (s as f32).to_radians()),
+        }
+    }
+
+    pub fn has_sun(&self) -> bool {
+        self.sun_intensity > 0.0 && self.sun_direction.y > 0.0
+    }
+
+    /// The sun as a directional light, with the colour it has after passing through the atmosphere.
+    pub fn sun_light(&self) -> Option<Light> {
+        self.has_sun().then(|| Light {
+            light_type: LightType::Directional,
+            color:      self.sun_transmittance(),
+            intensity:  self.sun_intensity,
+            position:   glm::Vec3::zeros(),
+            direction:  -self.sun_direction,
+            cos_inner:  1.0,
+            cos_outer:  1.0,
+            edge_u:     glm::Vec3::zeros(),
+            edge_v:     glm::Vec3::zeros(),
+        })
+    }
+
+    /// Radiance of the sun's disc, such that it gives the same irradiance as `sun_light`.
+    pub fn sun_radiance(&self) -> glm::Vec3 {
+        match self.has_sun() {
+            true => self.sun_transmittance() * self.sun_intensity / self.sun_solid_angle(),
+            false => glm::Vec3::zeros(),
+        }
+    }
+
+    pub fn sun_cos_radius(&self) -> f32 {
+        (0.5 * self.sun_size).cos()
+    }
+
+    fn sun_solid_angle(&self) -> f32 {
+        2.0 * PI * (1.0 - self.sun_cos_radius())
+    }
+
+    /// Fraction of the sun's light in the red, green and blue wavelengths reaching the ground, accounting for
+    /// Rayleigh scattering and scattering on aerosols (Ångström's formula).
+    fn sun_transmittance(&self) -> glm::Vec3 {
+        let zenith_angle = self.sun_direction.y.clamp(0.0, 1.0).acos();
+        // Relative optical air mass (Kasten, Young 1989).
+        let air_mass = 1.0 / (zenith_angle.cos() + 0.50572 * (96.07995 - zenith_angle.to_degrees()).powf(-1.6364));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let wavelengths = glm::vec3(0.680, 0.550, 0.440);
+        wavelengths.map(|lambda: f32| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosols = beta * lambda.powf(-1.3);
+            (-air_mass * (rayleigh + aerosols)).exp()
+        })
+    }
+
+    /// Radiance of the sky coming from the given direction above the horizon, excluding the sun's disc.
+    pub fn radiance(&self, direction: &glm::Vec3) -> glm::Vec3 {
+        let t = self.turbidity;
+        // The model breaks down with the sun below the horizon.
+        let sun_theta = self.sun_direction.y.clamp(0.0, 1.0).acos().min(0.5 * PI - 1e-3);
+        let theta = direction.y.clamp(0.0, 1.0).acos().min(0.5 * PI - 1e-3);
+        let gamma = glm::dot(direction, &self.sun_direction).clamp(-1.0, 1.0).acos();
+
+        let perez = |[a, b, c, d, e]: [f32; 5], theta: f32, gamma: f32| {
+            (1.0 + a * (b / theta.cos()).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+        };
+        let distribution = |coefficients: [f32; 5], zenith: f32| {
+            zenith * perez(coefficients, theta, gamma) / perez(coefficients, 0.0, sun_theta)
+        };
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * sun_theta);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let zenith_chromaticity = |coefficients: [[f32; 4]; 3]| {
+            let [t2, t1, t0] = coefficients
+                .map(|[c3, c2, c1, c0]| c3 * sun_theta.powi(3) + c2 * sun_theta.powi(2) + c1 * sun_theta + c0);
+            t2 * t * t + t1 * t + t0
+        };
+        let perez_coefficients = |coefficients: [[f32; 2]; 5]| coefficients.map(|[slope, offset]| slope * t + offset);
+
+        let luminance = distribution(perez_coefficients(PEREZ_LUMINANCE), zenith_luminance);
+        let x = distribution(perez_coefficients(PEREZ_X), zenith_chromaticity(ZENITH_X));
+        let y = distribution(perez_coefficients(PEREZ_Y), zenith_chromaticity(ZENITH_Y));
+
+        xyy_to_linear_srgb(x, y, luminance * LUMINANCE_SCALE)
+    }
+
+    /// Bakes the sky into an equirectangular map laid out like `mappingOnUnitSphere` in the shaders. Directions
+    /// below the horizon see the ground, lit by the sky and the sun as a diffuse surface.
+    pub fn bake(&self) -> Rgba32FImage {
+        let (width, height) = (SKY_MAP_WIDTH, SKY_MAP_HEIGHT);
+        let texel_solid_angle = |row: u32| {
+            let elevation = PI * (0.5 - (row as f32 + 0.5) / height as f32);
+            elevation.cos() * (2.0 * PI / width as f32) * (PI / height as f32)
+        };
+
+        let mut texels = vec![0.0; (4 * width * height) as usize];
+        let mut irradiance = glm::Vec3::zeros();
+        for row in 0..height / 2 {
+            for column in 0..width {
+                let direction = map_direction((column as f32 + 0.5) / width as f32, (row as f32 + 0.5) / height as f32);
+                let radiance = self.radiance(&direction);
+                irradiance += radiance * direction.y * texel_solid_angle(row);
+                let i = (4 * (row * width + column)) as usize;
+                texels[i..i + 4].copy_from_slice(&[radiance.x, radiance.y, radiance.z, 1.0]);
+            }
+        }
+        if let Some(sun) = self.sun_light() {
+            irradiance += sun.color * sun.intensity * self.sun_direction.y;
+        }
+
+        let ground = self.ground_albedo.component_mul(&irradiance) / PI;
+        for texel in texels.chunks_mut(4).skip((width * height / 2) as usize) {
+            texel.copy_from_slice(&[ground.x, ground.y, ground.z, 1.0]);
+        }
+
+        Rgba32FImage::from_raw(width, height, texels).unwrap()
+    }
+}
+
+/// Direction seen at the given texture coordinates of an equirectangular map, the inverse of
+/// `mappingOnUnitSphere` in the shaders.
+fn map_direction(u: f32, v: f32) -> glm::Vec3 {
+    let azimuth = (1.0 - u) * 2.0 * PI - PI;
+    let elevation = (1.0 - v) * PI - 0.5 * PI;
+    glm::vec3(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin())
+}
+
+fn xyy_to_linear_srgb(x: f32, y: f32, luminance: f32) -> glm::Vec3 {
+    if y <= 0.0 {
+        return glm::Vec3::zeros();
+    }
+    let xyz = glm::vec3(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    let rgb = glm::mat3(3.2406, -1.5372, -0.4986, -0.9689, 1.8758, 0.0415, 0.0557, -0.2040, 1.0570) * xyz;
+    rgb.map(|c| c.max(0.0))
+}
@@ -0,0 +1,198 @@
+use std::{collections::HashMap, ops::Range};
+
+use itertools::Itertools;
+use nalgebra_glm as glm;
+
+use crate::{
+    Bih,
+    BihNode,
+    BihNodeUniform,
+    BvhNode,
+    BvhNodeUniform,
+    EmitterTable,
+    RtSceneBuffers,
+    Scene,
+    Triangle,
+    TriangleUniform,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RebuildPolicy {
+    /// Only refit the hierarchies.
+    Never,
+    /// Rebuild the part of the BIH covering the moved triangles once its SAH cost exceeds the given multiple of
+    /// the cost it had right after being built.
+    SahThreshold(f32),
+}
+
+/// Ranges of scene data modified by an update, which have to be uploaded to the GPU again.
+#[derive(Clone, Debug, Default)]
+pub struct SceneUpdate {
+    /// Meshes whose vertices have moved.
+    pub meshes:      Vec<usize>,
+    pub triangles:   Vec<Range<usize>>,
+    pub bih_nodes:   Vec<Range<usize>>,
+    pub bvh_nodes:   Vec<Range<usize>>,
+    pub bvh_indices: Vec<Range<usize>>,
+    pub bih_rebuilt: bool,
+    /// Whether the emitter table has changed, as emissive triangles have moved or been reordered.
+    pub emitters:    bool,
+}
+
+impl Scene {
+    pub fn update_mesh_transform(
+        &mut self,
+        mesh_index: usize,
+        transform: glm::Mat4x4,
+        policy: RebuildPolicy,
+    ) -> SceneUpdate {
+        let mesh = &mut self.meshes[mesh_index];
+        mesh.transform = transform;
+        let mesh_triangles = mesh.transformed_triangles(mesh_index);
+
+        let mut changed_triangles = vec![];
+        for (index, triangle) in self.triangles.iter_mut().enumerate().filter(|(_, t)| t.mesh_index == mesh_index) {
+            *triangle = mesh_triangles[triangle.mesh_triangle].clone();
+            changed_triangles.push(index);
+        }
+        if changed_triangles.is_empty() {
+            return SceneUpdate::default();
+        }
+
+        let old_bih_nodes = self.bih.nodes.clone();
+        let old_bvh_nodes = self.bvh.nodes.clone();
+        let old_bvh_indices = self.bvh.triangle_indices.clone();
+
+        self.bih.refit(&self.triangles);
+
+        let mut bih_rebuilt = false;
+        if let RebuildPolicy::SahThreshold(threshold) = policy {
+            let degraded = |bih: &Bih| bih.stats().sah_cost > threshold * bih.built_sah_cost;
+            if degraded(&self.bih) {
+                let moved_range = changed_triangles[0]..changed_triangles[changed_triangles.len() - 1] + 1;
+                let mut subtree = self.bih.enclosing_node(moved_range);
+                changed_triangles.extend(self.rebuild_bih_subtree(subtree));
+
+                // The nodes above the rebuilt subtree can still be stretched over the old and new positions of the
+                // mesh, in which case only rebuilding the whole tree helps.
+                if subtree != 0 && degraded(&self.bih) {
+                    subtree = 0;
+                    changed_triangles.extend(self.rebuild_bih_subtree(subtree));
+                }
+                if subtree == 0 {
+                    self.bih.built_sah_cost = self.bih.stats().sah_cost;
+                }
+                bih_rebuilt = true;
+            }
+        }
+
+        self.bvh.refit(&self.triangles);
+
+        let emitters = EmitterTable::new(&self.triangles, &self.materials);
+        let emitters_changed = emitters != self.emitters;
+        self.emitters = emitters;
+
+        SceneUpdate {
+            meshes: vec![mesh_index],
+            triangles: to_ranges(changed_triangles),
+            bih_nodes: changed_ranges(&old_bih_nodes, &self.bih.nodes),
+            bvh_nodes: changed_ranges(&old_bvh_nodes, &self.bvh.nodes),
+            bvh_indices: changed_ranges(&old_bvh_indices, &self.bvh.triangle_indices),
+            bih_rebuilt,
+            emitters: emitters_changed,
+        }
+    }
+
+    /// Rebuilds a BIH subtree and returns the range of triangles it reordered.
+    fn rebuild_bih_subtree(&mut self, subtree: usize) -> Range<usize> {
+        let triangle_range = self.bih.triangle_range(subtree);
+        let old_order =
+            self.triangles[triangle_range.clone()].iter().map(|t| (t.mesh_index, t.mesh_triangle)).collect_vec();
+
+        self.bih.rebuild_subtree(&mut self.triangles, subtree);
+
+        // The BVH refers to the triangles by index, so it has to follow them to their new places.
+        let new_indices: HashMap<_, _> = self.triangles[triangle_range.clone()]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| ((t.mesh_index, t.mesh_triangle), triangle_range.start + i))
+            .collect();
+        self.bvh.remap_triangles(|index| {
+            if triangle_range.contains(&index) {
+                new_indices[&old_order[index - triangle_range.start]]
+            } else {
+                index
+            }
+        });
+
+        triangle_range
+    }
+}
+
+impl RtSceneBuffers {
+    /// Uploads the parts of the scene modified by an update.
+    pub fn apply_update(&self, scene: &Scene, update: &SceneUpdate) {
+        if self.triangle_layout.is_indexed() {
+            let vertex_offsets = scene.vertex_offsets();
+            for &mesh_index in update.meshes.iter() {
+                let offset = vertex_offsets[mesh_index];
+                let normals = scene.vertex_normals(mesh_index, self.triangle_layout);
+                let normals_start = offset * self.triangle_layout.normal_stride() * size_of::<u32>();
+                self.vertex_positions_buffer
+                    .set_data_at(offset * 3 * size_of::<f32>(), &scene.vertex_positions(mesh_index));
+                self.vertex_normals_buffer.set_data_at(normals_start, &normals);
+            }
+            for range in update.triangles.iter().cloned() {
+                let start = range.start * size_of::<[u32; 4]>();
+                self.triangle_indices_buffer.set_data_at(start, &scene.triangle_indices(range));
+            }
+        } else {
+            for range in update.triangles.iter().cloned() {
+                let triangles =
+                    scene.triangles[range.clone()].iter().cloned().map(Triangle::into_uniform).collect_vec();
+                self.triangles_buffer.set_data_at(range.start * size_of::<TriangleUniform>(), &triangles);
+            }
+        }
+        if scene.has_motion() {
+            for range in update.triangles.iter().cloned() {
+                let start = 3 * range.start * size_of::<[f32; 4]>();
+                self.triangle_motion_buffer.set_data_at(start, &scene.triangle_motion(range));
+            }
+        }
+        for range in update.triangles.iter().cloned() {
+            let start = range.start * size_of::<u32>();
+            self.triangle_objects_buffer.set_data_at(start, &scene.triangle_objects(range));
+        }
+        for range in update.bih_nodes.iter().cloned() {
+            let nodes = scene.bih.nodes[range.clone()].iter().copied().map(BihNode::into_uniform).collect_vec();
+            self.bih_buffer.set_data_at(range.start * size_of::<BihNodeUniform>(), &nodes);
+        }
+        for range in update.bvh_nodes.iter().cloned() {
+            let nodes = scene.bvh.nodes[range.clone()].iter().copied().map(BvhNode::into_uniform).collect_vec();
+            self.bvh_buffer.set_data_at(range.start * size_of::<BvhNodeUniform>(), &nodes);
+        }
+        for range in update.bvh_indices.iter().cloned() {
+            self.bvh_indices_buffer.set_data_at(range.start * size_of::<u32>(), &scene.bvh.triangle_indices[range]);
+        }
+        if update.emitters {
+            self.emitters_buffer.set_data_at(0, &scene.emitters.uniforms());
+        }
+    }
+}
+
+fn to_ranges(mut indices: Vec<usize>) -> Vec<Range<usize>> {
+    indices.sort_unstable();
+    indices.dedup();
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for index in indices {
+        match ranges.last_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+fn changed_ranges<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Range<usize>> {
+    to_ranges((0..new.len()).filter(|&i| old.get(i) != Some(&new[i])).collect())
+}
@@ -66,6 +66,22 @@ impl<T: Sized> AllocatedBuffer<T> {
         self.allocator.read().unwrap().unmap_memory(&self.allocation);
     }
 
+    /// Copies the buffer's contents, e.g. written by shaders, into a vector.
+    pub fn read_data(&self) -> Vec<T>
+    where
+        T: Copy,
+    {
+        let allocator = self.allocator.read().unwrap();
+        allocator.invalidate_allocation(&self.allocation, 0, self.size as usize);
+        let buffer_addr = allocator.map_memory(&self.allocation).expect("Cannot map allocated memory");
+        assert_ne!(buffer_addr, std::ptr::null_mut());
+        let data = unsafe {
+            std::slice::from_raw_parts(buffer_addr as *const T, self.size as usize / size_of::<T>()).to_vec()
+        };
+        allocator.unmap_memory(&self.allocation);
+        data
+    }
+
     pub fn destroy(&self) {
         self.allocator.read().unwrap().destroy_buffer(self.buffer, &self.allocation);
     }
@@ -1,8 +1,18 @@
+use std::time::Duration;
+
 use erupt::{vk, DeviceLoader};
 
 use crate::VulkanContext;
 
-pub fn immediate_submit<F>(vk_ctx: VulkanContext, execute_commands: F)
+/// How long an immediate submission may run before it's given up on, unless the context sets otherwise. Long enough
+/// for a heavy tile, and short enough not to leave the desktop unresponsive for long.
+pub const DEFAULT_SUBMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Records commands into a fresh command buffer, submits it and waits for it to finish.
+///
+/// Returns `vk::Result::TIMEOUT` if the commands haven't finished within the context's `submit_timeout`, after waiting
+/// for the device to go idle so that the upload fence and the command pool can be reused by the next submission.
+pub fn immediate_submit<F>(vk_ctx: VulkanContext, execute_commands: F) -> Result<(), vk::Result>
 where
     F: FnOnce(&DeviceLoader, vk::CommandBuffer),
 {
@@ -23,17 +33,39 @@ where
     let submit_info = vk::SubmitInfoBuilder::new().command_buffers(&command_buffers);
     unsafe {
         vk_ctx.device.queue_submit(vk_ctx.queue, &[submit_info], vk_ctx.upload_fence).expect("Cannot submit queue");
-        vk_ctx.device.wait_for_fences(&[vk_ctx.upload_fence], true, 9999999999).expect("Cannot wait for upload fence");
+        let timeout = vk_ctx.submit_timeout.as_nanos().min(u64::MAX as u128) as u64;
+        let wait = vk_ctx.device.wait_for_fences(&[vk_ctx.upload_fence], true, timeout);
+        let timed_out = wait.raw == vk::Result::TIMEOUT;
+        if timed_out {
+            // The commands keep running, and the driver either finishes them or loses the device.
+            vk_ctx.device.device_wait_idle().result()?;
+        } else {
+            wait.result()?;
+        }
         vk_ctx.device.reset_fences(&[vk_ctx.upload_fence]).expect("Cannot reset upload fence");
         vk_ctx
             .device
             .reset_command_pool(vk_ctx.command_pool, vk::CommandPoolResetFlags::empty())
             .expect("Cannot reset command pool");
+        if timed_out {
+            return Err(vk::Result::TIMEOUT);
+        }
     }
+    Ok(())
 }
 
 pub unsafe fn set_scissor_and_viewport(device: &DeviceLoader, command_buffer: vk::CommandBuffer, extent: vk::Extent2D) {
-    device.cmd_set_scissor(command_buffer, 0, &[vk::Rect2DBuilder::new().extent(extent)]);
+    set_tile_scissor_and_viewport(device, command_buffer, extent, vk::Rect2D { offset: Default::default(), extent });
+}
+
+/// Sets the viewport to the whole image, but limits drawing to a single tile of it.
+pub unsafe fn set_tile_scissor_and_viewport(
+    device: &DeviceLoader,
+    command_buffer: vk::CommandBuffer,
+    extent: vk::Extent2D,
+    tile: vk::Rect2D,
+) {
+    device.cmd_set_scissor(command_buffer, 0, &[vk::Rect2DBuilder::new().offset(tile.offset).extent(tile.extent)]);
     device.cmd_set_viewport(command_buffer, 0, &[vk::ViewportBuilder::new()
         .width(extent.width as _)
         .height(extent.height as _)
@@ -1,15 +1,20 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use erupt::{vk, DeviceLoader};
 use vk_mem_3_erupt as vma;
 
 #[derive(Clone)]
 pub struct VulkanContext {
-    pub device:       Arc<DeviceLoader>,
-    pub allocator:    Arc<RwLock<vma::Allocator>>,
-    pub queue:        vk::Queue,
-    pub command_pool: vk::CommandPool,
-    pub upload_fence: vk::Fence,
+    pub device:         Arc<DeviceLoader>,
+    pub allocator:      Arc<RwLock<vma::Allocator>>,
+    pub queue:          vk::Queue,
+    pub command_pool:   vk::CommandPool,
+    pub upload_fence:   vk::Fence,
+    /// How long to wait for an immediate submission before giving up on it.
+    pub submit_timeout: Duration,
 }
 
 #[derive(Copy, Clone)]
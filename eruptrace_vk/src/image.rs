@@ -61,7 +61,8 @@ impl AllocatedImage {
                         .image(image)
                         .subresource_range(subresource_range)]),
                 );
-            });
+            })
+            .expect("Cannot transition image layout");
         }
 
         Self {
@@ -281,12 +282,13 @@ impl AllocatedImage {
                     .image(self.image)
                     .subresource_range(self.subresource_range)]),
             );
-        });
+        })
+        .expect("Cannot upload image data");
 
         image_buffer.destroy();
     }
 
-    pub fn copy_to_buffer<T>(&self, vk_ctx: VulkanContext, buffer: &AllocatedBuffer<T>) {
+    pub fn copy_to_buffer<T>(&self, vk_ctx: VulkanContext, buffer: &AllocatedBuffer<T>) -> Result<(), vk::Result> {
         command::immediate_submit(vk_ctx, |device, command_buffer| unsafe {
             device.cmd_pipeline_barrier2(
                 command_buffer,
@@ -320,6 +322,6 @@ impl AllocatedImage {
                         )
                         .image_extent(self.extent)]),
             );
-        });
+        })
     }
 }
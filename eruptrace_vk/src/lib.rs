@@ -9,7 +9,10 @@ pub mod pipeline;
 pub mod push_constants;
 pub mod shader;
 pub mod std140;
+pub mod targets;
+pub mod tiles;
 
 pub use buffer::AllocatedBuffer;
 pub use contexts::VulkanContext;
 pub use image::AllocatedImage;
+pub use targets::RenderTargets;
@@ -267,14 +267,26 @@ impl Pipeline {
         device: &DeviceLoader,
         descriptor_sets_infos: &[DescriptorSetCreateInfo],
     ) -> vk::DescriptorPool {
-        let sizes = descriptor_sets_infos
+        // The sets are allocated once each, so the pool only needs room for exactly as many descriptors as they hold.
+        let mut counts: Vec<(vk::DescriptorType, u32)> = vec![];
+        for descriptor_type in descriptor_sets_infos
             .iter()
             .flat_map(|set_info| set_info.descriptor_infos.iter())
             .map(|bind_info| bind_info.descriptor_type)
-            .dedup()
-            .map(|descriptor_type| vk::DescriptorPoolSizeBuilder::new()._type(descriptor_type).descriptor_count(10))
+        {
+            match counts.iter_mut().find(|(t, _)| *t == descriptor_type) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((descriptor_type, 1)),
+            }
+        }
+        let sizes = counts
+            .into_iter()
+            .map(|(descriptor_type, count)| {
+                vk::DescriptorPoolSizeBuilder::new()._type(descriptor_type).descriptor_count(count)
+            })
             .collect_vec();
-        let create_info = vk::DescriptorPoolCreateInfoBuilder::new().max_sets(10).pool_sizes(&sizes);
+        let create_info =
+            vk::DescriptorPoolCreateInfoBuilder::new().max_sets(descriptor_sets_infos.len() as u32).pool_sizes(&sizes);
         unsafe { device.create_descriptor_pool(&create_info, None).expect("Cannot create descriptor pool") }
     }
 
@@ -410,6 +422,19 @@ impl<'a> DescriptorBindingCreateInfo<'a> {
             sampler_index: Some(sampler_index),
         }
     }
+
+    pub fn storage_image(
+        shader_stage_flags: vk::ShaderStageFlags,
+        image_info: vk::DescriptorImageInfoBuilder<'a>,
+    ) -> Self {
+        Self {
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            shader_stage_flags,
+            buffer_info: None,
+            image_info: Some(image_info),
+            sampler_index: None,
+        }
+    }
 }
 
 impl SamplerCreateInfo {
@@ -6,15 +6,38 @@ bitflags! {
         const USE_BIH = 1 << 0;
         const RENDER_NORMALS = 1 << 1;
         const RENDER_BIH = 1 << 2;
+        const USE_BVH = 1 << 3;
+        const INDEXED_TRIANGLES = 1 << 4;
+        const COMPACT_VERTICES = 1 << 5;
+        const RUSSIAN_ROULETTE = 1 << 6;
+        const MOTION_BLUR = 1 << 7;
+        const CROP = 1 << 8;
+        const ADAPTIVE_SAMPLING = 1 << 9;
+        const SAMPLE_HEATMAP = 1 << 10;
+        const AOVS = 1 << 11;
+        const TRANSPARENT_BACKGROUND = 1 << 12;
     }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct RtPushConstants {
-    pub n_triangles:    u32,
-    pub flags:          RtFlags,
-    pub draw_bih_level: u32,
+    pub n_triangles:        u32,
+    pub flags:              RtFlags,
+    pub draw_bih_node:      u32,
+    pub n_lights:           u32,
+    pub n_emitters:         u32,
+    pub emitter_power:      f32,
+    /// Number of rays traced along a path before Russian roulette starts terminating it.
+    pub rr_min_bounces:     u32,
+    /// Pixels of the render target traced when cropping, as their x, y, width and height.
+    pub crop_region:        [u32; 4],
+    /// Relative error of a pixel's mean below which adaptive sampling stops taking more samples for it.
+    pub adaptive_threshold: f32,
+    /// Number of samples adaptive sampling takes at most for a pixel.
+    pub max_samples:        u32,
+    /// Index of the pass over the image, where only the first one samples every pixel when sampling adaptively.
+    pub sample_pass:        u32,
 }
 
 #[repr(C)]
@@ -22,3 +45,15 @@ pub struct RtPushConstants {
 pub struct GuiPushConstants {
     pub screen_size: glm::Vec2,
 }
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DenoiserPushConstants {
+    /// Pixels of the images being denoised, as their x, y, width and height.
+    pub area:         [u32; 4],
+    pub iteration:    u32,
+    pub iterations:   u32,
+    pub sigma_colour: f32,
+    pub sigma_normal: f32,
+    pub sigma_depth:  f32,
+}
@@ -0,0 +1,133 @@
+use erupt::{vk, DeviceLoader};
+
+use crate::{pipeline::ColorAttachmentInfo, AllocatedImage, VulkanContext};
+
+/// Images the ray tracers render into, one for each output of their fragment shaders, holding linear values.
+#[derive(Clone)]
+pub struct RenderTargets {
+    pub colour:       AllocatedImage,
+    /// Reflectance of the first surface seen through each pixel.
+    pub albedo:       AllocatedImage,
+    /// Shading normal of the first surface seen through each pixel, and its distance from the camera in the last
+    /// component, which is zero where nothing has been hit.
+    pub normal_depth: AllocatedImage,
+    /// Only created when the arbitrary output variables are rendered, and discarded by the shaders otherwise.
+    pub aovs:         Option<AovTargets>,
+}
+
+/// Arbitrary output variables rendered alongside the colours, for compositing and debugging.
+#[derive(Clone)]
+pub struct AovTargets {
+    /// World space position of the first surface seen through each pixel.
+    pub position: AllocatedImage,
+    /// Indices of the material and of the mesh of the first surface seen through each pixel in the first two
+    /// components, which are -1 where nothing has been hit.
+    pub ids:      AllocatedImage,
+    /// Light which has bounced off exactly one surface on its way to the camera.
+    pub direct:   AllocatedImage,
+    /// Light which has bounced off more than one surface.
+    pub indirect: AllocatedImage,
+    /// Light coming straight from the emitters and the environment.
+    pub emission: AllocatedImage,
+}
+
+impl RenderTargets {
+    /// Number of the fragment shaders' outputs.
+    pub const COUNT: usize = 8;
+    pub const FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
+    pub fn new(vk_ctx: VulkanContext, extent: vk::Extent2D, with_aovs: bool) -> Self {
+        let make_target = || {
+            let image_info = vk::ImageCreateInfoBuilder::new()
+                .usage(
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                )
+                .format(Self::FORMAT)
+                .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlagBits::_1)
+                .image_type(vk::ImageType::_2D);
+
+            let range = vk::ImageSubresourceRangeBuilder::new()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .base_array_layer(0)
+                .level_count(1)
+                .layer_count(1)
+                .build();
+
+            AllocatedImage::new(vk_ctx.clone(), image_info, None, vk::ImageViewType::_2D, range)
+        };
+
+        Self {
+            colour:       make_target(),
+            albedo:       make_target(),
+            normal_depth: make_target(),
+            aovs:         with_aovs.then(|| AovTargets {
+                position: make_target(),
+                ids:      make_target(),
+                direct:   make_target(),
+                indirect: make_target(),
+                emission: make_target(),
+            }),
+        }
+    }
+
+    /// Targets of each of the fragment shaders' output locations, which are missing for the AOVs not rendered.
+    pub fn slots(&self) -> [Option<&AllocatedImage>; Self::COUNT] {
+        let aovs = self.aovs.as_ref();
+        [
+            Some(&self.colour),
+            Some(&self.albedo),
+            Some(&self.normal_depth),
+            aovs.map(|aovs| &aovs.position),
+            aovs.map(|aovs| &aovs.ids),
+            aovs.map(|aovs| &aovs.direct),
+            aovs.map(|aovs| &aovs.indirect),
+            aovs.map(|aovs| &aovs.emission),
+        ]
+    }
+
+    /// The created render targets in the order of the fragment shaders' output locations.
+    pub fn images(&self) -> Vec<&AllocatedImage> {
+        self.slots().into_iter().flatten().collect()
+    }
+
+    /// Formats of the pipelines' colour attachments matching the render targets.
+    pub fn color_attachment_infos() -> Vec<ColorAttachmentInfo> {
+        vec![
+            ColorAttachmentInfo {
+                format:           Self::FORMAT,
+                color_write_mask: vk::ColorComponentFlags::all(),
+                blend_enable:     false,
+            };
+            Self::COUNT
+        ]
+    }
+
+    /// Attachments without an image view have the shaders' writes to them discarded.
+    pub fn create_colour_attachment_infos(&self) -> Vec<vk::RenderingAttachmentInfoBuilder> {
+        self.slots()
+            .into_iter()
+            .map(|target| {
+                vk::RenderingAttachmentInfoBuilder::new()
+                    .image_view(target.map_or_else(vk::ImageView::default, |target| target.view))
+                    .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .clear_value(vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } })
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            })
+            .collect()
+    }
+
+    pub fn destroy(&self, device: &DeviceLoader) {
+        for target in self.images() {
+            target.destroy(device);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use erupt::vk;
+use itertools::Itertools;
+
+use crate::{command, RenderTargets, VulkanContext};
+
+/// Splits an image into tiles at most `tile_size` pixels across, row by row starting from the top-left corner.
+pub fn split_into_tiles(extent: vk::Extent2D, tile_size: u32) -> Vec<vk::Rect2D> {
+    let tile_size = tile_size.max(1);
+    (0..extent.height)
+        .step_by(tile_size as usize)
+        .flat_map(|y| {
+            (0..extent.width).step_by(tile_size as usize).map(move |x| vk::Rect2D {
+                offset: vk::Offset2D { x: x as i32, y: y as i32 },
+                extent: vk::Extent2D {
+                    width:  tile_size.min(extent.width - x),
+                    height: tile_size.min(extent.height - y),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Prepares the targets for their tiles to be rendered into them one submission at a time, discarding their contents.
+pub fn begin_tiled_render(vk_ctx: VulkanContext, targets: &RenderTargets) -> Result<(), vk::Result> {
+    command::immediate_submit(vk_ctx, |device, command_buffer| unsafe {
+        let barriers = targets
+            .images()
+            .into_iter()
+            .map(|target| {
+                vk::ImageMemoryBarrier2Builder::new()
+                    .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                    .src_access_mask(vk::AccessFlags2::NONE)
+                    .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .image(target.image)
+                    .subresource_range(target.subresource_range)
+            })
+            .collect_vec();
+        device
+            .cmd_pipeline_barrier2(command_buffer, &vk::DependencyInfoBuilder::new().image_memory_barriers(&barriers));
+    })
+}
+
+/// Makes the targets ready to be copied from once all of their tiles have been rendered.
+pub fn finish_tiled_render(vk_ctx: VulkanContext, targets: &RenderTargets) -> Result<(), vk::Result> {
+    command::immediate_submit(vk_ctx, |device, command_buffer| unsafe {
+        let barriers = targets
+            .images()
+            .into_iter()
+            .map(|target| {
+                vk::ImageMemoryBarrier2Builder::new()
+                    .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE_KHR)
+                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                    .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .image(target.image)
+                    .subresource_range(target.subresource_range)
+            })
+            .collect_vec();
+        device
+            .cmd_pipeline_barrier2(command_buffer, &vk::DependencyInfoBuilder::new().image_memory_barriers(&barriers));
+    })
+}
@@ -0,0 +1,99 @@
+#ifndef CAMERA
+#define CAMERA
+
+#include <constants.glsl>
+#include <filter.glsl>
+#include <structs.glsl>
+#include <utils.glsl>
+
+const uint PROJECTION_PERSPECTIVE = 0;
+const uint PROJECTION_ORTHOGRAPHIC = 1;
+const uint PROJECTION_EQUIRECTANGULAR = 2;
+const uint PROJECTION_FISHEYE = 3;
+
+bool cameraRay(vec2 imageCoords, float seed, out Ray ray);
+vec2 sampleLens(float seed);
+vec2 imagePixelCoords(vec2 regionPixelCoords);
+
+// Ray through the given point of the rendered region, with (0, 0) in the bottom left corner and (1, 1) in the top
+// right one.
+// Perspective cameras with a lens start their rays at a random point on it, all of which converge on the plane in
+// focus. Rays are spread over the shutter interval. Returns false for points outside of a fisheye's image circle.
+bool cameraRay(vec2 imageCoords, float seed, out Ray ray) {
+    imageCoords = camera.regionOffset + (imageCoords * camera.regionScale);
+    vec3 pointOnPlane = (camera.bottomLeft + (imageCoords.x * camera.horizontal) + (imageCoords.y * camera.vertical)).xyz;
+    vec3 right = normalize(camera.horizontal.xyz);
+    vec3 up = normalize(camera.vertical.xyz);
+    vec3 forward = camera.forward.xyz;
+    ray.origin = camera.position.xyz;
+    ray.time = mix(camera.shutterOpen, camera.shutterClose, rand(seed + 23.f));
+
+    switch (camera.projection) {
+        case PROJECTION_PERSPECTIVE: {
+            vec2 pointOnLens = sampleLens(seed);
+            ray.origin += (pointOnLens.x * camera.lensHorizontal.xyz) + (pointOnLens.y * camera.lensVertical.xyz);
+            ray.direction = pointOnPlane - ray.origin;
+            break;
+        }
+        case PROJECTION_ORTHOGRAPHIC: {
+            ray.origin = pointOnPlane;
+            ray.direction = forward;
+            break;
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            float azimuth = (imageCoords.x - 0.5f) * TWO_PI;
+            float elevation = (imageCoords.y - 0.5f) * PI;
+            vec3 horizontal = (sin(azimuth) * right) + (cos(azimuth) * forward);
+            ray.direction = (cos(elevation) * horizontal) + (sin(elevation) * up);
+            break;
+        }
+        case PROJECTION_FISHEYE: {
+            vec2 fullSize = camera.imgSize / camera.regionScale;
+            vec2 fromCentre = (imageCoords - 0.5f) * vec2(fullSize.x / fullSize.y, 1.f);
+            float angle = 2.f * length(fromCentre) * (0.5f * camera.verticalFov);
+            if (angle > PI) {
+                return false;
+            }
+            vec2 side = length(fromCentre) > 0.f ? normalize(fromCentre) : vec2(0.f);
+            ray.direction = (sin(angle) * ((side.x * right) + (side.y * up))) + (cos(angle) * forward);
+            break;
+        }
+    }
+    ray.invDirection = 1.f / ray.direction;
+    return true;
+}
+
+// Uniformly distributed point on the unit disc, or on a regular polygon inscribed in it to shape the bokeh like the
+// camera's diaphragm.
+vec2 sampleLens(float seed) {
+    if (camera.lensRadius <= 0.f) {
+        return vec2(0.f);
+    }
+
+    vec2 random = vec2(rand(seed + 20.f), rand(seed + 21.f));
+    if (camera.blades < 3) {
+        float radius = sqrt(random.x);
+        float angle = TWO_PI * random.y;
+        return radius * vec2(cos(angle), sin(angle));
+    }
+
+    // Pick one of the triangles the polygon consists of, then a point in it.
+    float bladeAngle = TWO_PI / float(camera.blades);
+    float blade = min(floor(rand(seed + 22.f) * camera.blades), float(camera.blades - 1));
+    float angle = camera.bladeRotation + (blade * bladeAngle);
+    vec2 corner1 = vec2(cos(angle), sin(angle));
+    vec2 corner2 = vec2(cos(angle + bladeAngle), sin(angle + bladeAngle));
+    if (random.x + random.y > 1.f) {
+        random = 1.f - random;
+    }
+    return (random.x * corner1) + (random.y * corner2);
+}
+
+// Point of the whole image at the given point of the rendered region, both in pixels from their top left corners.
+vec2 imagePixelCoords(vec2 regionPixelCoords) {
+    vec2 fullSize = camera.imgSize / camera.regionScale;
+    vec2 regionTopLeft = vec2(camera.regionOffset.x, 1.f - camera.regionOffset.y - camera.regionScale.y) * fullSize;
+    return round(regionTopLeft) + regionPixelCoords;
+}
+
+#endif // CAMERA
@@ -3,7 +3,6 @@
 
 const float FLOAT_MAX = 3.402823466e+38f;
 const float FLOAT_MIN = 1.175494351e-38f;
-const float EPSILON = 1e-4f;
 const float PI = 3.1415926535897932384626433832795f;
 const float TWO_PI = 2.f * PI;
 const float HALF_PI = 0.5f * PI;
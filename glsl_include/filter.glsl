@@ -0,0 +1,54 @@
+#ifndef FILTER
+#define FILTER
+
+#include <constants.glsl>
+
+const uint FILTER_BOX = 0;
+const uint FILTER_TENT = 1;
+const uint FILTER_GAUSSIAN = 2;
+const uint FILTER_BLACKMAN_HARRIS = 3;
+const uint FILTER_MITCHELL_NETRAVALI = 4;
+
+float filterWeight(vec2 offset);
+float filterWeight1D(float offset);
+
+// Weight of a sample at the given offset from the pixel's centre, in pixels, under the camera's reconstruction
+// filter. All of the filters are separable.
+float filterWeight(vec2 offset) {
+    return filterWeight1D(offset.x) * filterWeight1D(offset.y);
+}
+
+float filterWeight1D(float offset) {
+    float radius = camera.filterRadius;
+    float x = abs(offset);
+    if (x > radius) {
+        return 0.f;
+    }
+
+    switch (camera.pixelFilter) {
+        case FILTER_TENT:
+            return radius - x;
+        case FILTER_GAUSSIAN: {
+            // Standard deviation of a third of the radius, shifted down to reach zero at the radius.
+            float falloff = 4.5f / (radius * radius);
+            return exp(-falloff * x * x) - exp(-falloff * radius * radius);
+        }
+        case FILTER_BLACKMAN_HARRIS: {
+            float angle = PI * (1.f + (x / radius));
+            return 0.35875f - (0.48829f * cos(angle)) + (0.14128f * cos(2.f * angle)) - (0.01168f * cos(3.f * angle));
+        }
+        case FILTER_MITCHELL_NETRAVALI: {
+            const float B = 1.f / 3.f;
+            const float C = 1.f / 3.f;
+            float t = 2.f * x / radius;
+            if (t < 1.f) {
+                return (((12.f - 9.f * B - 6.f * C) * t * t * t) + ((-18.f + 12.f * B + 6.f * C) * t * t) + (6.f - 2.f * B)) / 6.f;
+            }
+            return (((-B - 6.f * C) * t * t * t) + ((6.f * B + 30.f * C) * t * t) + ((-12.f * B - 48.f * C) * t) + (8.f * B + 24.f * C)) / 6.f;
+        }
+        default:
+            return 1.f;
+    }
+}
+
+#endif // FILTER
@@ -11,57 +11,214 @@ const uint MATERIAL_DIFFUSIVE = 0;
 const uint MATERIAL_REFLECTIVE = 1;
 const uint MATERIAL_REFRACTIVE = 2;
 const uint MATERIAL_EMITTING = 3;
+const uint MATERIAL_SHADOW_CATCHER = 4;
+const uint MATERIAL_HOLDOUT = 5;
 
 const uint BIH_BRANCH_X = 0;
 const uint BIH_BRANCH_Y = 1;
 const uint BIH_BRANCH_Z = 2;
 const uint BIH_LEAF = 3;
 
+const uint BVH_BRANCH = 0;
+const uint BVH_LEAF = 1;
+
+const uint LIGHT_POINT = 0;
+const uint LIGHT_SPOT = 1;
+const uint LIGHT_DIRECTIONAL = 2;
+const uint LIGHT_AREA = 3;
+
 const uint FLAG_USE_BIH = 1 << 0;
 const uint FLAG_RENDER_NORMALS = 1 << 1;
 const uint FLAG_RENDER_BIH = 1 << 2;
+const uint FLAG_USE_BVH = 1 << 3;
+const uint FLAG_INDEXED_TRIANGLES = 1 << 4;
+const uint FLAG_COMPACT_VERTICES = 1 << 5;
+const uint FLAG_RUSSIAN_ROULETTE = 1 << 6;
+const uint FLAG_MOTION_BLUR = 1 << 7;
+const uint FLAG_CROP = 1 << 8;
+const uint FLAG_ADAPTIVE_SAMPLING = 1 << 9;
+const uint FLAG_SAMPLE_HEATMAP = 1 << 10;
+const uint FLAG_AOVS = 1 << 11;
+const uint FLAG_TRANSPARENT_BACKGROUND = 1 << 12;
+
+const uint MAX_PATH_LENGTH = 256;
+
+// Material index and object ID of pixels where nothing has been hit, written out as -1.
+const uint NO_INDEX = 0xffffffff;
+
+// Features of the surface hit by the last camera ray traced and the light it has brought back, reset by the caller
+// before tracing it.
+SurfaceFeatures firstHit;
+LightPaths lightPaths;
+// Whether the last camera ray has hit a shadow catcher, after which only the light of the other objects is gathered.
+bool bShadowCatcherPath;
+// Makes the light sources reach every point, as if there were nothing in the way.
+bool bShadowsIgnored = false;
 
 // Ray tracing ---------------------------------------------------------------------------------------------------------
 
-vec4 trace(Ray ray);
+vec4 trace(Ray ray, vec4 throughput, float bsdfPdf, uint pathLength);
+vec4 shade(Hit hit);
 
+bool hitShape(in Ray ray, out Hit hit);
 bool hitShapeBih(in Ray ray, out Hit hit);
+bool hitShapeBvh(in Ray ray, out Hit hit);
 bool hitShapeBruteforce(in Ray ray, out Hit hit);
-bool hitTriangle(in Ray ray, in Triangle triangle, float distMin, float distMax, out Hit hit);
+bool hitTriangle(in Ray ray, uint triangleIndex, float distMin, float distMax, out Hit hit);
+void loadTrianglePositions(uint triangleIndex, float time, out vec3 positions[3]);
+void loadTriangleAttributes(uint triangleIndex, vec3 barycentric, out vec3 normal, out vec2 texCoords, out uint materialIndex);
+vec3 loadVertexNormal(uint vertexIndex);
+vec2 loadVertexTexcoord(uint vertexIndex);
+float hitBoundingBox(in Ray ray, vec3 boundsMin, vec3 boundsMax, float distMax);
+Ray spawnRay(in Hit hit, vec3 direction);
 
 bool scatter(Hit hit, out Scattering scattering);
+bool scatterFirstHit(in Hit hit, out Scattering scattering, out float alpha);
+float catchShadows(in Hit hit, in Material material, out Scattering scattering);
+float coverCaughtLight(vec3 caught, float alpha);
 bool scatterDiffusive(in Hit hit, in Material mat, out Scattering scattering);
 bool scatterReflective(in Hit hit, in Material mat, out Scattering scattering);
 bool scatterRefractive(in Hit hit, in Material mat, out Scattering scattering);
 bool scatterEmitting(in Hit hit, in Material mat, out Scattering scattering);
 
-vec4 trace(Ray ray) {
-    vec4 finalColor = vec4(1.f);
-    for (int iReflection = 0; iReflection < camera.maxReflections; ++iReflection) {
+vec3 sampleLights(in Hit hit, float seed);
+bool sampleLight(in Light light, vec3 position, vec2 pointOnLight, out vec3 direction, out float distance, out vec3 radiance);
+bool isOccluded(in Hit hit, vec3 direction, float distance);
+vec3 sampleEmitters(in Hit hit, float seed);
+float emitterPdf(in Hit hit, vec3 origin);
+float powerHeuristic(float pdf, float otherPdf);
+void recordPathLength(uint pathLength);
+void resetPathRecords();
+void recordFirstHit(in Hit hit, float depth, uint objectIndex);
+void recordLight(vec3 light, uint bounces);
+bool isCroppedOut(vec2 fragCoord);
+bool isBackgroundTransparent();
+PixelSums emptyPixelSums();
+void addSample(inout PixelSums sums, vec4 colour, float weight);
+void writeOutputs(in PixelSums sums);
+
+vec3 sampleEnvironment(vec3 direction);
+vec3 sampleSunDisc(vec3 direction);
+vec3 sampleEnvironmentLight(in Hit hit, float seed);
+vec3 pickEnvironmentDirection(vec2 random, out float pdf);
+float environmentPdf(vec3 direction);
+vec2 environmentCoords(vec3 direction);
+vec3 environmentDirection(vec2 coords);
+uint findCdfInterval(uint first, uint count, float value);
+
+// `throughput` is the fraction of the light arriving along the ray which reaches the camera, `bsdfPdf` the probability
+// density of the material at the ray's origin having picked its direction, and `pathLength` the number of rays traced
+// before this one, all needed when the ray starts at a hit found outside of this function.
+vec4 trace(Ray ray, vec4 throughput, float bsdfPdf, uint pathLength) {
+    vec4 radiance = vec4(0.f);
+    float alpha = 1.f;
+    bool bRussianRoulette = (flags & FLAG_RUSSIAN_ROULETTE) != 0;
+    // Russian roulette terminates paths on its own, so the limit only guards against rays stuck between mirrors.
+    uint maxPathLength = bRussianRoulette ? MAX_PATH_LENGTH : camera.maxReflections;
+    while (pathLength < maxPathLength) {
+        ++pathLength;
         Hit hit;
-        if ((flags & FLAG_USE_BIH) != 0 ? hitShapeBih(ray, hit) : hitShapeBruteforce(ray, hit)) {
+        if (hitShape(ray, hit)) {
             Scattering scattering;
-            bool bScattered = scatter(hit, scattering);
-            finalColor *= scattering.color;
+            bool bScattered;
+            if (pathLength == 1) {
+                recordFirstHit(hit, distance(hit.position, ray.origin), triangleObjects[hit.triangleIndex]);
+                bScattered = scatterFirstHit(hit, scattering, alpha);
+            } else if (bShadowCatcherPath && materials[hit.materialIndex].materialType == MATERIAL_SHADOW_CATCHER) {
+                // The catchers stand in for surfaces already in the image they are composited over.
+                break;
+            } else {
+                bScattered = scatter(hit, scattering);
+            }
+            vec4 direct = throughput * scattering.direct;
+            radiance += direct;
+            recordLight(direct.rgb, pathLength);
             if (bScattered) {
+                throughput *= scattering.color;
+                bsdfPdf = scattering.pdf;
                 ray = scattering.newRay;
+                if (bRussianRoulette && pathLength >= rrMinBounces) {
+                    // Paths carrying little light are likely to be terminated, and the surviving ones are boosted
+                    // to make up for the terminated ones.
+                    float survival = min(max(throughput.r, max(throughput.g, throughput.b)), 0.95f);
+                    if (rand(dot(hit.position, vec3(randomCoords, 1.f)) + 12.f) >= survival) {
+                        break;
+                    }
+                    throughput /= survival;
+                }
             } else {
-                // Emission
+                // Emission, which could also have been reached by sampling the emitters at the previous hit.
+                float weight = 1.f;
+                if (bsdfPdf > 0.f && materials[hit.materialIndex].materialType == MATERIAL_EMITTING) {
+                    weight = powerHeuristic(bsdfPdf, emitterPdf(hit, ray.origin));
+                }
+                vec4 emitted = throughput * weight * scattering.color;
+                radiance += emitted;
+                recordLight(emitted.rgb, pathLength - 1);
                 break;
             }
         } else {
-            // Sky
+            if (pathLength == 1 && isBackgroundTransparent()) {
+                // The background is left out of the image, but still lights the scene and shows in reflections.
+                alpha = 0.f;
+                break;
+            }
+            if (bShadowCatcherPath) {
+                // Already lighting the image the shadow catchers are composited over.
+                break;
+            }
+            // Environment, which could also have been reached by sampling it at the previous hit.
             vec3 rayDir = normalize(ray.direction);
-            finalColor *= sampleTexture(mappingOnUnitSphere(rayDir), 0);
+            float weight = bsdfPdf > 0.f ? powerHeuristic(bsdfPdf, environmentPdf(rayDir)) : 1.f;
+            vec3 environmentLight = weight * sampleEnvironment(rayDir);
+            if (bsdfPdf == 0.f) {
+                // Diffuse surfaces are lit by the sun as a directional light instead.
+                environmentLight += sampleSunDisc(rayDir);
+            }
+            vec4 emitted = throughput * vec4(environmentLight, 0.f);
+            radiance += emitted;
+            recordLight(emitted.rgb, pathLength - 1);
             break;
         }
     }
-    return finalColor;
+    recordPathLength(pathLength);
+    if (bShadowCatcherPath) {
+        alpha = coverCaughtLight(radiance.rgb, alpha);
+    }
+    return vec4(radiance.rgb, alpha);
+}
+
+// Radiance leaving a hit found outside of `trace`, e.g. read from the G-buffer, towards the ray that hit it.
+vec4 shade(Hit hit) {
+    Scattering scattering;
+    float alpha;
+    bool bScattered = scatterFirstHit(hit, scattering, alpha);
+    recordLight(scattering.direct.rgb, 1);
+    if (bScattered) {
+        vec3 indirect = trace(scattering.newRay, scattering.color, scattering.pdf, 1).rgb;
+        if (bShadowCatcherPath) {
+            alpha = coverCaughtLight(indirect, alpha);
+        }
+        return vec4(scattering.direct.rgb + indirect, alpha);
+    }
+    recordPathLength(1);
+    recordLight(scattering.color.rgb, 0);
+    return vec4(scattering.direct.rgb + scattering.color.rgb, alpha);
+}
+
+bool hitShape(in Ray ray, out Hit hit) {
+    if ((flags & FLAG_USE_BIH) != 0) {
+        return hitShapeBih(ray, hit);
+    } else if ((flags & FLAG_USE_BVH) != 0) {
+        return hitShapeBvh(ray, hit);
+    } else {
+        return hitShapeBruteforce(ray, hit);
+    }
 }
 
 bool hitShapeBih(in Ray ray, out Hit hit) {
     bool hitOccured = false;
-    float minDistance = EPSILON;
+    float minDistance = 0.f;
     float maxDistance = FLOAT_MAX;
 
     struct StackEntry {
@@ -102,7 +259,7 @@ bool hitShapeBih(in Ray ray, out Hit hit) {
                 bihNodes[currEntry.nodeIndex].childRight
             );
 
-            if ((flags & FLAG_RENDER_BIH) != 0 && currEntry.nodeIndex == drawBihLevel) {
+            if ((flags & FLAG_RENDER_BIH) != 0 && currEntry.nodeIndex == drawBihNode) {
                 if (hit1Occurred) {
                     if (hit2Occurred) {
                         hit.materialIndex = 3;
@@ -145,7 +302,7 @@ bool hitShapeBih(in Ray ray, out Hit hit) {
             uint triangleCount = bihNodes[currEntry.nodeIndex].childRight;
             for (uint i = triangleIndex; i < triangleIndex + triangleCount; ++i) {
                 Hit tempHit;
-                if (hitTriangle(ray, triangles[i], minDistance, maxDistance, tempHit)) {
+                if (hitTriangle(ray, i, minDistance, maxDistance, tempHit)) {
                     hitOccured = true;
                     maxDistance = tempHit.distance;
                     hit = tempHit;
@@ -160,14 +317,72 @@ bool hitShapeBih(in Ray ray, out Hit hit) {
     return hitOccured;
 }
 
+bool hitShapeBvh(in Ray ray, out Hit hit) {
+    bool hitOccured = false;
+    const float minDistance = 0.f;
+    float maxDistance = FLOAT_MAX;
+
+    struct StackEntry {
+        uint nodeIndex;
+        float distance;
+    } stack[64];
+    stack[0].nodeIndex = 0;
+    stack[0].distance = hitBoundingBox(ray, bvhNodes[0].boundsMin, bvhNodes[0].boundsMax, maxDistance);
+
+    int entryIndex = 0;
+    while (entryIndex >= 0) {
+        StackEntry currEntry = stack[entryIndex--];
+        if (currEntry.distance >= maxDistance) {
+            // Either the ray misses the node or a closer triangle has been found since it was pushed.
+            continue;
+        }
+
+        if (bvhNodes[currEntry.nodeIndex].nodeType == BVH_LEAF) {
+            uint firstIndex = bvhNodes[currEntry.nodeIndex].childLeft;
+            uint triangleCount = bvhNodes[currEntry.nodeIndex].childRight;
+            for (uint i = firstIndex; i < firstIndex + triangleCount; ++i) {
+                Hit tempHit;
+                if (hitTriangle(ray, bvhTriangleIndices[i], minDistance, maxDistance, tempHit)) {
+                    hitOccured = true;
+                    maxDistance = tempHit.distance;
+                    hit = tempHit;
+                }
+            }
+        } else {
+            uint children[2] = uint[](
+                bvhNodes[currEntry.nodeIndex].childLeft,
+                bvhNodes[currEntry.nodeIndex].childRight
+            );
+            float distances[2] = float[](
+                hitBoundingBox(ray, bvhNodes[children[0]].boundsMin, bvhNodes[children[0]].boundsMax, maxDistance),
+                hitBoundingBox(ray, bvhNodes[children[1]].boundsMin, bvhNodes[children[1]].boundsMax, maxDistance)
+            );
+
+            // Push the farther child first so that the nearer one gets traversed first.
+            uint near = uint(distances[1] < distances[0]);
+            uint far = 1 - near;
+            if (distances[far] < maxDistance) {
+                stack[++entryIndex].nodeIndex = children[far];
+                stack[entryIndex].distance = distances[far];
+            }
+            if (distances[near] < maxDistance) {
+                stack[++entryIndex].nodeIndex = children[near];
+                stack[entryIndex].distance = distances[near];
+            }
+        }
+    }
+
+    return hitOccured;
+}
+
 bool hitShapeBruteforce(in Ray ray, out Hit hit) {
     bool hitOccured = false;
-    const float minDistance = EPSILON;
+    const float minDistance = 0.f;
     float maxDistance = FLOAT_MAX;
 
     for (int i = 0; i < nTriangles; ++i) {
         Hit tempHit;
-        if (hitTriangle(ray, triangles[i], minDistance, maxDistance, tempHit)) {
+        if (hitTriangle(ray, i, minDistance, maxDistance, tempHit)) {
             hitOccured = true;
             maxDistance = tempHit.distance;
             hit = tempHit;
@@ -177,47 +392,149 @@ bool hitShapeBruteforce(in Ray ray, out Hit hit) {
     return hitOccured;
 }
 
-// Möller-Trumbore algorithm
-bool hitTriangle(in Ray ray, in Triangle triangle, float distMin, float distMax, out Hit hit) {
-    vec3 edge1 = triangle.positions[1] - triangle.positions[0];
-    vec3 edge2 = triangle.positions[2] - triangle.positions[0];
-    vec3 p = cross(ray.direction, edge2);
-    float determinant = dot(edge1, p);
+// Watertight ray-triangle intersection, see: Woop, Benthin, Wald, "Watertight Ray/Triangle Intersection",
+// Journal of Computer Graphics Techniques (2013). Mirrored by `Triangle::hit` in eruptrace_scene.
+bool hitTriangle(in Ray ray, uint triangleIndex, float distMin, float distMax, out Hit hit) {
+    vec3 positions[3];
+    loadTrianglePositions(triangleIndex, ray.time, positions);
 
-    if (abs(determinant) < EPSILON) {
-        return false;
+    // Permute the axes so that the largest component of the ray direction is along z.
+    vec3 absDirection = abs(ray.direction);
+    int kz = absDirection.x > absDirection.y ? (absDirection.x > absDirection.z ? 0 : 2) : (absDirection.y > absDirection.z ? 1 : 2);
+    int kx = (kz + 1) % 3;
+    int ky = (kz + 2) % 3;
+    if (ray.direction[kz] < 0.f) {
+        int swap = kx;
+        kx = ky;
+        ky = swap;
     }
 
-    float determinantInv = 1.f / determinant;
-    vec3 t = ray.origin - triangle.positions[0];
-    vec3 q = cross(t, edge1);
-    float u = dot(t, p) * determinantInv;
-    float v = dot(ray.direction, q) * determinantInv;
+    // Shear the vertices into the space where the ray starts at the origin and goes along +z.
+    vec3 shear = vec3(ray.direction[kx], ray.direction[ky], 1.f) / ray.direction[kz];
+    vec3 a = positions[0] - ray.origin;
+    vec3 b = positions[1] - ray.origin;
+    vec3 c = positions[2] - ray.origin;
+    vec3 xs = vec3(a[kx], b[kx], c[kx]) - (shear.x * vec3(a[kz], b[kz], c[kz]));
+    vec3 ys = vec3(a[ky], b[ky], c[ky]) - (shear.y * vec3(a[kz], b[kz], c[kz]));
+
+    vec3 edges = vec3(
+        (xs.z * ys.y) - (ys.z * xs.y),
+        (xs.x * ys.z) - (ys.x * xs.z),
+        (xs.y * ys.x) - (ys.y * xs.x));
 
-    if (u < 0.f || u > 1.f || v < 0.f || u + v > 1.f) {
+    // Unlike the CPU version, this doesn't fall back to double precision when an edge function is exactly zero, as
+    // not every device supports 64-bit floats. A zero still counts as a hit for both triangles sharing the edge.
+    if (any(lessThan(edges, vec3(0.f))) && any(greaterThan(edges, vec3(0.f)))) {
         return false;
     }
 
-    float distance = dot(q, edge2) * determinantInv;
+    float determinant = edges.x + edges.y + edges.z;
+    if (determinant == 0.f) {
+        return false;
+    }
 
-    if (distance < distMin || distance > distMax) {
+    // Compare the distance before dividing it by the determinant.
+    float scaledDistance = dot(edges, shear.z * vec3(a[kz], b[kz], c[kz]));
+    float determinantSign = sign(determinant);
+    if (determinantSign * scaledDistance <= distMin * abs(determinant)
+        || determinantSign * scaledDistance > distMax * abs(determinant)) {
         return false;
     }
 
-    vec3 normal = ((1.f - u - v) * triangle.normals[0]) + (u * triangle.normals[1]) + (v * triangle.normals[2]);
+    float determinantInv = 1.f / determinant;
+    vec3 barycentric = edges * determinantInv;
+
+    vec3 normal;
+    loadTriangleAttributes(triangleIndex, barycentric, normal, hit.texCoords, hit.materialIndex);
+    vec3 geometricNormal = normalize(cross(positions[1] - positions[0], positions[2] - positions[0]));
     float dotRayNorm = dot(ray.direction, normal);
 
-    hit.distance = distance;
-    hit.position = pointOnRay(ray, distance);
+    hit.distance = scaledDistance * determinantInv;
+    // Interpolating the vertices is more precise than moving along the ray, which keeps the offset origins of
+    // secondary rays on the correct side of the surface.
+    hit.position = (barycentric.x * positions[0]) + (barycentric.y * positions[1]) + (barycentric.z * positions[2]);
     hit.incidental = ray.direction;
     hit.normal = normal * -sign(dotRayNorm);
-    hit.texCoords = ((1.f - u - v) * triangle.texcoords[0]) + (u * triangle.texcoords[1]) + (v * triangle.texcoords[2]);
+    hit.geometricNormal = dot(ray.direction, geometricNormal) < 0.f ? geometricNormal : -geometricNormal;
+    hit.triangleIndex = triangleIndex;
     hit.bFrontFace = dotRayNorm < 0.f;
-    hit.materialIndex = triangle.materialIndex;
+    hit.time = ray.time;
 
     return true;
 }
 
+// Positions of the triangle's vertices at the given time, moving in straight lines over the shutter interval.
+void loadTrianglePositions(uint triangleIndex, float time, out vec3 positions[3]) {
+    if ((flags & FLAG_INDEXED_TRIANGLES) != 0) {
+        uvec4 indices = triangleIndices[triangleIndex];
+        for (int i = 0; i < 3; ++i) {
+            uint first = 3 * indices[i];
+            positions[i] = vec3(vertexPositions[first], vertexPositions[first + 1], vertexPositions[first + 2]);
+        }
+    } else {
+        positions = triangles[triangleIndex].positions;
+    }
+    if ((flags & FLAG_MOTION_BLUR) != 0) {
+        for (int i = 0; i < 3; ++i) {
+            positions[i] += time * triangleMotion[(3 * triangleIndex) + i].xyz;
+        }
+    }
+}
+
+void loadTriangleAttributes(uint triangleIndex, vec3 barycentric, out vec3 normal, out vec2 texCoords, out uint materialIndex) {
+    if ((flags & FLAG_INDEXED_TRIANGLES) != 0) {
+        uvec4 indices = triangleIndices[triangleIndex];
+        normal = (barycentric.x * loadVertexNormal(indices.x))
+            + (barycentric.y * loadVertexNormal(indices.y))
+            + (barycentric.z * loadVertexNormal(indices.z));
+        texCoords = (barycentric.x * loadVertexTexcoord(indices.x))
+            + (barycentric.y * loadVertexTexcoord(indices.y))
+            + (barycentric.z * loadVertexTexcoord(indices.z));
+        materialIndex = indices.w;
+    } else {
+        normal = (barycentric.x * triangles[triangleIndex].normals[0])
+            + (barycentric.y * triangles[triangleIndex].normals[1])
+            + (barycentric.z * triangles[triangleIndex].normals[2]);
+        texCoords = (barycentric.x * triangles[triangleIndex].texcoords[0])
+            + (barycentric.y * triangles[triangleIndex].texcoords[1])
+            + (barycentric.z * triangles[triangleIndex].texcoords[2]);
+        materialIndex = triangles[triangleIndex].materialIndex;
+    }
+}
+
+vec3 loadVertexNormal(uint vertexIndex) {
+    if ((flags & FLAG_COMPACT_VERTICES) != 0) {
+        return decodeOctahedral(vertexNormals[vertexIndex]);
+    }
+    uint first = 3 * vertexIndex;
+    return uintBitsToFloat(uvec3(vertexNormals[first], vertexNormals[first + 1], vertexNormals[first + 2]));
+}
+
+vec2 loadVertexTexcoord(uint vertexIndex) {
+    if ((flags & FLAG_COMPACT_VERTICES) != 0) {
+        return unpackHalf2x16(vertexTexcoords[vertexIndex]);
+    }
+    uint first = 2 * vertexIndex;
+    return uintBitsToFloat(uvec2(vertexTexcoords[first], vertexTexcoords[first + 1]));
+}
+
+// Slab test, returns the distance at which the ray enters the box or FLOAT_MAX if it misses it.
+float hitBoundingBox(in Ray ray, vec3 boundsMin, vec3 boundsMax, float distMax) {
+    vec3 distancesToMin = (boundsMin - ray.origin) * ray.invDirection;
+    vec3 distancesToMax = (boundsMax - ray.origin) * ray.invDirection;
+    vec3 distancesNear = min(distancesToMin, distancesToMax);
+    vec3 distancesFar = max(distancesToMin, distancesToMax);
+    float entry = max(max(distancesNear.x, distancesNear.y), max(distancesNear.z, 0.f));
+    float exit = min(min(distancesFar.x, distancesFar.y), min(distancesFar.z, distMax));
+    return entry <= exit ? entry : FLOAT_MAX;
+}
+
+// Starts a ray at the hit point, moved off of the surface to the side the ray is going to.
+Ray spawnRay(in Hit hit, vec3 direction) {
+    vec3 offsetNormal = dot(direction, hit.geometricNormal) < 0.f ? -hit.geometricNormal : hit.geometricNormal;
+    return Ray(offsetRayOrigin(hit.position, offsetNormal), direction, 1.f / direction, hit.time);
+}
+
 bool scatter(Hit hit, out Scattering scattering) {
     Material material = materials[hit.materialIndex];
 
@@ -226,6 +543,8 @@ bool scatter(Hit hit, out Scattering scattering) {
 
     if ((flags & FLAG_RENDER_NORMALS) != 0) {
         scattering.color = vec4(0.5f + (0.5f * hit.normal), 1.f);
+        scattering.direct = vec4(0.f);
+        scattering.pdf = 0.f;
         return false;
     } else if ((flags & FLAG_RENDER_BIH) != 0) {
         scattering.color = vec4(
@@ -233,6 +552,8 @@ bool scatter(Hit hit, out Scattering scattering) {
             float(hit.materialIndex == 2 || hit.materialIndex == 3),
             float(hit.materialIndex == 1),
             1.f);
+        scattering.direct = vec4(0.f);
+        scattering.pdf = 0.f;
         return false;
     } else {
         switch (material.materialType) {
@@ -248,30 +569,63 @@ bool scatter(Hit hit, out Scattering scattering) {
             case MATERIAL_EMITTING: {
                 return scatterEmitting(hit, material, scattering);
             }
+            case MATERIAL_SHADOW_CATCHER: {
+                // Seen by anything but the camera, the catchers are the surfaces they stand in for.
+                return scatterDiffusive(hit, material, scattering);
+            }
             default: {
+                // Holdouts are black.
+                scattering.color = vec4(0.f);
+                scattering.direct = vec4(0.f);
+                scattering.pdf = 0.f;
                 return false;
             }
         }
     }
 }
 
+// Scatters a camera ray off the first surface it has hit like `scatter`, with the sample's alpha being how much of the
+// image to be composited under it is hidden. Holdouts hide all of it, and shadow catchers as much as they are shadowed.
+bool scatterFirstHit(in Hit hit, out Scattering scattering, out float alpha) {
+    alpha = 1.f;
+    Material material = materials[hit.materialIndex];
+    if ((flags & (FLAG_RENDER_NORMALS | FLAG_RENDER_BIH)) == 0) {
+        if (material.materialType == MATERIAL_SHADOW_CATCHER) {
+            alpha = catchShadows(hit, material, scattering);
+            return true;
+        }
+        if (material.materialType == MATERIAL_HOLDOUT) {
+            alpha = 0.f;
+        }
+    }
+    return scatter(hit, scattering);
+}
+
 bool scatterDiffusive(in Hit hit, in Material material, out Scattering scattering) {
-    vec3 scatterDirection = randDirection(dot(hit.position, gl_FragCoord.xyz));
-    scatterDirection *= sign(dot(scatterDirection, hit.normal));
-    scatterDirection = normalize(scatterDirection);
-    scattering.newRay = Ray(hit.position, scatterDirection, 1.f / scatterDirection);
-    scattering.color = sampleTexture(hit.texCoords, material.textureIndex);
+    // Offsetting a random unit vector by the normal gives a cosine-weighted direction, whose pdf cancels out with
+    // the cosine and the 1/π of the Lambertian BRDF, leaving just the albedo as the weight.
+    float seed = dot(hit.position, vec3(randomCoords, 1.f));
+    vec3 scatterDirection = hit.normal + randDirection(seed);
+    scatterDirection = dot(scatterDirection, scatterDirection) > 1e-8f ? normalize(scatterDirection) : hit.normal;
+    vec4 albedo = sampleTexture(hit.texCoords, material.textureIndex);
+    scattering.newRay = spawnRay(hit, scatterDirection);
+    scattering.color = albedo;
+    vec3 directLight = sampleLights(hit, seed) + sampleEmitters(hit, seed) + sampleEnvironmentLight(hit, seed);
+    scattering.direct = albedo * vec4(directLight * ONE_OVER_PI, 0.f);
+    scattering.pdf = dot(scatterDirection, hit.normal) * ONE_OVER_PI;
     return true;
 }
 
 bool scatterReflective(in Hit hit, in Material material, out Scattering scattering) {
     float fuzz = material.parameter;
     vec3 reflected = reflect(hit.incidental, hit.normal);
-    vec3 randDir = randDirection(dot(hit.position, gl_FragCoord.xyz));
+    vec3 randDir = randDirection(dot(hit.position, vec3(randomCoords, 1.f)));
     vec3 scatterDirection = reflected + (fuzz * randDir);
     scatterDirection *= sign(dot(scatterDirection, hit.normal));
     scattering.color = sampleTexture(hit.texCoords, material.textureIndex);
-    scattering.newRay = Ray(hit.position, scatterDirection, 1.f / scatterDirection);
+    scattering.newRay = spawnRay(hit, scatterDirection);
+    scattering.direct = vec4(0.f);
+    scattering.pdf = 0.f;
     return true;
 }
 
@@ -285,7 +639,7 @@ bool scatterRefractive(in Hit hit, in Material material, out Scattering scatteri
     float reflectance = (1.f - refractiveIndex) / (1.f + refractiveIndex);
     reflectance *= reflectance;
     reflectance += (1.f - reflectance) * pow((1.f - cosTheta), 5.f);
-    bool shouldReflect = reflectance > rand(dot(hit.position, gl_FragCoord.xyz));
+    bool shouldReflect = reflectance > rand(dot(hit.position, vec3(randomCoords, 1.f)));
 
     vec3 scatterDirection;
     if (cannotRefract || shouldReflect) {
@@ -295,14 +649,402 @@ bool scatterRefractive(in Hit hit, in Material material, out Scattering scatteri
     }
 
     scattering.color = sampleTexture(hit.texCoords, material.textureIndex);
-    scattering.newRay = Ray(hit.position, scatterDirection, 1.f / scatterDirection);
+    scattering.newRay = spawnRay(hit, scatterDirection);
+    scattering.direct = vec4(0.f);
+    scattering.pdf = 0.f;
     return true;
 }
 
+// Returns the shadow catcher's alpha, which is one minus the ratio of the light reaching the hit point to the light
+// which would reach it with nothing in the way. Its own lighting is already in the image it is composited over, so
+// only the light bouncing off the other objects is gathered by the scattered ray, and `coverCaughtLight` then raises
+// the alpha to make room for it.
+float catchShadows(in Hit hit, in Material material, out Scattering scattering) {
+    const vec3 luminanceWeights = vec3(0.2126f, 0.7152f, 0.0722f);
+    float seed = dot(hit.position, vec3(randomCoords, 1.f));
+    vec3 shadowed = sampleLights(hit, seed) + sampleEmitters(hit, seed) + sampleEnvironmentLight(hit, seed);
+    // The same seed picks the same points on the lights again.
+    bShadowsIgnored = true;
+    vec3 unshadowed = sampleLights(hit, seed) + sampleEmitters(hit, seed) + sampleEnvironmentLight(hit, seed);
+    bShadowsIgnored = false;
+    float unshadowedLuminance = dot(unshadowed, luminanceWeights);
+    float shadowRatio = unshadowedLuminance > 0.f ? dot(shadowed, luminanceWeights) / unshadowedLuminance : 1.f;
+
+    vec3 scatterDirection = hit.normal + randDirection(seed);
+    scatterDirection = dot(scatterDirection, scatterDirection) > 1e-8f ? normalize(scatterDirection) : hit.normal;
+    scattering.newRay = spawnRay(hit, scatterDirection);
+    scattering.color = sampleTexture(hit.texCoords, material.textureIndex);
+    scattering.direct = vec4(0.f);
+    scattering.pdf = 0.f;
+    bShadowCatcherPath = true;
+    return 1.f - clamp(shadowRatio, 0.f, 1.f);
+}
+
+// The image is composited as `colour + (1 - alpha) * background`, with the colours premultiplied by the alpha. A shadow
+// catcher's colour is the light falling onto it from the other objects, which replaces as much of the background as its
+// brightest channel after exposure, so that the colour never exceeds the alpha and survives being divided by it.
+float coverCaughtLight(vec3 caught, float alpha) {
+    vec3 exposed = camera.exposure * caught;
+    return max(alpha, clamp(max(exposed.r, max(exposed.g, exposed.b)), 0.f, 1.f));
+}
+
 bool scatterEmitting(in Hit hit, in Material material, out Scattering scattering) {
     float intensity = material.parameter;
     scattering.color = intensity * sampleTexture(hit.texCoords, material.textureIndex);
+    scattering.direct = vec4(0.f);
+    scattering.pdf = 0.f;
     return false;
 }
 
+// Light sources -------------------------------------------------------------------------------------------------------
+
+// Returns the light reaching the hit point from one of the light sources, picked at random to keep the cost of a
+// bounce independent of the number of lights, multiplied by the cosine of its angle with the normal.
+vec3 sampleLights(in Hit hit, float seed) {
+    if (nLights == 0) {
+        return vec3(0.f);
+    }
+
+    uint lightIndex = min(uint(rand(seed + 3.f) * nLights), nLights - 1);
+    vec2 pointOnLight = vec2(rand(seed + 4.f), rand(seed + 5.f));
+    vec3 direction;
+    float distance;
+    vec3 radiance;
+    if (!sampleLight(lights[lightIndex], hit.position, pointOnLight, direction, distance, radiance)) {
+        return vec3(0.f);
+    }
+
+    float cosSurface = dot(direction, hit.normal);
+    if (cosSurface <= 0.f || dot(direction, hit.geometricNormal) <= 0.f || isOccluded(hit, direction, distance)) {
+        return vec3(0.f);
+    }
+    return float(nLights) * radiance * cosSurface;
+}
+
+// Picks a point on a light and returns the direction to it along with the light arriving from there, divided by the
+// probability of picking that point. Returns false if the light doesn't reach the given position.
+bool sampleLight(in Light light, vec3 position, vec2 pointOnLight, out vec3 direction, out float distance, out vec3 radiance) {
+    switch (light.lightType) {
+        case LIGHT_POINT:
+        case LIGHT_SPOT: {
+            vec3 toLight = light.position.xyz - position;
+            distance = length(toLight);
+            direction = toLight / distance;
+            radiance = light.color.rgb * light.intensity / (distance * distance);
+            if (light.lightType == LIGHT_SPOT) {
+                float cosAngle = dot(-direction, light.direction.xyz);
+                radiance *= light.cosInner > light.cosOuter
+                    ? smoothstep(light.cosOuter, light.cosInner, cosAngle)
+                    : float(cosAngle >= light.cosOuter);
+            }
+            return true;
+        }
+        case LIGHT_DIRECTIONAL: {
+            direction = -light.direction.xyz;
+            distance = FLOAT_MAX;
+            radiance = light.color.rgb * light.intensity;
+            return true;
+        }
+        case LIGHT_AREA: {
+            vec3 lightPosition = light.position.xyz + (pointOnLight.x * light.edgeU.xyz) + (pointOnLight.y * light.edgeV.xyz);
+            vec3 toLight = lightPosition - position;
+            distance = length(toLight);
+            direction = toLight / distance;
+            vec3 lightNormal = cross(light.edgeU.xyz, light.edgeV.xyz);
+            float area = length(lightNormal);
+            float cosLight = dot(-direction, lightNormal / area);
+            if (cosLight <= 0.f) {
+                return false;
+            }
+            // The point is picked with a probability of 1/area, which has to be converted to solid angle.
+            radiance = light.color.rgb * light.intensity * cosLight * area / (distance * distance);
+            return true;
+        }
+        default: {
+            return false;
+        }
+    }
+}
+
+bool isOccluded(in Hit hit, vec3 direction, float distance) {
+    if (bShadowsIgnored) {
+        return false;
+    }
+    Ray shadowRay = spawnRay(hit, direction);
+    Hit occluderHit;
+    // Area lights are often placed right on top of geometry, which shouldn't cast shadows on its own light.
+    return hitShape(shadowRay, occluderHit) && occluderHit.distance < 0.999f * distance;
+}
+
+// Picks an emissive triangle with a probability proportional to its power, then a point on it, and returns the light
+// arriving from there like `sampleLights`, weighted against the diffuse material having picked the same direction.
+vec3 sampleEmitters(in Hit hit, float seed) {
+    if (nEmitters == 0 || emitterPower <= 0.f) {
+        return vec3(0.f);
+    }
+
+    uint entry = min(uint(rand(seed + 6.f) * nEmitters), nEmitters - 1);
+    if (rand(seed + 7.f) >= emitters[entry].probability) {
+        entry = emitters[entry].alias;
+    }
+    uint triangleIndex = emitters[entry].triangleIndex;
+
+    float sqrtU = sqrt(rand(seed + 8.f));
+    float v = rand(seed + 9.f);
+    vec3 barycentric = vec3(1.f - sqrtU, sqrtU * (1.f - v), sqrtU * v);
+    vec3 positions[3];
+    loadTrianglePositions(triangleIndex, hit.time, positions);
+    vec3 normal;
+    vec2 texCoords;
+    uint materialIndex;
+    loadTriangleAttributes(triangleIndex, barycentric, normal, texCoords, materialIndex);
+
+    vec3 lightPosition = (barycentric.x * positions[0]) + (barycentric.y * positions[1]) + (barycentric.z * positions[2]);
+    vec3 toLight = lightPosition - hit.position;
+    float distance = length(toLight);
+    vec3 direction = toLight / distance;
+    vec3 lightNormal = cross(positions[1] - positions[0], positions[2] - positions[0]);
+    float area = 0.5f * length(lightNormal);
+    // Emitting materials shine from both sides.
+    float cosLight = abs(dot(direction, lightNormal)) / (2.f * area);
+    float cosSurface = dot(direction, hit.normal);
+    if (cosLight <= 0.f || cosSurface <= 0.f || dot(direction, hit.geometricNormal) <= 0.f
+        || isOccluded(hit, direction, distance)) {
+        return vec3(0.f);
+    }
+
+    float lightPdf = (emitters[entry].power / emitterPower) * distance * distance / (area * cosLight);
+    float bsdfPdf = cosSurface * ONE_OVER_PI;
+    Material material = materials[materialIndex];
+    vec3 emitted = material.parameter * sampleTexture(texCoords, material.textureIndex).rgb;
+    return emitted * cosSurface * powerHeuristic(lightPdf, bsdfPdf) / lightPdf;
+}
+
+// Probability density of `sampleEmitters` picking the direction from `origin` to a point on an emitting triangle.
+float emitterPdf(in Hit hit, vec3 origin) {
+    if (nEmitters == 0 || emitterPower <= 0.f) {
+        return 0.f;
+    }
+
+    vec3 positions[3];
+    loadTrianglePositions(hit.triangleIndex, hit.time, positions);
+    vec3 toLight = hit.position - origin;
+    float cosLight = abs(dot(normalize(toLight), normalize(cross(positions[1] - positions[0], positions[2] - positions[0]))));
+    if (cosLight <= 0.f) {
+        return 0.f;
+    }
+    // The probability of picking the triangle is its area times the intensity over the total power, and the area
+    // cancels out when converting the probability of picking the point to solid angle.
+    return materials[hit.materialIndex].parameter * dot(toLight, toLight) / (emitterPower * cosLight);
+}
+
+float powerHeuristic(float pdf, float otherPdf) {
+    float pdfSquared = pdf * pdf;
+    return pdfSquared / max(pdfSquared + (otherPdf * otherPdf), FLOAT_MIN);
+}
+
+// Counts the path in the histogram of path lengths, the last bucket of which also counts all longer paths.
+void recordPathLength(uint pathLength) {
+    atomicAdd(pathLengthCounts[min(pathLength, uint(pathLengthCounts.length()) - 1)], 1);
+}
+
+void resetPathRecords() {
+    firstHit = SurfaceFeatures(vec3(0.f), vec3(0.f), vec3(0.f), 0.f, NO_INDEX, NO_INDEX);
+    lightPaths = LightPaths(vec3(0.f), vec3(0.f));
+    bShadowCatcherPath = false;
+}
+
+// Keeps the albedo and the normal-mapped normal of the hit, as seen by the material, along with where it is and what
+// it belongs to.
+void recordFirstHit(in Hit hit, float depth, uint objectIndex) {
+    Material material = materials[hit.materialIndex];
+    firstHit.albedo = sampleTexture(hit.texCoords, material.textureIndex).rgb;
+    firstHit.normal = mapNormal(hit.normal, sampleNormalMap(hit.texCoords, material.normalMapIndex));
+    firstHit.position = hit.position;
+    firstHit.depth = depth;
+    firstHit.materialIndex = hit.materialIndex;
+    firstHit.objectIndex = objectIndex;
+}
+
+// Sorts light reaching the camera into the light paths by the number of surfaces it has bounced off on the way.
+void recordLight(vec3 light, uint bounces) {
+    if (bounces == 0) {
+        lightPaths.emission += light;
+    } else if (bounces == 1) {
+        lightPaths.direct += light;
+    }
+}
+
+// Whether the pixel lies outside of the crop region, which leaves it untraced.
+bool isCroppedOut(vec2 fragCoord) {
+    uvec2 pixel = uvec2(fragCoord);
+    uvec2 cropMin = uvec2(cropRegion[0], cropRegion[1]);
+    uvec2 cropMax = cropMin + uvec2(cropRegion[2], cropRegion[3]);
+    return (flags & FLAG_CROP) != 0 && (any(lessThan(pixel, cropMin)) || any(greaterThanEqual(pixel, cropMax)));
+}
+
+// Whether camera rays which don't hit anything are left out of the image, rather than showing the environment.
+bool isBackgroundTransparent() {
+    return (flags & FLAG_TRANSPARENT_BACKGROUND) != 0;
+}
+
+PixelSums emptyPixelSums() {
+    vec3 none = vec3(0.f);
+    return PixelSums(vec4(0.f), none, vec4(0.f), none, none, none, 0.f, -FLOAT_MAX, NO_INDEX, NO_INDEX);
+}
+
+// Adds a sample of the pixel along with what has been recorded while tracing it.
+void addSample(inout PixelSums sums, vec4 colour, float weight) {
+    sums.colour += weight * colour;
+    sums.albedo += weight * firstHit.albedo;
+    sums.normalDepth += weight * vec4(firstHit.normal, firstHit.depth);
+    sums.weight += weight;
+    if ((flags & FLAG_AOVS) != 0) {
+        sums.position += weight * firstHit.position;
+        sums.emission += weight * lightPaths.emission;
+        sums.direct += weight * lightPaths.direct;
+        if (weight > sums.maxWeight) {
+            sums.maxWeight = weight;
+            sums.materialIndex = firstHit.materialIndex;
+            sums.objectIndex = firstHit.objectIndex;
+        }
+    }
+}
+
+float indexOutput(uint index) {
+    return index == NO_INDEX ? -1.f : float(index);
+}
+
+// Writes the weighted averages of a pixel's samples and of what has been recorded along them to the render targets,
+// where filters with negative lobes can push them below zero.
+void writeOutputs(in PixelSums sums) {
+    if (sums.weight == 0.f) {
+        fragColour = vec4(0.f);
+        fragAlbedo = vec4(0.f);
+        fragNormalDepth = vec4(0.f);
+        fragPosition = vec4(0.f);
+        fragIds = vec4(-1.f, -1.f, 0.f, 0.f);
+        fragDirect = vec4(0.f);
+        fragIndirect = vec4(0.f);
+        fragEmission = vec4(0.f);
+        return;
+    }
+    // The alpha is the fraction of the samples which have hit something, unless the background is opaque.
+    vec4 colour = sums.colour / sums.weight;
+    colour.rgb *= camera.exposure;
+    fragColour = vec4(max(colour.rgb, 0.f), clamp(colour.a, 0.f, 1.f));
+    fragAlbedo = vec4(max(sums.albedo / sums.weight, 0.f), 1.f);
+    // Normals of differently facing surfaces get shorter when averaged.
+    vec4 normalDepth = sums.normalDepth / sums.weight;
+    float normalLength = length(normalDepth.xyz);
+    fragNormalDepth = vec4(normalLength > 0.f ? normalDepth.xyz / normalLength : vec3(0.f), max(normalDepth.w, 0.f));
+    if ((flags & FLAG_AOVS) != 0) {
+        vec3 emission = camera.exposure * sums.emission / sums.weight;
+        vec3 direct = camera.exposure * sums.direct / sums.weight;
+        fragPosition = vec4(sums.position / sums.weight, 1.f);
+        fragIds = vec4(indexOutput(sums.materialIndex), indexOutput(sums.objectIndex), 0.f, 0.f);
+        fragDirect = vec4(max(direct, 0.f), 1.f);
+        // Whatever isn't emission or direct light, so that the three passes add up to the colours.
+        fragIndirect = vec4(max(colour.rgb - emission - direct, 0.f), 1.f);
+        fragEmission = vec4(max(emission, 0.f), 1.f);
+    }
+}
+
+// Environment ---------------------------------------------------------------------------------------------------------
+
+vec3 sampleEnvironment(vec3 direction) {
+    return environment.intensity * texture(environmentMap, environmentCoords(direction)).rgb;
+}
+
+// The sky's sun, which is too small and bright to be a part of the environment map.
+vec3 sampleSunDisc(vec3 direction) {
+    bool bInDisc = dot(direction, environment.sunDirection.xyz) >= environment.sunCosRadius;
+    return bInDisc ? environment.sunRadiance.rgb : vec3(0.f);
+}
+
+// Picks a direction towards the environment with a probability proportional to its luminance, and returns the light
+// arriving from there like `sampleLights`, weighted against the diffuse material having picked the same direction.
+vec3 sampleEnvironmentLight(in Hit hit, float seed) {
+    float lightPdf;
+    vec3 direction = pickEnvironmentDirection(vec2(rand(seed + 10.f), rand(seed + 11.f)), lightPdf);
+    float cosSurface = dot(direction, hit.normal);
+    if (lightPdf <= 0.f || cosSurface <= 0.f || dot(direction, hit.geometricNormal) <= 0.f
+        || isOccluded(hit, direction, FLOAT_MAX)) {
+        return vec3(0.f);
+    }
+
+    float bsdfPdf = cosSurface * ONE_OVER_PI;
+    return sampleEnvironment(direction) * cosSurface * powerHeuristic(lightPdf, bsdfPdf) / lightPdf;
+}
+
+// Samples the marginal distribution of the rows and then the conditional distribution of the picked row, see
+// `environment_distribution` in the scene crate for the layout.
+vec3 pickEnvironmentDirection(vec2 random, out float pdf) {
+    uint width = environment.width;
+    uint height = environment.height;
+
+    uint row = findCdfInterval(0, height, random.y);
+    float rowStart = environmentCdf[row];
+    float rowProbability = environmentCdf[row + 1] - rowStart;
+    float v = (float(row) + ((random.y - rowStart) / max(rowProbability, FLOAT_MIN))) / float(height);
+
+    uint rowCdf = height + 1 + (row * (width + 1));
+    uint column = findCdfInterval(rowCdf, width, random.x);
+    float columnStart = environmentCdf[rowCdf + column];
+    float columnProbability = environmentCdf[rowCdf + column + 1] - columnStart;
+    float u = (float(column) + ((random.x - columnStart) / max(columnProbability, FLOAT_MIN))) / float(width);
+
+    // Converting from texture coordinates to solid angle divides by the area of the sphere's mapping, 2π², and by
+    // the sine of the polar angle.
+    float sinTheta = sin(v * PI);
+    pdf = sinTheta > 0.f ? (rowProbability * height * columnProbability * width) / (2.f * PI * PI * sinTheta) : 0.f;
+    return environmentDirection(vec2(u, v));
+}
+
+float environmentPdf(vec3 direction) {
+    uint width = environment.width;
+    uint height = environment.height;
+    vec2 coords = environmentCoords(direction);
+    uint column = min(uint(coords.x * width), width - 1);
+    uint row = min(uint(coords.y * height), height - 1);
+    uint rowCdf = height + 1 + (row * (width + 1));
+    float rowProbability = environmentCdf[row + 1] - environmentCdf[row];
+    float columnProbability = environmentCdf[rowCdf + column + 1] - environmentCdf[rowCdf + column];
+    float sinTheta = sin(coords.y * PI);
+    return sinTheta > 0.f ? (rowProbability * height * columnProbability * width) / (2.f * PI * PI * sinTheta) : 0.f;
+}
+
+// Texture coordinates of the direction on the environment map, turned by its rotation around the y axis.
+vec2 environmentCoords(vec3 direction) {
+    float c = cos(environment.rotation);
+    float s = sin(environment.rotation);
+    vec3 rotated = vec3((c * direction.x) + (s * direction.z), direction.y, (c * direction.z) - (s * direction.x));
+    return mappingOnUnitSphere(clamp(rotated, -1.f, 1.f));
+}
+
+// Inverse of `environmentCoords`.
+vec3 environmentDirection(vec2 coords) {
+    float phi = ((1.f - coords.x) * TWO_PI) - PI;
+    float theta = ((1.f - coords.y) * PI) - HALF_PI;
+    vec3 rotated = vec3(cos(theta) * cos(phi), sin(theta), cos(theta) * sin(phi));
+    float c = cos(environment.rotation);
+    float s = sin(environment.rotation);
+    return vec3((c * rotated.x) - (s * rotated.z), rotated.y, (s * rotated.x) + (c * rotated.z));
+}
+
+// Index of the interval of the CDF starting at `first` and spanning `count` intervals that `value` falls into.
+uint findCdfInterval(uint first, uint count, float value) {
+    uint low = 0;
+    uint high = count;
+    while (high - low > 1) {
+        uint middle = (low + high) / 2;
+        if (environmentCdf[first + middle] <= value) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
 #endif // RAY_TRACING
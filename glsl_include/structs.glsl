@@ -9,6 +9,14 @@ struct BihNode {
     float clipRight;
 };
 
+struct BvhNode {
+    uint nodeType;
+    uint childLeft;
+    uint childRight;
+    vec3 boundsMin;
+    vec3 boundsMax;
+};
+
 struct Triangle {
     vec3 positions[3];
     vec3 normals[3];
@@ -23,25 +31,87 @@ struct Material {
     float parameter;
 };
 
+struct Light {
+    uint lightType;
+    float intensity;
+    float cosInner;
+    float cosOuter;
+    vec4 color;
+    vec4 position;
+    vec4 direction;
+    vec4 edgeU;
+    vec4 edgeV;
+};
+
+struct Emitter {
+    uint triangleIndex;
+    uint alias;
+    float probability;
+    float power;
+};
+
 struct Ray {
     vec3 origin;
     vec3 direction;
     vec3 invDirection;
+    // Time within the shutter interval at which the ray sees the scene.
+    float time;
 };
 
 struct Hit {
     vec3 position;
     vec3 incidental;
     vec3 normal;
+    vec3 geometricNormal;
     vec2 texCoords;
     float distance;
     uint materialIndex;
+    uint triangleIndex;
     bool bFrontFace;
+    float time;
 };
 
 struct Scattering {
     Ray newRay;
     vec4 color;
+    // Light reaching the hit point straight from the light sources, already weighted by the material.
+    vec4 direct;
+    // Probability density of sampling `newRay`'s direction, or 0 if it couldn't have been picked by sampling a light.
+    float pdf;
+};
+
+// Properties of the first surface a camera ray hits, which the denoiser tells edges apart by and which are written out
+// as AOVs. The depth is the distance from the ray's origin, and zero if the ray hasn't hit anything, in which case the
+// indices are `NO_INDEX`.
+struct SurfaceFeatures {
+    vec3 albedo;
+    vec3 normal;
+    vec3 position;
+    float depth;
+    uint materialIndex;
+    uint objectIndex;
+};
+
+// Light brought back by a camera ray that has come straight from the emitters or the environment, and that has bounced
+// off a single surface. The rest of it is indirect.
+struct LightPaths {
+    vec3 emission;
+    vec3 direct;
+};
+
+// Filter-weighted sums of a pixel's samples and of the features and light paths recorded while tracing them.
+struct PixelSums {
+    vec4 colour;
+    vec3 albedo;
+    vec4 normalDepth;
+    vec3 position;
+    vec3 emission;
+    vec3 direct;
+    float weight;
+    // Indices can't be averaged, so the pixel gets those of its sample with the greatest weight.
+    float maxWeight;
+    uint materialIndex;
+    uint objectIndex;
 };
 
 #endif // TYPES
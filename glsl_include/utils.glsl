@@ -1,8 +1,12 @@
 #ifndef UTILS
 #define UTILS
 
+// Point of the whole image which the random numbers are drawn for, set by the shaders before drawing any so that the
+// regions and tiles the image is rendered in don't repeat each other's noise.
+vec2 randomCoords;
+
 float rand(float at) {
-    return fract(sin(dot((at + gl_FragCoord.xy), vec2(12.9898f, 78.233f))) * 43758.5453123f);
+    return fract(sin(dot((at + randomCoords), vec2(12.9898f, 78.233f))) * 43758.5453123f);
 }
 
 vec3 randPointInUnitCube(float at) {
@@ -29,6 +33,19 @@ vec3 pointOnRay(in Ray ray, float distance) {
     return ray.origin + (ray.direction * distance);
 }
 
+// Moves a point off of a surface along the surface's normal by a distance proportional to the magnitude of the
+// point's coordinates. Taken from: Wächter, Binder, "A Fast and Robust Method for Avoiding Self-Intersection",
+// Ray Tracing Gems (2019), matching `offset_ray_origin` in eruptrace_scene.
+vec3 offsetRayOrigin(vec3 position, vec3 normal) {
+    const float origin = 1.f / 32.f;
+    const float floatScale = 1.f / 65536.f;
+    const float intScale = 256.f;
+
+    ivec3 intOffset = ivec3(intScale * normal);
+    vec3 offsetPosition = intBitsToFloat(floatBitsToInt(position) + mix(intOffset, -intOffset, lessThan(position, vec3(0.f))));
+    return mix(offsetPosition, position + (floatScale * normal), lessThan(abs(position), vec3(origin)));
+}
+
 vec2 mappingOnUnitSphere(vec3 pointOnSphere) {
     return vec2(
         1.f - ((atan(pointOnSphere.z, pointOnSphere.x) + PI) * ONE_OVER_TWO_PI),
@@ -44,6 +61,16 @@ vec3 sampleNormalMap(vec2 texCoords, uint normalMapIndex) {
     return (normal * 2.f) - 1.f;
 }
 
+// Inverse of the encoding done by `encode_octahedral` in eruptrace_scene.
+vec3 decodeOctahedral(uint packedNormal) {
+    vec2 encoded = unpackSnorm2x16(packedNormal);
+    vec3 normal = vec3(encoded, 1.f - abs(encoded.x) - abs(encoded.y));
+    float fold = max(-normal.z, 0.f);
+    normal.x += normal.x >= 0.f ? -fold : fold;
+    normal.y += normal.y >= 0.f ? -fold : fold;
+    return normalize(normal);
+}
+
 vec3 mapNormal(vec3 worldNormal, vec3 mappedNormal) {
     vec3 t = cross(worldNormal, vec3(0.f, 1.f, 0.f));
     if (dot(t, t) == 0.f) {
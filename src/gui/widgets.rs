@@ -1,5 +1,10 @@
+use std::{ops::RangeInclusive, path::Path, sync::Arc};
+
+use eruptrace_scene::Sky;
 use nalgebra_glm as glm;
 
+use crate::post_processing::{CubeLut, PostEffect, PostStack, PostStage};
+
 pub fn drag_vec3(ui: &mut egui::Ui, vec: &mut glm::Vec3) -> egui::InnerResponse<()> {
     ui.horizontal(|ui| {
         ui.add(egui::DragValue::new(&mut vec.x).range(-1000.0..=1000.0).speed(0.1).prefix("X: "));
@@ -7,3 +12,163 @@ pub fn drag_vec3(ui: &mut egui::Ui, vec: &mut glm::Vec3) -> egui::InnerResponse<
         ui.add(egui::DragValue::new(&mut vec.z).range(-1000.0..=1000.0).speed(0.1).prefix("Z: "));
     })
 }
+
+/// Edits the sky's settings, returning whether any of them have changed.
+pub fn sky_settings(ui: &mut egui::Ui, sky: &mut Sky) -> bool {
+    let mut changed = false;
+
+    // The sun's direction is easier to edit as angles.
+    let mut elevation = sky.sun_direction.y.clamp(-1.0, 1.0).asin().to_degrees();
+    let mut azimuth = sky.sun_direction.z.atan2(sky.sun_direction.x).to_degrees();
+    let mut direction_changed = false;
+    ui.horizontal(|ui| {
+        direction_changed |=
+            ui.add(egui::DragValue::new(&mut elevation).range(-90.0..=90.0).speed(0.5).suffix("°")).changed();
+        ui.label("Sun elevation");
+    });
+    ui.horizontal(|ui| {
+        direction_changed |=
+            ui.add(egui::DragValue::new(&mut azimuth).range(-180.0..=180.0).speed(0.5).suffix("°")).changed();
+        ui.label("Sun azimuth");
+    });
+    if direction_changed {
+        let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+        sky.sun_direction =
+            glm::vec3(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+        changed = true;
+    }
+
+    ui.horizontal(|ui| {
+        changed |= ui.add(egui::DragValue::new(&mut sky.turbidity).range(1.7..=10.0).speed(0.05)).changed();
+        ui.label("Turbidity");
+    });
+    ui.horizontal(|ui| {
+        let mut albedo = sky.ground_albedo.into();
+        if ui.color_edit_button_rgb(&mut albedo).changed() {
+            sky.ground_albedo = albedo.into();
+            changed = true;
+        }
+        ui.label("Ground albedo");
+    });
+    ui.horizontal(|ui| {
+        changed |= ui.add(egui::DragValue::new(&mut sky.sun_intensity).range(0.0..=100.0).speed(0.1)).changed();
+        ui.label("Sun intensity");
+    });
+    ui.horizontal(|ui| {
+        let mut size = sky.sun_size.to_degrees();
+        if ui.add(egui::DragValue::new(&mut size).range(0.1..=20.0).speed(0.05).suffix("°")).changed() {
+            sky.sun_size = size.to_radians();
+            changed = true;
+        }
+        ui.label("Sun size");
+    });
+
+    changed
+}
+
+/// Edits the post-processing effects and their order, returning whether any of them have changed. LUTs are loaded
+/// from paths relative to the scene's directory.
+pub fn post_stack(ui: &mut egui::Ui, stack: &mut PostStack, scene_path: &Path) -> bool {
+    let mut changed = false;
+    let mut moved = None;
+    let mut removed = None;
+    let stage_count = stack.stages.len();
+    for (i, stage) in stack.stages.iter_mut().enumerate() {
+        ui.push_id(i, |ui| {
+            ui.horizontal(|ui| {
+                changed |= ui.checkbox(&mut stage.enabled, stage.effect.label()).changed();
+                if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                    moved = Some((i, i - 1));
+                }
+                if ui.add_enabled(i + 1 < stage_count, egui::Button::new("⏷")).clicked() {
+                    moved = Some((i, i + 1));
+                }
+                if ui.button("🗙").clicked() {
+                    removed = Some(i);
+                }
+            });
+            ui.add_enabled_ui(stage.enabled, |ui| {
+                ui.indent("settings", |ui| changed |= post_effect_settings(ui, &mut stage.effect, scene_path));
+            });
+        });
+    }
+    if let Some((from, to)) = moved {
+        stack.stages.swap(from, to);
+        changed = true;
+    }
+    if let Some(i) = removed {
+        stack.stages.remove(i);
+        changed = true;
+    }
+
+    ui.menu_button("Add effect", |ui| {
+        for effect in PostEffect::defaults() {
+            if ui.button(effect.label()).clicked() {
+                stack.stages.push(PostStage::new(effect));
+                changed = true;
+                ui.close_menu();
+            }
+        }
+    });
+
+    changed
+}
+
+fn post_effect_settings(ui: &mut egui::Ui, effect: &mut PostEffect, scene_path: &Path) -> bool {
+    let drag = |ui: &mut egui::Ui, value: &mut f32, range: RangeInclusive<f32>, speed: f64, label: &str| {
+        ui.horizontal(|ui| {
+            let changed = ui.add(egui::DragValue::new(value).range(range).speed(speed)).changed();
+            ui.label(label);
+            changed
+        })
+        .inner
+    };
+    match effect {
+        PostEffect::Bloom { threshold, intensity, radius } => {
+            let mut changed = drag(ui, threshold, 0.0..=100.0, 0.05, "Threshold");
+            changed |= drag(ui, intensity, 0.0..=10.0, 0.01, "Intensity");
+            changed | drag(ui, radius, 0.0..=0.5, 0.001, "Radius")
+        }
+        PostEffect::Vignette { strength } => drag(ui, strength, 0.0..=1.0, 0.01, "Strength"),
+        PostEffect::ChromaticAberration { strength } => drag(ui, strength, -0.1..=0.1, 0.0005, "Strength"),
+        PostEffect::WhiteBalance { temperature, tint } => {
+            let changed = drag(ui, temperature, 1667.0..=25000.0, 50.0, "Temperature (K)");
+            changed | drag(ui, tint, -1.0..=1.0, 0.01, "Tint")
+        }
+        PostEffect::Saturation { saturation } => drag(ui, saturation, 0.0..=4.0, 0.01, "Saturation"),
+        PostEffect::Contrast { contrast } => drag(ui, contrast, 0.1..=4.0, 0.01, "Contrast"),
+        PostEffect::Lut { path, lut } => {
+            // The error of the last attempt to load the LUT is kept until it is loaded successfully.
+            let error_id = ui.id().with("lut_error");
+            let mut changed = false;
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(path);
+                if ui.button("Load").clicked() {
+                    match CubeLut::load(&scene_path.join(&*path)) {
+                        Ok(loaded) => {
+                            *lut = Some(Arc::new(loaded));
+                            ui.data_mut(|data| data.remove::<String>(error_id));
+                        }
+                        Err(e) => {
+                            *lut = None;
+                            ui.data_mut(|data| data.insert_temp(error_id, format!("Loading failed: {e}")));
+                        }
+                    }
+                    changed = true;
+                }
+            });
+            if let Some(error) = ui.data(|data| data.get_temp::<String>(error_id)) {
+                ui.label(error);
+            } else if let Some(lut) = lut {
+                ui.label(format!("{0}×{0}×{0} entries", lut.size()));
+            } else {
+                ui.label("Not loaded");
+            }
+            changed
+        }
+        PostEffect::Unknown { object } => {
+            ui.label(format!("Not supported: {object}"));
+            false
+        }
+    }
+}
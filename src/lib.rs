@@ -1,9 +1,11 @@
 pub mod gui;
+pub mod post_processing;
+mod render_output;
 mod shaders;
 
 use std::{
     borrow::Borrow,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
@@ -12,15 +14,34 @@ use egui::{ClippedPrimitive, FullOutput, TextureOptions, TexturesDelta, Viewport
 use erupt::{utils::surface, vk, DeviceLoader, EntryLoader, ExtendableFrom, InstanceLoader, ObjectHandle, SmallVec};
 use erupt_bootstrap as vkb;
 use eruptrace_deferred::DeferredRayTracer;
+use eruptrace_denoiser::{Denoiser, DenoiserSettings};
 use eruptrace_pure::PureRayTracer;
-use eruptrace_scene::{camera::Camera, CameraUniform, RtSceneBuffers, Scene};
+use eruptrace_scene::{
+    camera::Camera,
+    BihStats,
+    CameraUniform,
+    EnvironmentSource,
+    Exposure,
+    ImageRegion,
+    PathStats,
+    PixelFilter,
+    Projection,
+    RebuildPolicy,
+    RtSceneBuffers,
+    Scene,
+    TriangleLayout,
+};
 use eruptrace_vk::{
+    command,
     contexts::{FrameContext, RenderContext, VulkanContext},
     debug::debug_callback,
     push_constants::{RtFlags, RtPushConstants},
+    tiles,
     AllocatedBuffer,
     AllocatedImage,
+    RenderTargets,
 };
+use nalgebra_glm as glm;
 use vk_mem_3_erupt as vma;
 use winit::{
     application::ApplicationHandler,
@@ -29,7 +50,11 @@ use winit::{
     window::{Window, WindowId},
 };
 
-use crate::gui::{widgets, GuiIntegration};
+use crate::{
+    gui::{widgets, GuiIntegration},
+    post_processing::PostStack,
+    render_output::{RenderLayer, RenderOutput},
+};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum RendererChoice {
@@ -38,15 +63,105 @@ enum RendererChoice {
 }
 
 pub struct EruptraceArgs {
-    scene_path: PathBuf,
+    scene_path:       PathBuf,
+    bih_stats:        bool,
+    export_bih:       Option<PathBuf>,
+    triangle_layout:  TriangleLayout,
+    /// Directory to render the scene's animation into, frame by frame, instead of opening the GUI.
+    render_animation: Option<PathBuf>,
+    /// Image file to render the scene into instead of opening the GUI.
+    output:           Option<PathBuf>,
+    /// Image size overriding the one given in camera.json.
+    resolution:       Option<[u32; 2]>,
+    /// Size of the tiles rendered in separate submissions, in pixels.
+    tile_size:        Option<u32>,
+    /// How long a single submission may take before the render is given up on.
+    submit_timeout:   Option<Duration>,
+    /// Part of the image to render, saving only that part.
+    crop:             Option<ImageRegion>,
+    /// Error threshold enabling adaptive sampling with the pure renderer.
+    adaptive:         Option<f32>,
+    /// Number of samples adaptive sampling takes at most for a pixel.
+    max_samples:      Option<u32>,
+    denoise:          bool,
+    /// Whether to render the arbitrary output variables, which are saved as layers of EXR files.
+    aovs:             bool,
+    /// Whether to leave the environment out of the image where nothing has been hit.
+    transparent:      bool,
 }
 
 impl EruptraceArgs {
     pub fn parse_args() -> Result<Self, pico_args::Error> {
         let mut pargs = pico_args::Arguments::from_env();
-        let args = Self { scene_path: pargs.free_from_str()? };
+        let args = Self {
+            bih_stats:        pargs.contains("--bih-stats"),
+            export_bih:       pargs.opt_value_from_str("--export-bih")?,
+            triangle_layout:  pargs.opt_value_from_str("--triangle-layout")?.unwrap_or_default(),
+            render_animation: pargs.opt_value_from_str("--render-animation")?,
+            output:           pargs.opt_value_from_str("--output")?,
+            resolution:       pargs.opt_value_from_fn("--resolution", parse_resolution)?,
+            tile_size:        pargs.opt_value_from_str("--tile-size")?,
+            submit_timeout:   pargs.opt_value_from_fn("--submit-timeout", parse_seconds)?,
+            crop:             pargs.opt_value_from_fn("--crop", parse_crop)?,
+            adaptive:         pargs.opt_value_from_str("--adaptive")?,
+            max_samples:      pargs.opt_value_from_str("--max-samples")?,
+            denoise:          pargs.contains("--denoise"),
+            aovs:             pargs.contains("--aovs"),
+            transparent:      pargs.contains("--transparent"),
+            scene_path:       pargs.free_from_str()?,
+        };
         Ok(args)
     }
+
+    /// Whether the arguments only ask for work that doesn't need a window.
+    pub fn is_headless(&self) -> bool {
+        self.bih_stats || self.export_bih.is_some()
+    }
+
+    /// Whether the arguments ask for rendering straight into files, without the GUI.
+    pub fn is_batch(&self) -> bool {
+        self.render_animation.is_some() || self.output.is_some()
+    }
+}
+
+/// Parses an image size written as `<width>x<height>`.
+fn parse_resolution(s: &str) -> Result<[u32; 2], String> {
+    let invalid = || format!("Invalid resolution '{s}', expected <width>x<height>.");
+    let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok([width, height]),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a positive duration written in seconds.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    match s.parse() {
+        Ok(seconds) if seconds > 0.0 => Duration::try_from_secs_f32(seconds).map_err(|e| e.to_string()),
+        _ => Err(format!("Invalid duration '{s}', expected a positive number of seconds.")),
+    }
+}
+
+/// Parses a region of the image written as `<x>,<y>,<width>,<height>`.
+fn parse_crop(s: &str) -> Result<ImageRegion, String> {
+    let invalid = || format!("Invalid crop region '{s}', expected <x>,<y>,<width>,<height>.");
+    let values: Vec<u32> = s.split(',').map(str::parse).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(ImageRegion { x, y, width, height }),
+        _ => Err(invalid()),
+    }
+}
+
+pub fn run_headless(args: &EruptraceArgs) -> anyhow::Result<()> {
+    let (_, scene) = Scene::load(&args.scene_path)?;
+    if args.bih_stats {
+        print!("{}", scene.bih.stats());
+    }
+    if let Some(path) = &args.export_bih {
+        scene.bih.export(path)?;
+        println!("BIH exported to {}", path.display());
+    }
+    Ok(())
 }
 
 pub struct App {
@@ -73,22 +188,96 @@ pub struct AppState {
     frames:                Vec<FrameContext>,
     upload_fence:          vk::Fence,
     allocator:             Option<Arc<RwLock<vma::Allocator>>>,
+    max_image_dimension:   u32,
 
     gui_integration:  Option<GuiIntegration>,
     renderer_choice:  RendererChoice,
     use_bih:          bool,
+    use_bvh:          bool,
     render_normals:   bool,
     render_bih:       bool,
+    russian_roulette: bool,
+    adaptive:         bool,
+    sample_heatmap:   bool,
+    denoise:          bool,
+    aovs:             bool,
+    transparent:      bool,
+    /// Layer of the last render shown in the GUI.
+    view_layer:       RenderLayer,
     target_texture:   Option<egui::TextureHandle>,
     last_render_time: Option<Duration>,
+    path_stats:       Option<PathStats>,
 
     rt_camera:         Camera,
     rt_camera_buffer:  Option<AllocatedBuffer<CameraUniform>>,
     rt_scene_buffers:  Option<RtSceneBuffers>,
     rt_push_constants: RtPushConstants,
 
+    rt_scene:        Scene,
+    bih_stats:       BihStats,
+    bih_export_path: String,
+    bih_export_info: Option<String>,
+
+    /// Directory the scene has been loaded from, which keeps the post-processing settings in its camera.json.
+    scene_path:     PathBuf,
+    post_stack:     PostStack,
+    post_save_info: Option<String>,
+
     pure_ray_tracer:     Option<PureRayTracer>,
     deferred_ray_tracer: Option<DeferredRayTracer>,
+    denoiser:            Option<Denoiser>,
+    denoiser_settings:   DenoiserSettings,
+
+    tile_size:      u32,
+    /// How long a tile may take before the render is given up on.
+    submit_timeout: Duration,
+    render_job:     Option<RenderJob>,
+    /// Why the last render has been given up on.
+    render_error:   Option<String>,
+    /// Part of the image to trace, leaving the rest as it was in the previous render.
+    crop_region:    Option<ImageRegion>,
+    /// Previous render at full size, which renders of the crop region are composited over.
+    last_render:    Option<RenderOutput>,
+}
+
+fn rect_to_region(rect: vk::Rect2D) -> ImageRegion {
+    ImageRegion {
+        x:      rect.offset.x as u32,
+        y:      rect.offset.y as u32,
+        width:  rect.extent.width,
+        height: rect.extent.height,
+    }
+}
+
+/// Size of the regions rendered at once, whose render targets take 48 bytes for each of their pixels, and adaptive
+/// sampling another 80 bytes of statistics. Rendering the AOVs takes 128 bytes for each, so the regions are halved.
+const MAX_REGION_SIZE: u32 = 4096;
+
+/// How long the GUI keeps rendering tiles before drawing a frame to show the progress.
+const GUI_RENDER_TIME_SLICE: Duration = Duration::from_millis(50);
+
+/// Render split into tiles which are submitted one at a time, so that no single submission runs long enough to trip
+/// the driver's watchdog, and so that the render can be cancelled in between.
+struct RenderJob {
+    /// Regions that haven't been started yet, last one first.
+    regions:     Vec<ImageRegion>,
+    current:     Option<RegionRender>,
+    crop:        Option<ImageRegion>,
+    /// Number of passes over each region, more than one when sampling adaptively.
+    passes:      u32,
+    denoise:     bool,
+    /// Linear RGBA values of each of the render targets over the whole image.
+    pixels:      Vec<Vec<f32>>,
+    tiles_done:  u32,
+    tiles_total: u32,
+    render_time: Duration,
+}
+
+struct RegionRender {
+    region:  ImageRegion,
+    targets: RenderTargets,
+    /// Tiles that haven't been rendered yet along with the passes they belong to, last one first.
+    tiles:   Vec<(vk::Rect2D, u32)>,
 }
 
 impl App {
@@ -97,10 +286,61 @@ impl App {
     }
 
     fn init(&mut self, event_loop: &ActiveEventLoop) {
-        self.window = Some(event_loop.create_window(Window::default_attributes().with_title("ErupTrace")).unwrap());
+        // Rendering into files still needs a window to create the Vulkan context, but there's no need to show it.
+        let window_attributes =
+            Window::default_attributes().with_title("ErupTrace").with_visible(!self.args.is_batch());
+        self.window = Some(event_loop.create_window(window_attributes).unwrap());
+
+        let (mut camera, scene) = Scene::load(&self.args.scene_path).unwrap();
+        if let Some(resolution) = self.args.resolution {
+            camera.img_size = resolution;
+        }
+        self.app_state = Some(
+            AppState::new(event_loop, self.window.as_ref().unwrap(), camera, scene, self.args.triangle_layout).unwrap(),
+        );
+        let app_state = self.app_state.as_mut().unwrap();
+        if let Some(tile_size) = self.args.tile_size {
+            app_state.tile_size = tile_size.max(1);
+        }
+        if let Some(submit_timeout) = self.args.submit_timeout {
+            app_state.submit_timeout = submit_timeout;
+        }
+        app_state.crop_region = self.args.crop;
+        if let Some(threshold) = self.args.adaptive {
+            app_state.adaptive = true;
+            app_state.rt_push_constants.adaptive_threshold = threshold;
+        }
+        if let Some(max_samples) = self.args.max_samples {
+            app_state.rt_push_constants.max_samples = max_samples.max(1);
+        }
+        app_state.denoise = self.args.denoise;
+        app_state.aovs = self.args.aovs;
+        app_state.transparent = self.args.transparent;
+        app_state.scene_path = self.args.scene_path.clone();
+        app_state.post_stack = PostStack::load(&self.args.scene_path).unwrap_or_else(|e| {
+            eprintln!("Cannot load the post-processing stack: {}", e);
+            PostStack::default()
+        });
 
-        let (camera, scene) = Scene::load(&self.args.scene_path).unwrap();
-        self.app_state = Some(AppState::new(event_loop, self.window.as_ref().unwrap(), camera, scene).unwrap());
+        if self.args.is_batch() {
+            let result = match (&self.args.render_animation, &self.args.output) {
+                (Some(output_dir), _) => app_state.render_animation(output_dir),
+                (None, Some(path)) => app_state.render_to_file(path).map(|()| {
+                    println!(
+                        "Rendered in {}s to {}",
+                        app_state.last_render_time.unwrap_or_default().as_secs_f32(),
+                        path.display()
+                    )
+                }),
+                (None, None) => unreachable!(),
+            };
+            self.app_state = None;
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            event_loop.exit();
+        }
     }
 }
 
@@ -114,7 +354,10 @@ impl ApplicationHandler for App {
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _window_id: WindowId, event: WindowEvent) {
         let window = self.window.as_ref().unwrap();
-        let app_state = self.app_state.as_mut().unwrap();
+        let Some(app_state) = self.app_state.as_mut() else {
+            // Already torn down after rendering an animation.
+            return;
+        };
         let egui_context = app_state.egui_context.clone();
         match event {
             WindowEvent::CloseRequested => event_loop.exit(),
@@ -123,6 +366,8 @@ impl ApplicationHandler for App {
                 app_state.resize(vk::Extent2D { width, height })
             }
             WindowEvent::RedrawRequested => {
+                app_state.continue_render(&egui_context);
+
                 let new_input = app_state.egui_winit_state.take_egui_input(self.window.as_ref().unwrap());
                 let FullOutput { platform_output, textures_delta, shapes, pixels_per_point, viewport_output } =
                     egui_context.run(new_input, |egui_context| app_state.gui(egui_context));
@@ -147,6 +392,10 @@ impl ApplicationHandler for App {
                 app_state.egui_winit_state.handle_platform_output(self.window.as_ref().unwrap(), platform_output);
                 let clipped_primitives = app_state.egui_context.tessellate(shapes, pixels_per_point);
                 app_state.render(&textures_delta, clipped_primitives);
+
+                if app_state.render_job.is_some() {
+                    window.request_redraw();
+                }
             }
             event => {
                 let response = app_state.egui_winit_state.on_window_event(self.window.as_ref().unwrap(), &event);
@@ -162,7 +411,13 @@ impl ApplicationHandler for App {
 }
 
 impl AppState {
-    pub fn new(event_loop: &ActiveEventLoop, window: &Window, rt_camera: Camera, scene: Scene) -> anyhow::Result<Self> {
+    pub fn new(
+        event_loop: &ActiveEventLoop,
+        window: &Window,
+        rt_camera: Camera,
+        scene: Scene,
+        triangle_layout: TriangleLayout,
+    ) -> anyhow::Result<Self> {
         let entry = EntryLoader::new()?;
         let (instance, debug_messenger, instance_meta) = {
             let builder = vkb::InstanceBuilder::new()
@@ -187,9 +442,10 @@ impl AppState {
             let graphics_present = vkb::QueueFamilyCriteria::graphics_present();
             let mut vulkan_1_3_features =
                 vk::PhysicalDeviceVulkan13FeaturesBuilder::new().dynamic_rendering(true).synchronization2(true);
-            let device_features = vk::PhysicalDeviceFeatures2Builder::new()
-                .extend_from(&mut vulkan_1_3_features)
-                .features(vk::PhysicalDeviceFeaturesBuilder::new().logic_op(true).build());
+            let device_features =
+                vk::PhysicalDeviceFeatures2Builder::new().extend_from(&mut vulkan_1_3_features).features(
+                    vk::PhysicalDeviceFeaturesBuilder::new().logic_op(true).fragment_stores_and_atomics(true).build(),
+                );
             let device_builder = vkb::DeviceBuilder::new()
                 .require_version(1, 3)
                 .require_extension(vk::KHR_SWAPCHAIN_EXTENSION_NAME)
@@ -242,6 +498,19 @@ impl AppState {
 
         let swapchain_image_views = SmallVec::new();
 
+        let limits =
+            unsafe { instance.as_ref().unwrap().get_physical_device_properties(device_meta.physical_device()) }.limits;
+        let max_image_dimension = limits.max_image_dimension2_d;
+        // The ray tracers' pipelines have an attachment for each of the render targets, even if the AOVs are off.
+        let max_attachments = limits.max_color_attachments.min(limits.max_fragment_output_attachments) as usize;
+        if max_attachments < RenderTargets::COUNT {
+            anyhow::bail!(
+                "The device supports {} colour attachments, but {} are needed for the render targets.",
+                max_attachments,
+                RenderTargets::COUNT
+            );
+        }
+
         let command_pool = {
             let create_info = vk::CommandPoolCreateInfoBuilder::new()
                 .flags(vk::CommandPoolCreateFlags::TRANSIENT | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -307,12 +576,14 @@ impl AppState {
             queue,
             command_pool,
             upload_fence,
+            submit_timeout: command::DEFAULT_SUBMIT_TIMEOUT,
         };
 
         let gui = Some(GuiIntegration::new(vk_ctx.clone(), swapchain.frames_in_flight()));
 
         let scene_meshes = scene.meshes.clone();
-        let rt_scene_buffers = Some(scene.create_buffers(vk_ctx.clone()));
+        let bih_stats = scene.bih.stats();
+        let rt_scene_buffers = Some(scene.create_buffers(vk_ctx.clone(), triangle_layout));
         let rt_camera_buffer = Some(rt_camera.into_uniform().create_buffer(vk_ctx.allocator.clone()));
 
         let pure_ray_tracer = Some(PureRayTracer::new(
@@ -322,6 +593,8 @@ impl AppState {
             rt_scene_buffers.as_ref().unwrap(),
         ));
 
+        let denoiser = Some(Denoiser::new(vk_ctx.clone()));
+
         let deferred_ray_tracer = Some(DeferredRayTracer::new(
             vk_ctx,
             rt_camera,
@@ -357,33 +630,67 @@ impl AppState {
             frames,
             upload_fence,
             allocator,
+            max_image_dimension,
             gui_integration: gui,
             renderer_choice: RendererChoice::Pure,
             use_bih: false,
+            use_bvh: false,
             render_normals: false,
             render_bih: false,
+            russian_roulette: false,
+            adaptive: false,
+            sample_heatmap: false,
+            denoise: false,
+            aovs: false,
+            transparent: false,
+            view_layer: RenderLayer::Colour,
             target_texture: None,
             last_render_time: None,
+            path_stats: None,
             rt_camera,
             rt_push_constants: RtPushConstants {
-                n_triangles:    rt_scene_buffers.as_ref().unwrap().n_triangles,
-                flags:          RtFlags::empty(),
-                draw_bih_level: 0,
+                n_triangles:        rt_scene_buffers.as_ref().unwrap().n_triangles,
+                flags:              triangle_layout.rt_flags(),
+                draw_bih_node:      0,
+                n_lights:           rt_scene_buffers.as_ref().unwrap().n_lights,
+                n_emitters:         rt_scene_buffers.as_ref().unwrap().n_emitters,
+                emitter_power:      scene.emitters.total_power,
+                rr_min_bounces:     3,
+                crop_region:        [0; 4],
+                adaptive_threshold: 0.05,
+                max_samples:        256,
+                sample_pass:        0,
             },
+            rt_scene: scene,
+            bih_stats,
+            bih_export_path: String::from("bih.obj"),
+            bih_export_info: None,
+            scene_path: PathBuf::new(),
+            post_stack: PostStack::default(),
+            post_save_info: None,
             rt_camera_buffer,
             rt_scene_buffers,
             pure_ray_tracer,
             deferred_ray_tracer,
+            denoiser,
+            denoiser_settings: DenoiserSettings::default(),
+            tile_size: 256,
+            submit_timeout: command::DEFAULT_SUBMIT_TIMEOUT,
+            render_job: None,
+            render_error: None,
+            crop_region: None,
+            last_render: None,
         })
     }
 
     fn vulkan_context(&self) -> VulkanContext {
         VulkanContext {
-            allocator:    self.allocator.as_ref().unwrap().clone(),
-            device:       self.device.as_ref().unwrap().clone(),
-            queue:        self.queue,
-            command_pool: self.command_pool,
-            upload_fence: self.upload_fence,
+            allocator:      self.allocator.as_ref().unwrap().clone(),
+            device:         self.device.as_ref().unwrap().clone(),
+            queue:          self.queue,
+            command_pool:   self.command_pool,
+            upload_fence:   self.upload_fence,
+            submit_timeout: self.submit_timeout,
         }
     }
 
@@ -391,8 +698,27 @@ impl AppState {
         self.swapchain.update(extent);
     }
 
+    pub fn update_mesh_transform(&mut self, mesh_index: usize, transform: glm::Mat4x4) {
+        let update = self.rt_scene.update_mesh_transform(mesh_index, transform, RebuildPolicy::SahThreshold(1.5));
+        self.rt_scene_buffers.as_ref().unwrap().apply_update(&self.rt_scene, &update);
+        self.rt_push_constants.emitter_power = self.rt_scene.emitters.total_power;
+        self.deferred_ray_tracer.as_mut().unwrap().update_mesh_transform(mesh_index, transform);
+        self.bih_stats = self.rt_scene.bih.stats();
+    }
+
+    pub fn update_environment(&mut self) {
+        let vk_ctx = self.vulkan_context();
+        let buffers = self.rt_scene_buffers.as_mut().unwrap();
+        buffers.update_environment(vk_ctx, &self.rt_scene);
+        self.rt_push_constants.n_lights = buffers.n_lights;
+    }
+
     pub fn gui(&mut self, ctx: &egui::Context) {
         egui::SidePanel::left("panel-settings").show(ctx, |ui| {
+            if self.render_job.is_some() {
+                // Changes to the settings would only apply to the tiles that haven't been rendered yet.
+                ui.disable();
+            }
             ui.heading("Settings");
 
             egui::CollapsingHeader::new("Renderer").default_open(true).show(ui, |ui| {
@@ -402,7 +728,14 @@ impl AppState {
 
             egui::CollapsingHeader::new("Render options").default_open(true).show(ui, |ui| {
                 if ui.checkbox(&mut self.use_bih, "Use BIH").clicked() {
+                    self.use_bvh &= !self.use_bih;
                     self.rt_push_constants.flags.set(RtFlags::USE_BIH, self.use_bih);
+                    self.rt_push_constants.flags.set(RtFlags::USE_BVH, self.use_bvh);
+                }
+                if ui.checkbox(&mut self.use_bvh, "Use BVH").clicked() {
+                    self.use_bih &= !self.use_bvh;
+                    self.rt_push_constants.flags.set(RtFlags::USE_BIH, self.use_bih);
+                    self.rt_push_constants.flags.set(RtFlags::USE_BVH, self.use_bvh);
                 }
                 if ui.checkbox(&mut self.render_normals, "Render normals").clicked() {
                     self.rt_push_constants.flags.set(RtFlags::RENDER_NORMALS, self.render_normals);
@@ -410,23 +743,182 @@ impl AppState {
                 if ui.checkbox(&mut self.render_bih, "Render BIH").clicked() {
                     self.rt_push_constants.flags.set(RtFlags::RENDER_BIH, self.render_bih);
                 }
+                if ui.checkbox(&mut self.russian_roulette, "Russian roulette").clicked() {
+                    self.rt_push_constants.flags.set(RtFlags::RUSSIAN_ROULETTE, self.russian_roulette);
+                }
+                ui.add_enabled_ui(self.russian_roulette, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.rt_push_constants.rr_min_bounces).range(1..=100).speed(1),
+                        );
+                        ui.label("Min bounces");
+                    });
+                });
+                ui.add_enabled_ui(self.renderer_choice == RendererChoice::Pure, |ui| {
+                    ui.checkbox(&mut self.adaptive, "Adaptive sampling");
+                    ui.add_enabled_ui(self.adaptive, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::DragValue::new(&mut self.rt_push_constants.adaptive_threshold)
+                                    .range(0.001..=1.0)
+                                    .speed(0.001),
+                            );
+                            ui.label("Error threshold");
+                        });
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::DragValue::new(&mut self.rt_push_constants.max_samples).range(1..=65536).speed(1),
+                            );
+                            ui.label("Max samples");
+                        });
+                        ui.checkbox(&mut self.sample_heatmap, "Sample count heatmap");
+                    });
+                });
+                ui.checkbox(&mut self.denoise, "Denoise");
+                ui.add_enabled_ui(self.denoise, |ui| {
+                    let settings = &mut self.denoiser_settings;
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut settings.iterations).range(1..=10).speed(1));
+                        ui.label("Iterations");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut settings.sigma_colour).range(0.01..=100.0).speed(0.01));
+                        ui.label("Colour sigma");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut settings.sigma_normal).range(0.0..=512.0).speed(1.0));
+                        ui.label("Normal sigma");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut settings.sigma_depth).range(0.001..=10.0).speed(0.001));
+                        ui.label("Depth sigma");
+                    });
+                });
+                ui.checkbox(&mut self.aovs, "Render AOVs");
+                ui.checkbox(&mut self.transparent, "Transparent background");
+                ui.horizontal(|ui| {
+                    let max_node = self.rt_scene.bih.nodes.len() as u32 - 1;
+                    ui.add(
+                        egui::DragValue::new(&mut self.rt_push_constants.draw_bih_node).range(0..=max_node).speed(1),
+                    );
+                    ui.label("Draw BIH node");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.tile_size).range(16..=4096).speed(1));
+                    ui.label("Tile size");
+                });
+                ui.horizontal(|ui| {
+                    let mut seconds = self.submit_timeout.as_secs_f32();
+                    ui.add(egui::DragValue::new(&mut seconds).range(1.0..=600.0).speed(0.1));
+                    self.submit_timeout = Duration::from_secs_f32(seconds);
+                    ui.label("Tile timeout (s)");
+                });
+            });
+
+            egui::CollapsingHeader::new("Post-processing").default_open(false).show(ui, |ui| {
+                if widgets::post_stack(ui, &mut self.post_stack, &self.scene_path) && self.last_render.is_some() {
+                    self.show_last_render(ctx);
+                }
+                if ui.button("Save to camera.json").clicked() {
+                    self.post_save_info = Some(match self.post_stack.save(&self.scene_path) {
+                        Ok(()) => String::from("Saved"),
+                        Err(e) => format!("Saving failed: {}", e),
+                    });
+                }
+                if let Some(info) = self.post_save_info.borrow() {
+                    ui.label(info);
+                }
+            });
+
+            egui::CollapsingHeader::new("BIH").default_open(false).show(ui, |ui| {
+                let stats = &self.bih_stats;
+                ui.label(format!("Nodes: {}", stats.node_count));
+                ui.label(format!("Leaves: {} ({} empty)", stats.leaf_count, stats.empty_leaf_count));
+                ui.label(format!("Depth: {}", stats.depth_histogram.len().saturating_sub(1)));
+                ui.label(format!("SAH cost: {:.3}", stats.sah_cost));
+                egui::CollapsingHeader::new("Depth histogram").show(ui, |ui| {
+                    for (depth, count) in stats.depth_histogram.iter().enumerate() {
+                        ui.label(format!("{}: {}", depth, count));
+                    }
+                });
+                egui::CollapsingHeader::new("Leaf size histogram").show(ui, |ui| {
+                    for (size, count) in stats.leaf_size_histogram.iter().enumerate().filter(|(_, &c)| c > 0) {
+                        ui.label(format!("{}: {}", size, count));
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.bih_export_path);
+                    ui.label("Export path");
+                });
                 ui.horizontal(|ui| {
-                    ui.add(egui::DragValue::new(&mut self.rt_push_constants.draw_bih_level).range(0..=4096).speed(1));
-                    ui.label("Draw BIH level");
+                    for extension in ["obj", "json"] {
+                        if ui.button(format!("Export {}", extension.to_uppercase())).clicked() {
+                            let path = PathBuf::from(&self.bih_export_path).with_extension(extension);
+                            self.bih_export_info = Some(match self.rt_scene.bih.export(&path) {
+                                Ok(()) => format!("Exported to {}", path.display()),
+                                Err(e) => format!("Export failed: {}", e),
+                            });
+                        }
+                    }
                 });
+                if let Some(info) = self.bih_export_info.borrow() {
+                    ui.label(info);
+                }
             });
 
             egui::CollapsingHeader::new("Image size").default_open(true).show(ui, |ui| {
                 ui.horizontal(|ui| {
-                    ui.add(egui::DragValue::new(&mut self.rt_camera.img_size[0]).range(1..=4096).speed(1));
+                    ui.add(egui::DragValue::new(&mut self.rt_camera.img_size[0]).range(1..=65536).speed(1));
                     ui.label("Width");
                 });
                 ui.horizontal(|ui| {
-                    ui.add(egui::DragValue::new(&mut self.rt_camera.img_size[1]).range(1..=4096).speed(1));
+                    ui.add(egui::DragValue::new(&mut self.rt_camera.img_size[1]).range(1..=65536).speed(1));
                     ui.label("Height");
                 });
+
+                let [width, height] = self.rt_camera.img_size;
+                let mut crop = self.crop_region.is_some();
+                if ui.checkbox(&mut crop, "Crop").changed() {
+                    self.crop_region = crop.then_some(ImageRegion {
+                        x:      width / 4,
+                        y:      height / 4,
+                        width:  (width / 2).max(1),
+                        height: (height / 2).max(1),
+                    });
+                }
+                if let Some(region) = &mut self.crop_region {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut region.x).range(0..=width - 1).speed(1));
+                        ui.add(egui::DragValue::new(&mut region.y).range(0..=height - 1).speed(1));
+                        ui.label("Crop offset");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut region.width)
+                                .range(1..=width.saturating_sub(region.x).max(1))
+                                .speed(1),
+                        );
+                        ui.add(
+                            egui::DragValue::new(&mut region.height)
+                                .range(1..=height.saturating_sub(region.y).max(1))
+                                .speed(1),
+                        );
+                        ui.label("Crop size");
+                    });
+                }
             });
 
+            if let EnvironmentSource::Sky(sky) = &mut self.rt_scene.environment.source {
+                let mut changed = false;
+                egui::CollapsingHeader::new("Sky").default_open(true).show(ui, |ui| {
+                    changed = widgets::sky_settings(ui, sky);
+                });
+                if changed {
+                    self.update_environment();
+                }
+            }
+
             egui::CollapsingHeader::new("Camera").default_open(true).show(ui, |ui| {
                 ui.label("Position");
                 widgets::drag_vec3(ui, &mut self.rt_camera.position);
@@ -434,10 +926,72 @@ impl AppState {
                 widgets::drag_vec3(ui, &mut self.rt_camera.look_at);
                 ui.label("Up");
                 widgets::drag_vec3(ui, &mut self.rt_camera.up);
+                egui::ComboBox::from_label("Projection")
+                    .selected_text(format!("{:?}", self.rt_camera.projection))
+                    .show_ui(ui, |ui| {
+                        for projection in [
+                            Projection::Perspective,
+                            Projection::Orthographic,
+                            Projection::Equirectangular,
+                            Projection::Fisheye,
+                        ] {
+                            ui.selectable_value(
+                                &mut self.rt_camera.projection,
+                                projection,
+                                format!("{:?}", projection),
+                            );
+                        }
+                    });
+                if self.rt_camera.projection == Projection::Orthographic {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.rt_camera.ortho_height).range(0.001..=1000.0).speed(0.01),
+                        );
+                        ui.label("Ortho height");
+                    });
+                }
                 ui.horizontal(|ui| {
                     ui.add(egui::DragValue::new(&mut self.rt_camera.vertical_fov).range(0.0..=360.0).speed(0.1));
                     ui.label("Vertical FOV");
                 });
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.rt_camera.aperture).range(0.0..=10.0).speed(0.001));
+                    ui.label("Aperture");
+                });
+                ui.add_enabled_ui(self.rt_camera.aperture > 0.0, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut self.rt_camera.focus_distance).range(0.001..=1000.0).speed(0.01),
+                        );
+                        ui.label("Focus distance");
+                        if ui.button("Look at").clicked() {
+                            self.rt_camera.focus_distance =
+                                glm::distance(&self.rt_camera.position, &self.rt_camera.look_at);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut self.rt_camera.blades).range(0..=16).speed(1));
+                        ui.label("Bokeh blades");
+                    });
+                    ui.horizontal(|ui| {
+                        let mut rotation = self.rt_camera.blade_rotation.to_degrees();
+                        if ui
+                            .add(egui::DragValue::new(&mut rotation).range(0.0..=360.0).speed(0.5).suffix("°"))
+                            .changed()
+                        {
+                            self.rt_camera.blade_rotation = rotation.to_radians();
+                        }
+                        ui.label("Bokeh rotation");
+                    });
+                });
+                self.exposure_settings(ui);
+                ui.add_enabled_ui(self.rt_scene.has_motion(), |ui| {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut self.rt_camera.shutter_open).range(0.0..=1.0).speed(0.01));
+                        ui.add(egui::DragValue::new(&mut self.rt_camera.shutter_close).range(0.0..=1.0).speed(0.01));
+                        ui.label("Shutter");
+                    });
+                });
                 ui.horizontal(|ui| {
                     ui.add(egui::DragValue::new(&mut self.rt_camera.max_reflections).range(1..=100).speed(1));
                     ui.label("Max reflections");
@@ -450,18 +1004,75 @@ impl AppState {
                             ui.selectable_value(&mut self.rt_camera.sqrt_samples, choice as u32 + 1, label);
                         }
                     });
+                egui::ComboBox::from_label("Pixel filter")
+                    .selected_text(format!("{:?}", self.rt_camera.filter))
+                    .show_ui(ui, |ui| {
+                        for filter in [
+                            PixelFilter::Box,
+                            PixelFilter::Tent,
+                            PixelFilter::Gaussian,
+                            PixelFilter::BlackmanHarris,
+                            PixelFilter::MitchellNetravali,
+                        ] {
+                            if ui
+                                .selectable_value(&mut self.rt_camera.filter, filter, format!("{:?}", filter))
+                                .clicked()
+                            {
+                                self.rt_camera.filter_radius = filter.default_radius();
+                            }
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.rt_camera.filter_radius).range(0.5..=4.0).speed(0.01));
+                    ui.label("Filter radius");
+                });
             });
 
             if ui.button("Render").clicked() {
-                self.render_scene(ctx);
+                self.start_render();
             }
 
-            if let Some(duration) = self.last_render_time.borrow() {
+            if let Some(error) = self.render_error.borrow() {
+                ui.label(error);
+            } else if let Some(duration) = self.last_render_time.borrow() {
                 ui.label(format!("Render completed in {}s", duration.as_secs_f32()));
             }
+            if let Some(stats) = self.path_stats.borrow() {
+                ui.label(format!(
+                    "Average path length: {:.2} (max {}) over {} paths",
+                    stats.average_length(),
+                    stats.max_length(),
+                    stats.path_count()
+                ));
+            }
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
+            if let Some((tiles_done, tiles_total)) =
+                self.render_job.as_ref().map(|job| (job.tiles_done, job.tiles_total))
+            {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::ProgressBar::new(tiles_done as f32 / tiles_total as f32)
+                            .desired_width(200.0)
+                            .text(format!("Tile {}/{}", tiles_done, tiles_total)),
+                    );
+                    if ui.button("Cancel").clicked() {
+                        self.cancel_render();
+                    }
+                });
+            }
+            if let Some(last_render) = &self.last_render {
+                let previous_layer = self.view_layer;
+                egui::ComboBox::from_label("Layer").selected_text(self.view_layer.label()).show_ui(ui, |ui| {
+                    for layer in last_render.layers() {
+                        ui.selectable_value(&mut self.view_layer, layer, layer.label());
+                    }
+                });
+                if self.view_layer != previous_layer {
+                    self.show_last_render(ctx);
+                }
+            }
             if let Some(texture) = self.target_texture.borrow() {
                 ui.image(texture);
             } else {
@@ -470,76 +1081,427 @@ impl AppState {
         });
     }
 
-    fn render_scene(&mut self, egui_ctx: &egui::Context) {
+    /// Renders every frame of the scene's animation into numbered PNG files in `output_dir`.
+    pub fn render_animation(&mut self, output_dir: &Path) -> anyhow::Result<()> {
+        let animation =
+            self.rt_scene.animation.clone().ok_or_else(|| anyhow::anyhow!("The scene has no animation."))?;
+        std::fs::create_dir_all(output_dir)?;
+
+        // Meshes move between frames, so `render_to_file` uses the BIH, which is refitted, or rebuilt when that isn't
+        // enough.
+        for frame in animation.frames() {
+            animation.apply_to_camera(frame as f32, &mut self.rt_camera);
+            for (mesh_index, transform) in animation.mesh_transforms(frame as f32) {
+                self.update_mesh_transform(mesh_index, transform);
+            }
+
+            let path = output_dir.join(format!("frame_{:04}.png", frame));
+            self.render_to_file(&path)?;
+            println!(
+                "Frame {}/{} rendered in {}s to {}",
+                frame,
+                animation.frame_end,
+                self.last_render_time.unwrap_or_default().as_secs_f32(),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Renders the scene with the current camera into an image file, in a format matching its extension. EXR files get
+    /// every layer of the render in linear values, and other formats only the post-processed colours.
+    pub fn render_to_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.use_bih = true;
+        self.rt_push_constants.flags.set(RtFlags::USE_BIH, true);
+
+        let mut render = self.render_pixels()?;
+        if let Some(crop) = self.clamped_crop_region() {
+            // Nothing outside of the crop region has been traced.
+            render = render.cropped(crop);
+        }
+        match path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("exr")) {
+            true => render.write_exr(path)?,
+            false => render.display_image(RenderLayer::Colour, &self.post_stack).save(path)?,
+        }
+        Ok(())
+    }
+
+    /// Renders tiles of the started render for a while, showing the image once all of them are done.
+    fn continue_render(&mut self, egui_ctx: &egui::Context) {
+        let started = Instant::now();
+        while self.render_job.is_some() && started.elapsed() < GUI_RENDER_TIME_SLICE {
+            match self.render_next_tile() {
+                Ok(true) => self.show_rendered_image(egui_ctx),
+                Ok(false) => (),
+                Err(e) => self.render_error = Some(format!("Render failed: {}", e)),
+            }
+        }
+    }
+
+    fn show_rendered_image(&mut self, egui_ctx: &egui::Context) {
+        let render = self.finish_render();
+        if !render.layers().any(|layer| layer == self.view_layer) {
+            self.view_layer = RenderLayer::Colour;
+        }
+        self.last_render = Some(render);
+        self.show_last_render(egui_ctx);
+    }
+
+    /// Shows the chosen layer of the last render.
+    fn show_last_render(&mut self, egui_ctx: &egui::Context) {
+        let mut image = self.last_render.as_ref().unwrap().display_image(self.view_layer, &self.post_stack);
+        let (width, height) = image.dimensions();
+        if width.max(height) > self.max_image_dimension {
+            // Too large to preview as a texture, but saved at full size.
+            let scale = self.max_image_dimension as f32 / width.max(height) as f32;
+            let (preview_width, preview_height) =
+                (((width as f32 * scale) as u32).max(1), ((height as f32 * scale) as u32).max(1));
+            image = image::imageops::thumbnail(&image, preview_width, preview_height);
+        }
+        let color_image =
+            egui::ColorImage::from_rgba_unmultiplied([image.width() as usize, image.height() as usize], image.as_raw());
+        let image_data = egui::ImageData::Color(Arc::new(color_image));
+        self.target_texture.replace(egui_ctx.load_texture("scene", image_data, TextureOptions::LINEAR));
+    }
+
+    fn exposure_settings(&mut self, ui: &mut egui::Ui) {
+        let camera = &mut self.rt_camera;
+        let modes = [
+            ("None", Exposure::None),
+            ("EV", Exposure::Ev100(camera.ev100().unwrap_or(0.0))),
+            ("ISO, shutter and f-stop", Exposure::Manual {
+                iso:           100.0,
+                shutter_speed: 1.0 / 125.0,
+                f_stop:        camera.f_stop().unwrap_or(16.0),
+            }),
+        ];
+        let same_mode = |a: &Exposure, b: &Exposure| std::mem::discriminant(a) == std::mem::discriminant(b);
+        let selected = modes.iter().find(|(_, mode)| same_mode(mode, &camera.exposure)).map_or("", |(label, _)| *label);
+        egui::ComboBox::from_label("Exposure").selected_text(selected).show_ui(ui, |ui| {
+            for (label, mode) in modes {
+                let is_selected = same_mode(&mode, &camera.exposure);
+                if ui.selectable_label(is_selected, label).clicked() && !is_selected {
+                    camera.exposure = mode;
+                }
+            }
+        });
+
+        let lens_f_stop = camera.f_stop();
+        match &mut camera.exposure {
+            Exposure::None => {}
+            Exposure::Ev100(ev) => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(ev).range(-10.0..=24.0).speed(0.05));
+                    ui.label("EV100");
+                });
+            }
+            Exposure::Manual { iso, shutter_speed, f_stop } => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(iso).range(25.0..=102400.0).speed(10.0));
+                    ui.label("ISO");
+                });
+                ui.horizontal(|ui| {
+                    // Written as a fraction of a second, the way cameras show it.
+                    let mut speed = 1.0 / *shutter_speed;
+                    if ui.add(egui::DragValue::new(&mut speed).range(0.01..=32000.0).speed(1.0).prefix("1/")).changed()
+                    {
+                        *shutter_speed = 1.0 / speed;
+                    }
+                    ui.label("Shutter speed");
+                });
+                // Cameras with a lens share its f-number with the depth of field.
+                let mut new_f_stop = lens_f_stop.unwrap_or(*f_stop);
+                let changed = ui
+                    .horizontal(|ui| {
+                        let changed =
+                            ui.add(egui::DragValue::new(&mut new_f_stop).range(0.5..=64.0).speed(0.1).prefix("f/"));
+                        ui.label("f-stop");
+                        changed.changed()
+                    })
+                    .inner;
+                if changed {
+                    *f_stop = new_f_stop;
+                    if lens_f_stop.is_some() {
+                        camera.set_f_stop(new_f_stop);
+                    }
+                }
+            }
+        }
+        if let Some(ev) = camera.ev100() {
+            ui.label(format!("EV100: {:.2}, multiplier: {:.3e}", ev, camera.exposure_multiplier()));
+        }
+    }
+
+    /// Renders the scene with the current camera and returns every render target's linear values, printing the
+    /// progress to stderr.
+    fn render_pixels(&mut self) -> anyhow::Result<RenderOutput> {
+        self.start_render();
+        while !self.render_next_tile()? {
+            let job = self.render_job.as_ref().unwrap();
+            eprint!("\rTile {}/{}", job.tiles_done, job.tiles_total);
+        }
+        eprintln!();
+        Ok(self.finish_render())
+    }
+
+    /// Splits the image into tiles to be rendered one by one with `render_next_tile`. Images larger than the device
+    /// can hold are first split into regions rendered into separate images.
+    fn start_render(&mut self) {
+        self.render_error = None;
+        let motion_blur = self.rt_scene.has_motion() && self.rt_camera.shutter_close != self.rt_camera.shutter_open;
+        self.rt_push_constants.flags.set(RtFlags::MOTION_BLUR, motion_blur);
+        self.rt_scene_buffers.as_ref().unwrap().reset_path_stats();
+
+        // Adaptive sampling keeps adding samples in further passes until the pixels converge or reach the maximum.
+        let adaptive = self.adaptive && self.renderer_choice == RendererChoice::Pure;
+        self.rt_push_constants.flags.set(RtFlags::ADAPTIVE_SAMPLING, adaptive);
+        self.rt_push_constants.flags.set(RtFlags::AOVS, self.aovs);
+        self.rt_push_constants.flags.set(RtFlags::TRANSPARENT_BACKGROUND, self.transparent);
+        self.rt_push_constants.flags.set(RtFlags::SAMPLE_HEATMAP, adaptive && self.sample_heatmap);
+        let samples_per_pass = self.rt_camera.sqrt_samples * self.rt_camera.sqrt_samples;
+        let passes = match adaptive {
+            true => self.rt_push_constants.max_samples.div_ceil(samples_per_pass).max(1),
+            false => 1,
+        };
+
+        // The deferred renderer's G-buffers hold every sample of a region and of the margin around it which the
+        // filter reaches, and the render targets and adaptive sampling's statistics take up a lot of memory for every
+        // pixel of a region.
+        let region_size = match self.renderer_choice {
+            RendererChoice::Pure => self.max_image_dimension,
+            RendererChoice::Deferred => (self.max_image_dimension / self.rt_camera.sqrt_samples)
+                .saturating_sub(2 * self.rt_camera.filter_margin())
+                .max(1),
+        }
+        .min(if self.aovs { MAX_REGION_SIZE / 2 } else { MAX_REGION_SIZE });
+        let [width, height] = self.rt_camera.img_size;
+        let crop = self.clamped_crop_region();
+        let regions: Vec<_> = tiles::split_into_tiles(vk::Extent2D { width, height }, region_size)
+            .into_iter()
+            .map(rect_to_region)
+            .filter(|region| crop.is_none_or(|crop| region.intersection(&crop).is_some()))
+            .collect();
+
+        let tiles_total =
+            passes * regions.iter().map(|&region| self.region_tiles(region, crop).len() as u32).sum::<u32>();
+
+        // Pixels outside of the crop region are kept from the previous render.
+        let target_count = if self.aovs { RenderTargets::COUNT } else { 3 };
+        let pixels = (0..target_count)
+            .map(|target| match (&self.last_render, crop) {
+                (Some(last_render), Some(_)) if last_render.dimensions() == (width, height) => {
+                    last_render.targets.get(target).map(|image| image.to_vec())
+                }
+                _ => None,
+            })
+            .map(|pixels| pixels.unwrap_or_else(|| vec![0.0; 4 * width as usize * height as usize]))
+            .collect();
+
+        self.render_job = Some(RenderJob {
+            regions: regions.into_iter().rev().collect(),
+            current: None,
+            crop,
+            passes,
+            // The heatmap isn't noise, and gets blurred together with the colours around it.
+            denoise: self.denoise && !self.rt_push_constants.flags.contains(RtFlags::SAMPLE_HEATMAP),
+            pixels,
+            tiles_done: 0,
+            tiles_total,
+            render_time: Duration::ZERO,
+        });
+    }
+
+    /// Renders the next tile of the started render, and returns whether it was the last one.
+    ///
+    /// A submission that fails or times out abandons the render, destroying the images of the region being rendered.
+    fn render_next_tile(&mut self) -> Result<bool, vk::Result> {
         let vk_ctx = self.vulkan_context();
-        let extent = self.rt_camera.image_extent_3d();
-        let target_image = {
-            let image_info = vk::ImageCreateInfoBuilder::new()
-                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
-                .format(vk::Format::R8G8B8A8_UNORM)
-                .extent(extent)
-                .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlagBits::_1)
-                .image_type(vk::ImageType::_2D);
-
-            let range = vk::ImageSubresourceRangeBuilder::new()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .base_mip_level(0)
-                .base_array_layer(0)
-                .level_count(1)
-                .layer_count(1)
-                .build();
-
-            AllocatedImage::new(vk_ctx.clone(), image_info, None, vk::ImageViewType::_2D, range)
+        let mut job = self.render_job.take().unwrap();
+        let mut current = match job.current.take() {
+            Some(current) => current,
+            None => self.begin_region(job.regions.pop().unwrap(), job.crop, job.passes)?,
         };
 
-        self.rt_camera_buffer.as_mut().unwrap().set_data(&[self.rt_camera.into_uniform()]);
+        if let Err(e) = self.render_tile(&mut job, &mut current) {
+            current.targets.destroy(&vk_ctx.device);
+            return Err(e);
+        }
+
+        if current.tiles.is_empty() {
+            let region = current.region;
+            let traced = job.crop.and_then(|crop| crop.intersection(&region)).unwrap_or(region);
+            let region_pixels = self.finish_region(&current, traced, job.denoise);
+            current.targets.destroy(&vk_ctx.device);
+            let region_pixels = region_pixels?;
+
+            let [width, _] = self.rt_camera.img_size;
+            let row_length = 4 * traced.width as usize;
+            for (pixels, region_pixels) in job.pixels.iter_mut().zip(region_pixels) {
+                for y in traced.y..traced.y + traced.height {
+                    let source = 4 * ((y - region.y) as usize * region.width as usize + (traced.x - region.x) as usize);
+                    let destination = 4 * (y as usize * width as usize + traced.x as usize);
+                    pixels[destination..destination + row_length]
+                        .copy_from_slice(&region_pixels[source..source + row_length]);
+                }
+            }
+        } else {
+            job.current = Some(current);
+        }
+
+        let finished = job.current.is_none() && job.regions.is_empty();
+        self.render_job = Some(job);
+        Ok(finished)
+    }
+
+    /// Renders the next of the region's tiles.
+    fn render_tile(&mut self, job: &mut RenderJob, current: &mut RegionRender) -> Result<(), vk::Result> {
+        let vk_ctx = self.vulkan_context();
+        let (tile, pass) = current.tiles.pop().unwrap();
+        self.rt_push_constants.sample_pass = pass;
+        let now = Instant::now();
         match self.renderer_choice {
-            RendererChoice::Pure => {
-                self.pure_ray_tracer.as_mut().unwrap().set_output_extent(self.rt_camera.image_extent_2d());
+            RendererChoice::Pure => self.pure_ray_tracer.as_ref().unwrap().render_tile(
+                vk_ctx,
+                &self.rt_push_constants,
+                &current.targets,
+                tile,
+            )?,
+            RendererChoice::Deferred => self.deferred_ray_tracer.as_mut().unwrap().render_tile(
+                vk_ctx,
+                &self.rt_push_constants,
+                &current.targets,
+                tile,
+            )?,
+        }
+        job.render_time += now.elapsed();
+        job.tiles_done += 1;
+        Ok(())
+    }
+
+    /// Denoises the region once all of its tiles have been rendered, and reads back its render targets.
+    fn finish_region(
+        &self,
+        current: &RegionRender,
+        traced: ImageRegion,
+        denoise: bool,
+    ) -> Result<Vec<Vec<f32>>, vk::Result> {
+        let vk_ctx = self.vulkan_context();
+        let region = current.region;
+        tiles::finish_tiled_render(vk_ctx.clone(), &current.targets)?;
+        if denoise {
+            // Pixels outside of the crop region are black, and mustn't be blurred into the traced ones.
+            let area = vk::Rect2D {
+                offset: vk::Offset2D { x: (traced.x - region.x) as i32, y: (traced.y - region.y) as i32 },
+                extent: vk::Extent2D { width: traced.width, height: traced.height },
+            };
+            let denoiser = self.denoiser.as_ref().unwrap();
+            denoiser.denoise(vk_ctx, &current.targets, area, &self.denoiser_settings)?;
+        }
+        self.read_targets(&current.targets)
+    }
+
+    /// Creates the image a region is rendered into, and sets up the renderer for it.
+    fn begin_region(
+        &mut self,
+        region: ImageRegion,
+        crop: Option<ImageRegion>,
+        passes: u32,
+    ) -> Result<RegionRender, vk::Result> {
+        let vk_ctx = self.vulkan_context();
+        let camera = Camera { region: Some(region), ..self.rt_camera };
+
+        let region_crop = crop.and_then(|crop| crop.intersection(&region));
+        self.rt_push_constants.flags.set(RtFlags::CROP, region_crop.is_some());
+        if let Some(crop) = region_crop {
+            self.rt_push_constants.crop_region = [crop.x - region.x, crop.y - region.y, crop.width, crop.height];
+        }
 
-                let now = Instant::now();
-                self.pure_ray_tracer.as_mut().unwrap().render(vk_ctx.clone(), &self.rt_push_constants, &target_image);
-                self.last_render_time = Some(now.elapsed());
+        self.rt_camera_buffer.as_mut().unwrap().set_data(&[camera.into_uniform()]);
+        match self.renderer_choice {
+            RendererChoice::Pure => {
+                let pure_ray_tracer = self.pure_ray_tracer.as_mut().unwrap();
+                pure_ray_tracer.set_output_extent(camera.image_extent_2d());
+                if self.rt_push_constants.flags.contains(RtFlags::ADAPTIVE_SAMPLING) {
+                    pure_ray_tracer.reserve_pixel_stats(vk_ctx.clone(), self.aovs);
+                }
             }
             RendererChoice::Deferred => {
-                self.deferred_ray_tracer.as_mut().unwrap().update_output(vk_ctx.clone(), self.rt_camera);
-
-                let now = Instant::now();
-                self.deferred_ray_tracer.as_mut().unwrap().render(
-                    vk_ctx.clone(),
-                    &self.rt_push_constants,
-                    &target_image,
-                );
-                self.last_render_time = Some(now.elapsed());
+                let deferred_ray_tracer = self.deferred_ray_tracer.as_mut().unwrap();
+                deferred_ray_tracer.update_output(vk_ctx.clone(), camera);
+                deferred_ray_tracer.render_gbuffers(vk_ctx.clone())?;
             }
         }
+        let targets = RenderTargets::new(vk_ctx.clone(), camera.image_extent_2d(), self.aovs);
+        if let Err(e) = tiles::begin_tiled_render(vk_ctx.clone(), &targets) {
+            targets.destroy(&vk_ctx.device);
+            return Err(e);
+        }
+
+        let region_tiles = self.region_tiles(region, crop);
+        let mut tiles: Vec<_> =
+            (0..passes).flat_map(|pass| region_tiles.iter().map(move |&tile| (tile, pass))).collect();
+        tiles.reverse();
+        Ok(RegionRender { region, targets, tiles })
+    }
+
+    /// Tiles of the image a region is rendered into, leaving out those with nothing to trace.
+    fn region_tiles(&self, region: ImageRegion, crop: Option<ImageRegion>) -> Vec<vk::Rect2D> {
+        let extent = vk::Extent2D { width: region.width, height: region.height };
+        let mut tiles = tiles::split_into_tiles(extent, self.tile_size);
+        if let Some(crop) = crop {
+            tiles.retain(|&tile| {
+                let tile = rect_to_region(tile);
+                let tile = ImageRegion { x: region.x + tile.x, y: region.y + tile.y, ..tile };
+                tile.intersection(&crop).is_some()
+            });
+        }
+        tiles
+    }
+
+    /// Crop region limited to the image, or `None` if it doesn't overlap it and so the whole image is rendered.
+    fn clamped_crop_region(&self) -> Option<ImageRegion> {
+        let [width, height] = self.rt_camera.img_size;
+        self.crop_region.and_then(|crop| crop.intersection(&ImageRegion { x: 0, y: 0, width, height }))
+    }
 
+    /// Linear RGBA values of each of the render targets over the rendered region.
+    fn read_targets(&self, targets: &RenderTargets) -> Result<Vec<Vec<f32>>, vk::Result> {
+        targets.images().into_iter().map(|target| self.read_target(target)).collect()
+    }
+
+    fn read_target(&self, target: &AllocatedImage) -> Result<Vec<f32>, vk::Result> {
+        let vk_ctx = self.vulkan_context();
         let image_data_buffer = {
             let buffer_info = vk::BufferCreateInfoBuilder::new()
                 .usage(vk::BufferUsageFlags::TRANSFER_DST)
-                .size((4 * extent.width * extent.height) as vk::DeviceSize)
+                .size(16 * target.extent.width as vk::DeviceSize * target.extent.height as vk::DeviceSize)
                 .sharing_mode(vk::SharingMode::EXCLUSIVE);
-            AllocatedBuffer::<u8>::new(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferHost)
+            AllocatedBuffer::<f32>::new(vk_ctx.allocator.clone(), &buffer_info, vma::MemoryUsage::AutoPreferHost)
         };
 
-        target_image.copy_to_buffer(vk_ctx.clone(), &image_data_buffer);
-
-        let image_data = {
-            let data = unsafe {
-                std::slice::from_raw_parts(image_data_buffer.memory_ptr(), (4 * extent.width * extent.height) as usize)
-            };
-            let color_image =
-                egui::ColorImage::from_rgba_unmultiplied(self.rt_camera.img_size.map(|d| d as usize), data);
-            image_data_buffer.allocator.read().unwrap().unmap_memory(&image_data_buffer.allocation);
-            egui::ImageData::Color(Arc::new(color_image))
-        };
+        let pixels = target.copy_to_buffer(vk_ctx, &image_data_buffer).map(|()| image_data_buffer.read_data());
+        image_data_buffer.destroy();
+        pixels
+    }
 
-        self.target_texture.replace(egui_ctx.load_texture("scene", image_data, TextureOptions::LINEAR));
+    /// Takes the pixels of the finished render and records its statistics.
+    fn finish_render(&mut self) -> RenderOutput {
+        let job = self.render_job.take().unwrap();
+        self.last_render_time = Some(job.render_time);
+        self.path_stats = Some(self.rt_scene_buffers.as_ref().unwrap().path_stats());
+        let [width, height] = self.rt_camera.img_size;
+        let targets = job
+            .pixels
+            .into_iter()
+            .map(|pixels| image::Rgba32FImage::from_raw(width, height, pixels).unwrap())
+            .collect();
+        RenderOutput { targets }
+    }
 
-        image_data_buffer.destroy();
-        target_image.destroy(&vk_ctx.device);
+    fn cancel_render(&mut self) {
+        if let Some(RenderJob { current: Some(current), .. }) = self.render_job.take() {
+            current.targets.destroy(self.device.as_ref().unwrap());
+        }
     }
 
     pub fn render(&mut self, textures_delta: &TexturesDelta, clipped_meshes: Vec<ClippedPrimitive>) {
@@ -693,8 +1655,10 @@ impl AppState {
 impl Drop for AppState {
     fn drop(&mut self) {
         unsafe {
+            self.device.as_ref().unwrap().device_wait_idle().expect("Cannot wait idle");
+            self.cancel_render();
+
             let device = self.device.as_ref().unwrap();
-            device.device_wait_idle().expect("Cannot wait idle");
 
             for &image_view in self.swapchain_image_views.iter() {
                 device.destroy_image_view(image_view, None);
@@ -714,6 +1678,9 @@ impl Drop for AppState {
             drt_ref.destroy(device);
             self.deferred_ray_tracer = None;
 
+            self.denoiser.as_ref().unwrap().destroy(device);
+            self.denoiser = None;
+
             self.rt_scene_buffers.as_ref().unwrap().destroy(device);
             self.rt_scene_buffers = None;
 
@@ -1,8 +1,14 @@
-use eruptrace_rs::{App, EruptraceArgs};
+use eruptrace_rs::{run_headless, App, EruptraceArgs};
 use winit::event_loop::{ControlFlow, EventLoop};
 
 fn main() {
     match EruptraceArgs::parse_args() {
+        Ok(args) if args.is_headless() => {
+            if let Err(e) = run_headless(&args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
         Ok(args) => {
             let event_loop = EventLoop::new().unwrap();
             event_loop.set_control_flow(ControlFlow::Poll);
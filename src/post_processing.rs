@@ -0,0 +1,528 @@
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::{anyhow, bail, Context};
+use image::Rgba32FImage;
+use itertools::Itertools;
+use serde_json as json;
+
+const LUMINANCE_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Middle grey, which contrast changes leave as it is.
+const MIDDLE_GREY: f32 = 0.18;
+
+/// Colour temperature at which white balance leaves the colours as they are, in kelvins.
+const NEUTRAL_TEMPERATURE: f32 = 6500.0;
+
+/// Effects applied one after another to the linear colours of a finished render, before they are encoded to be shown
+/// and saved. They are kept in the `post_processing` array of the scene's camera.json.
+#[derive(Clone, Debug, Default)]
+pub struct PostStack {
+    pub stages: Vec<PostStage>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PostStage {
+    pub enabled: bool,
+    pub effect:  PostEffect,
+}
+
+#[derive(Clone, Debug)]
+pub enum PostEffect {
+    /// Light of the pixels brighter than `threshold` spread over their surroundings, as if scattered inside the lens.
+    /// The radius is a fraction of the image's height.
+    Bloom {
+        threshold: f32,
+        intensity: f32,
+        radius:    f32,
+    },
+    /// Darkens the image towards its corners.
+    Vignette {
+        strength: f32,
+    },
+    /// Red and blue magnified differently by the lens, by a fraction of the distance from the image's centre.
+    ChromaticAberration {
+        strength: f32,
+    },
+    /// Makes light of the given colour temperature, in kelvins, white. Positive tints go towards magenta and negative
+    /// ones towards green.
+    WhiteBalance {
+        temperature: f32,
+        tint:        f32,
+    },
+    Saturation {
+        saturation: f32,
+    },
+    /// Exponent of the colours relative to middle grey.
+    Contrast {
+        contrast: f32,
+    },
+    /// 3D lookup table read from a .cube file, relative to the scene's directory. It is applied to the colours
+    /// encoded with the same gamma of 2 as the shown images, which is what most such tables are made for.
+    Lut {
+        path: String,
+        lut:  Option<Arc<CubeLut>>,
+    },
+    /// Stage of a type which doesn't exist, kept as it was written so that it's saved back unchanged.
+    Unknown {
+        object: json::Value,
+    },
+}
+
+/// Table mapping each colour within the domain to another, sampled with trilinear interpolation.
+#[derive(Debug)]
+pub struct CubeLut {
+    size:       usize,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    /// Entries with red changing the fastest and blue the slowest.
+    table:      Vec<[f32; 3]>,
+}
+
+impl PostStack {
+    /// Reads the stack from camera.json in the scene's directory, which leaves it empty if it doesn't have one.
+    /// Stages which can't be read are kept disabled, printing why, so that saving the stack doesn't lose them.
+    pub fn load(scene_path: &Path) -> anyhow::Result<Self> {
+        let camera_json: json::Value = json::from_str(&fs::read_to_string(scene_path.join("camera.json"))?)?;
+        let stages = camera_json["post_processing"]
+            .as_array()
+            .map_or(&vec![], |v| v)
+            .iter()
+            .map(|stage| PostStage::from_json(scene_path, stage))
+            .collect();
+        Ok(Self { stages })
+    }
+
+    /// Writes the stack into camera.json in the scene's directory, keeping the rest of the file.
+    pub fn save(&self, scene_path: &Path) -> anyhow::Result<()> {
+        let camera_path = scene_path.join("camera.json");
+        let mut camera_json: json::Value = json::from_str(&fs::read_to_string(&camera_path)?)?;
+        camera_json["post_processing"] = self.stages.iter().map(PostStage::to_json).collect();
+        fs::write(camera_path, json::to_string_pretty(&camera_json)?)?;
+        Ok(())
+    }
+
+    pub fn apply(&self, image: &mut Rgba32FImage) {
+        for stage in self.stages.iter().filter(|stage| stage.enabled) {
+            stage.effect.apply(image);
+        }
+    }
+}
+
+impl PostStage {
+    pub fn new(effect: PostEffect) -> Self {
+        Self { enabled: true, effect }
+    }
+
+    fn from_json(scene_path: &Path, object: &json::Value) -> Self {
+        let mut enabled = object["enabled"].as_bool().unwrap_or(true);
+        let kind = object["type"].as_str().unwrap_or_default();
+        let Some(mut effect) = PostEffect::defaults().into_iter().find(|effect| effect.type_name() == kind) else {
+            eprintln!("Invalid post-processing effect '{kind}'.");
+            return Self { enabled: false, effect: PostEffect::Unknown { object: object.clone() } };
+        };
+        let read = |key: &str, value: &mut f32| {
+            if let Some(v) = object[key].as_f64() {
+                *value = v as f32;
+            }
+        };
+        match &mut effect {
+            PostEffect::Bloom { threshold, intensity, radius } => {
+                read("threshold", threshold);
+                read("intensity", intensity);
+                read("radius", radius);
+            }
+            PostEffect::Vignette { strength } | PostEffect::ChromaticAberration { strength } => {
+                read("strength", strength);
+            }
+            PostEffect::WhiteBalance { temperature, tint } => {
+                read("temperature", temperature);
+                read("tint", tint);
+            }
+            PostEffect::Saturation { saturation } => read("saturation", saturation),
+            PostEffect::Contrast { contrast } => read("contrast", contrast),
+            PostEffect::Lut { path, lut } => {
+                *path = object["path"].as_str().unwrap_or_default().to_owned();
+                // A stage added without choosing a file yet.
+                if !path.is_empty() {
+                    match CubeLut::load(&scene_path.join(&*path)) {
+                        Ok(loaded) => *lut = Some(Arc::new(loaded)),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            enabled = false;
+                        }
+                    }
+                }
+            }
+            PostEffect::Unknown { .. } => unreachable!("Unknown effects aren't among the defaults"),
+        }
+        Self { enabled, effect }
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut object = match &self.effect {
+            PostEffect::Unknown { object } => {
+                let mut object = object.clone();
+                if object.is_object() {
+                    object["enabled"] = self.enabled.into();
+                }
+                return object;
+            }
+            PostEffect::Bloom { threshold, intensity, radius } => {
+                json::json!({ "threshold": threshold, "intensity": intensity, "radius": radius })
+            }
+            PostEffect::Vignette { strength } | PostEffect::ChromaticAberration { strength } => {
+                json::json!({ "strength": strength })
+            }
+            PostEffect::WhiteBalance { temperature, tint } => json::json!({ "temperature": temperature, "tint": tint }),
+            PostEffect::Saturation { saturation } => json::json!({ "saturation": saturation }),
+            PostEffect::Contrast { contrast } => json::json!({ "contrast": contrast }),
+            PostEffect::Lut { path, .. } => json::json!({ "path": path }),
+        };
+        object["type"] = self.effect.type_name().into();
+        object["enabled"] = self.enabled.into();
+        object
+    }
+}
+
+impl PostEffect {
+    /// One of each effect, with settings leaving the image as it is or changing it only slightly.
+    pub fn defaults() -> [Self; 7] {
+        [
+            Self::Bloom { threshold: 1.0, intensity: 0.1, radius: 0.02 },
+            Self::Vignette { strength: 0.3 },
+            Self::ChromaticAberration { strength: 0.002 },
+            Self::WhiteBalance { temperature: NEUTRAL_TEMPERATURE, tint: 0.0 },
+            Self::Saturation { saturation: 1.0 },
+            Self::Contrast { contrast: 1.0 },
+            Self::Lut { path: String::new(), lut: None },
+        ]
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Bloom { .. } => "Bloom",
+            Self::Vignette { .. } => "Vignette",
+            Self::ChromaticAberration { .. } => "Chromatic aberration",
+            Self::WhiteBalance { .. } => "White balance",
+            Self::Saturation { .. } => "Saturation",
+            Self::Contrast { .. } => "Contrast",
+            Self::Lut { .. } => "LUT",
+            Self::Unknown { .. } => "Unknown effect",
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Bloom { .. } => "bloom",
+            Self::Vignette { .. } => "vignette",
+            Self::ChromaticAberration { .. } => "chromatic_aberration",
+            Self::WhiteBalance { .. } => "white_balance",
+            Self::Saturation { .. } => "saturation",
+            Self::Contrast { .. } => "contrast",
+            Self::Lut { .. } => "lut",
+            // Never one of the defaults' types, so it's not read back as one of them.
+            Self::Unknown { .. } => "",
+        }
+    }
+
+    fn apply(&self, image: &mut Rgba32FImage) {
+        match *self {
+            Self::Bloom { threshold, intensity, radius } => bloom(image, threshold, intensity, radius),
+            Self::Vignette { strength } => vignette(image, strength),
+            Self::ChromaticAberration { strength } => chromatic_aberration(image, strength),
+            Self::WhiteBalance { temperature, tint } => {
+                let [r, g, b] = white_balance_factors(temperature, tint);
+                map_colours(image, |[red, green, blue]| [r * red, g * green, b * blue]);
+            }
+            Self::Saturation { saturation } => map_colours(image, |rgb| {
+                let luminance = luminance(rgb);
+                rgb.map(|c| luminance + saturation * (c - luminance))
+            }),
+            Self::Contrast { contrast } => {
+                map_colours(image, |rgb| rgb.map(|c| MIDDLE_GREY * (c.max(0.0) / MIDDLE_GREY).powf(contrast)))
+            }
+            Self::Lut { lut: Some(ref lut), .. } => map_colours(image, |rgb| {
+                let encoded = rgb.map(|c| c.max(0.0).sqrt());
+                lut.sample(encoded).map(|c| c * c)
+            }),
+            Self::Lut { lut: None, .. } | Self::Unknown { .. } => {}
+        }
+    }
+}
+
+impl CubeLut {
+    /// Reads a table in Adobe's .cube format. Only 3D tables are supported.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents, path)
+    }
+
+    /// Reads the contents of the .cube file at `path`, which is only used in errors.
+    fn parse(contents: &str, path: &Path) -> anyhow::Result<Self> {
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let parse_rgb = |words: std::str::SplitWhitespace| -> anyhow::Result<[f32; 3]> {
+                let values: Vec<f32> = words.map(str::parse).try_collect()?;
+                values.try_into().map_err(|_| anyhow!("Expected three values in '{line}'."))
+            };
+            match keyword {
+                "TITLE" => {}
+                "LUT_3D_INPUT_RANGE" => {
+                    let range: Vec<f32> = words.map(str::parse).try_collect()?;
+                    let [min, max] = range[..] else { bail!("Expected two values in '{line}'.") };
+                    (domain_min, domain_max) = ([min; 3], [max; 3]);
+                }
+                "LUT_3D_SIZE" => size = Some(words.next().unwrap_or_default().parse::<usize>()?),
+                "LUT_1D_SIZE" => bail!("1D LUTs are not supported."),
+                "DOMAIN_MIN" => domain_min = parse_rgb(words)?,
+                "DOMAIN_MAX" => domain_max = parse_rgb(words)?,
+                _ => table.push(parse_rgb(line.split_whitespace())?),
+            }
+        }
+        let size = size.ok_or_else(|| anyhow!("{} doesn't have a LUT_3D_SIZE.", path.display()))?;
+        if size < 2 || table.len() != size * size * size {
+            bail!("{} has {} entries, expected {}.", path.display(), table.len(), size * size * size);
+        }
+        Ok(Self { size, domain_min, domain_max, table })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn sample(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let last = (self.size - 1) as f32;
+        let coords: [f32; 3] = std::array::from_fn(|c| {
+            let t = (rgb[c] - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+            t.clamp(0.0, 1.0) * last
+        });
+        let lower = coords.map(|c| (c.floor() as usize).min(self.size - 2));
+        let fraction: [f32; 3] = std::array::from_fn(|c| coords[c] - lower[c] as f32);
+        let entry =
+            |[r, g, b]: [usize; 3]| self.table[((lower[2] + b) * self.size + lower[1] + g) * self.size + lower[0] + r];
+
+        // Weighted sum of the eight entries around the colour.
+        let mut result = [0.0; 3];
+        for corner in 0..8 {
+            let offsets = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let weight: f32 = (0..3).map(|c| if offsets[c] == 1 { fraction[c] } else { 1.0 - fraction[c] }).product();
+            for (r, value) in result.iter_mut().zip(entry(offsets)) {
+                *r += weight * value;
+            }
+        }
+        result
+    }
+}
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+    (0..3).map(|c| LUMINANCE_WEIGHTS[c] * rgb[c]).sum()
+}
+
+/// Maps the colours with their alpha divided out, as the effects would otherwise treat the edges of transparent
+/// backgrounds as darker colours.
+fn map_colours(image: &mut Rgba32FImage, f: impl Fn([f32; 3]) -> [f32; 3]) {
+    for pixel in image.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        if a <= 0.0 {
+            continue;
+        }
+        let [r, g, b] = f([r / a, g / a, b / a]);
+        pixel.0 = [r * a, g * a, b * a, a];
+    }
+}
+
+fn bloom(image: &mut Rgba32FImage, threshold: f32, intensity: f32, radius: f32) {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut glow = image
+        .pixels()
+        .map(|pixel| {
+            let rgb = [pixel.0[0], pixel.0[1], pixel.0[2]];
+            let luminance = luminance(rgb);
+            match luminance > threshold {
+                true => rgb.map(|c| c * (luminance - threshold) / luminance),
+                false => [0.0; 3],
+            }
+        })
+        .collect_vec();
+
+    // Three box blurs add up to a close approximation of a Gaussian blur, whatever its radius.
+    let sigma = radius * height as f32;
+    let box_radius = (((12.0 * sigma * sigma / 3.0 + 1.0).sqrt() - 1.0) / 2.0).round() as usize;
+    if box_radius > 0 {
+        for _ in 0..3 {
+            box_blur(&mut glow, width, height, box_radius, 1, width);
+            box_blur(&mut glow, height, width, box_radius, width, 1);
+        }
+    }
+
+    for (pixel, glow) in image.pixels_mut().zip(glow) {
+        for (channel, glow) in pixel.0.iter_mut().zip(glow) {
+            *channel += intensity * glow;
+        }
+    }
+}
+
+/// Averages the values within `radius` along each of `lines` lines of `length` values, which are `step` apart within
+/// a line and whose first values are `line_step` apart.
+fn box_blur(values: &mut [[f32; 3]], length: usize, lines: usize, radius: usize, step: usize, line_step: usize) {
+    let mut line = vec![[0.0; 3]; length];
+    for l in 0..lines {
+        for (i, value) in line.iter_mut().enumerate() {
+            *value = values[l * line_step + i * step];
+        }
+        // Running sum of the window, which is cut short at the ends of the line.
+        let mut sum = [0.0; 3];
+        for value in line.iter().take(radius) {
+            (0..3).for_each(|c| sum[c] += value[c]);
+        }
+        for i in 0..length {
+            if i + radius < length {
+                (0..3).for_each(|c| sum[c] += line[i + radius][c]);
+            }
+            if i > radius {
+                (0..3).for_each(|c| sum[c] -= line[i - radius - 1][c]);
+            }
+            let count = ((i + radius).min(length - 1) - i.saturating_sub(radius) + 1) as f32;
+            values[l * line_step + i * step] = sum.map(|s| s / count);
+        }
+    }
+}
+
+fn vignette(image: &mut Rgba32FImage, strength: f32) {
+    let centre = [image.width() as f32 * 0.5, image.height() as f32 * 0.5];
+    let half_diagonal_squared = centre[0] * centre[0] + centre[1] * centre[1];
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (dx, dy) = (x as f32 + 0.5 - centre[0], y as f32 + 0.5 - centre[1]);
+        let factor = (1.0 - strength * (dx * dx + dy * dy) / half_diagonal_squared).max(0.0);
+        for channel in &mut pixel.0[..3] {
+            *channel *= factor;
+        }
+    }
+}
+
+fn chromatic_aberration(image: &mut Rgba32FImage, strength: f32) {
+    let source = image.clone();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let centre = [width * 0.5, height * 0.5];
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let offset = [x as f32 + 0.5 - centre[0], y as f32 + 0.5 - centre[1]];
+        // Red is magnified more than green, and blue less.
+        for (channel, scale) in [(0, 1.0 - strength), (2, 1.0 + strength)] {
+            let (sx, sy) = (centre[0] + offset[0] * scale - 0.5, centre[1] + offset[1] * scale - 0.5);
+            pixel.0[channel] = sample_bilinear(&source, sx, sy, channel);
+        }
+    }
+}
+
+fn sample_bilinear(image: &Rgba32FImage, x: f32, y: f32, channel: usize) -> f32 {
+    let (max_x, max_y) = (image.width() as f32 - 1.0, image.height() as f32 - 1.0);
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+    let (x0, y0) = (x.floor(), y.floor());
+    let (x1, y1) = ((x0 + 1.0).min(max_x), (y0 + 1.0).min(max_y));
+    let (fx, fy) = (x - x0, y - y0);
+    let value = |x: f32, y: f32| image.get_pixel(x as u32, y as u32).0[channel];
+    let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+    let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Factors of the colour channels making light of the given colour temperature as white as light of the neutral
+/// temperature, while keeping its luminance.
+fn white_balance_factors(temperature: f32, tint: f32) -> [f32; 3] {
+    let neutral = blackbody_colour(NEUTRAL_TEMPERATURE);
+    let light = blackbody_colour(temperature);
+    let mut factors: [f32; 3] = std::array::from_fn(|c| neutral[c] / light[c]);
+    factors[1] *= 1.0 - 0.25 * tint;
+    let luminance = luminance(factors);
+    factors.map(|f| f / luminance)
+}
+
+/// Linear sRGB colour of a black body at the given temperature, using Kim et al.'s approximation of the Planckian
+/// locus, which holds between 1667 K and 25000 K.
+fn blackbody_colour(temperature: f32) -> [f32; 3] {
+    let t = temperature.clamp(1667.0, 25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+    let x = match t <= 4000.0 {
+        true => -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910,
+        false => -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390,
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+    let (big_x, big_z) = (x / y, (1.0 - x - y) / y);
+    [
+        3.2404542 * big_x - 1.5371385 - 0.4985314 * big_z,
+        -0.9692660 * big_x + 1.8760108 + 0.0415560 * big_z,
+        0.0556434 * big_x - 0.2040259 + 1.0572252 * big_z,
+    ]
+    .map(|c| c.max(1e-4) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Table of the given size mapping each colour to itself.
+    fn identity_cube(size: usize) -> String {
+        let mut contents = format!("TITLE \"Identity\"\nLUT_3D_SIZE {size}\n");
+        let last = (size - 1) as f32;
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    contents += &format!("{} {} {}\n", r as f32 / last, g as f32 / last, b as f32 / last);
+                }
+            }
+        }
+        contents
+    }
+
+    #[test]
+    fn identity_lut_keeps_colours() {
+        let lut = CubeLut::parse(&identity_cube(5), Path::new("identity.cube")).unwrap();
+        assert_eq!(lut.size(), 5);
+        for rgb in [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.1, 0.5, 0.9], [0.33, 0.67, 0.2]] {
+            let sampled = lut.sample(rgb);
+            for c in 0..3 {
+                assert!((sampled[c] - rgb[c]).abs() < 1e-5, "{rgb:?} is mapped to {sampled:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn one_dimensional_lut_is_rejected() {
+        let contents = "LUT_1D_SIZE 2\n0 0 0\n1 1 1\n";
+        assert!(CubeLut::parse(contents, Path::new("1d.cube")).is_err());
+    }
+
+    #[test]
+    fn wrong_entry_count_is_rejected() {
+        let mut contents = identity_cube(3);
+        assert!(CubeLut::parse(&(contents.clone() + "0 0 0\n"), Path::new("long.cube")).is_err());
+        contents.truncate(contents.trim_end().rfind('\n').unwrap() + 1);
+        assert!(CubeLut::parse(&contents, Path::new("short.cube")).is_err());
+    }
+
+    #[test]
+    fn box_blur_keeps_constant_signal() {
+        let (width, height) = (16, 9);
+        let colour = [0.5, 0.25, 2.0];
+        let mut values = vec![colour; width * height];
+        box_blur(&mut values, width, height, 3, 1, width);
+        box_blur(&mut values, height, width, 3, width, 1);
+        for value in values {
+            for c in 0..3 {
+                assert!((value[c] - colour[c]).abs() < 1e-5, "{value:?} differs from {colour:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::path::Path;
+
+use eruptrace_scene::ImageRegion;
+use exr::prelude::{
+    AnyChannel,
+    AnyChannels,
+    Encoding,
+    FlatSamples,
+    Image,
+    ImageAttributes,
+    IntegerBounds,
+    Layer,
+    LayerAttributes,
+    WritableImage,
+};
+use itertools::Itertools;
+
+use crate::post_processing::PostStack;
+
+/// Part of a render which can be viewed on its own, and which makes up a layer of EXR files.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RenderLayer {
+    Colour,
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    MaterialIndex,
+    ObjectId,
+    Direct,
+    Indirect,
+    Emission,
+}
+
+impl RenderLayer {
+    pub const ALL: [Self; 10] = [
+        Self::Colour,
+        Self::Albedo,
+        Self::Normal,
+        Self::Depth,
+        Self::Position,
+        Self::MaterialIndex,
+        Self::ObjectId,
+        Self::Direct,
+        Self::Indirect,
+        Self::Emission,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Colour => "Colour",
+            Self::Albedo => "Albedo",
+            Self::Normal => "Normal",
+            Self::Depth => "Depth",
+            Self::Position => "Position",
+            Self::MaterialIndex => "Material index",
+            Self::ObjectId => "Object ID",
+            Self::Direct => "Direct light",
+            Self::Indirect => "Indirect light",
+            Self::Emission => "Emission",
+        }
+    }
+
+    fn exr_name(self) -> &'static str {
+        match self {
+            Self::Colour => "colour",
+            Self::Albedo => "albedo",
+            Self::Normal => "normal",
+            Self::Depth => "depth",
+            Self::Position => "position",
+            Self::MaterialIndex => "material_index",
+            Self::ObjectId => "object_id",
+            Self::Direct => "direct",
+            Self::Indirect => "indirect",
+            Self::Emission => "emission",
+        }
+    }
+
+    /// Index of the render target holding the layer, as laid out in `eruptrace_vk::RenderTargets::slots`.
+    fn target(self) -> usize {
+        match self {
+            Self::Colour => 0,
+            Self::Albedo => 1,
+            Self::Normal | Self::Depth => 2,
+            Self::Position => 3,
+            Self::MaterialIndex | Self::ObjectId => 4,
+            Self::Direct => 5,
+            Self::Indirect => 6,
+            Self::Emission => 7,
+        }
+    }
+
+    /// Components of the render target making up the layer, along with the names of their channels.
+    fn channels(self) -> &'static [(usize, &'static str)] {
+        match self {
+            Self::Colour => &[(0, "R"), (1, "G"), (2, "B"), (3, "A")],
+            Self::Albedo | Self::Direct | Self::Indirect | Self::Emission => &[(0, "R"), (1, "G"), (2, "B")],
+            Self::Normal | Self::Position => &[(0, "X"), (1, "Y"), (2, "Z")],
+            Self::Depth => &[(3, "Z")],
+            Self::MaterialIndex => &[(0, "ID")],
+            Self::ObjectId => &[(1, "ID")],
+        }
+    }
+}
+
+/// Linear values of every render target of a finished render.
+#[derive(Clone)]
+pub struct RenderOutput {
+    /// Images of the render targets in the order of the shaders' outputs, leaving out the AOVs if they haven't been
+    /// rendered.
+    pub targets: Vec<image::Rgba32FImage>,
+}
+
+impl RenderOutput {
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.targets[0].dimensions()
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = RenderLayer> + '_ {
+        RenderLayer::ALL.into_iter().filter(|layer| layer.target() < self.targets.len())
+    }
+
+    pub fn cropped(&self, region: ImageRegion) -> Self {
+        let targets = self
+            .targets
+            .iter()
+            .map(|target| image::imageops::crop_imm(target, region.x, region.y, region.width, region.height).to_image())
+            .collect();
+        Self { targets }
+    }
+
+    /// Converts the layer into 8-bit colours to be shown and saved, with the colours post-processed and encoded with a
+    /// gamma of 2, and the other layers mapped to a visible range.
+    pub fn display_image(&self, layer: RenderLayer, post_stack: &PostStack) -> image::RgbaImage {
+        let mut post_processed = None;
+        if layer == RenderLayer::Colour {
+            let mut colour = self.targets[0].clone();
+            post_stack.apply(&mut colour);
+            post_processed = Some(colour);
+        }
+        let target = post_processed.as_ref().unwrap_or(&self.targets[layer.target()]);
+        let unorm = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let encode = |value: f32| unorm(value.max(0.0).sqrt());
+        let map_pixels = |f: &dyn Fn([f32; 4]) -> [u8; 4]| {
+            image::RgbaImage::from_fn(target.width(), target.height(), |x, y| image::Rgba(f(target.get_pixel(x, y).0)))
+        };
+        match layer {
+            RenderLayer::Colour => map_pixels(&|[r, g, b, a]| {
+                // The colours are premultiplied by the coverage, to be composited as
+                // `colour + (1 - alpha) * background`, while 8-bit images keep them apart. Shadow catchers raise their
+                // alpha to cover the light they catch, so dividing by it doesn't push their colours above one.
+                let [r, g, b] = [r, g, b].map(|c| if a > 0.0 { c / a } else { 0.0 });
+                [encode(r), encode(g), encode(b), unorm(a)]
+            }),
+            RenderLayer::Albedo | RenderLayer::Direct | RenderLayer::Indirect | RenderLayer::Emission => {
+                map_pixels(&|[r, g, b, _]| [encode(r), encode(g), encode(b), 255])
+            }
+            RenderLayer::Normal => map_pixels(&|[x, y, z, _]| opaque([x, y, z].map(|n| unorm(0.5 + 0.5 * n)))),
+            RenderLayer::Depth => {
+                // Nearer surfaces are brighter, and the background is black.
+                let max_depth = target.pixels().map(|p| p.0[3]).fold(0.0, f32::max);
+                map_pixels(&|[_, _, _, depth]| {
+                    let brightness = if depth > 0.0 { unorm(1.0 - 0.9 * depth / max_depth) } else { 0 };
+                    opaque([brightness; 3])
+                })
+            }
+            RenderLayer::Position => {
+                // Scaled to fit the furthest coordinate, with the origin in grey.
+                let extent =
+                    target.pixels().flat_map(|p| p.0.into_iter().take(3)).map(f32::abs).fold(f32::EPSILON, f32::max);
+                map_pixels(&|[x, y, z, _]| opaque([x, y, z].map(|p| unorm(0.5 + 0.5 * p / extent))))
+            }
+            RenderLayer::MaterialIndex => map_pixels(&|[index, ..]| opaque(index_colour(index))),
+            RenderLayer::ObjectId => map_pixels(&|[_, index, ..]| opaque(index_colour(index))),
+        }
+    }
+
+    /// Writes every layer of the render into a multi-layer EXR file, keeping the linear values without any
+    /// post-processing and the colours premultiplied by their alpha, as EXR files expect.
+    pub fn write_exr(&self, path: &Path) -> anyhow::Result<()> {
+        let (width, height) = self.dimensions();
+        let size = (width as usize, height as usize);
+        let layers = self
+            .layers()
+            .map(|layer| {
+                let target = &self.targets[layer.target()];
+                let channels = layer
+                    .channels()
+                    .iter()
+                    .map(|&(component, name)| {
+                        let samples = target.pixels().map(|p| p.0[component]).collect_vec();
+                        AnyChannel::new(name, FlatSamples::F32(samples))
+                    })
+                    .collect_vec();
+                Layer::new(
+                    size,
+                    LayerAttributes::named(layer.exr_name()),
+                    Encoding::FAST_LOSSLESS,
+                    AnyChannels::sort(channels.into()),
+                )
+            })
+            .collect_vec();
+        Image::from_layers(ImageAttributes::new(IntegerBounds::from_dimensions(size)), layers).write().to_file(path)?;
+        Ok(())
+    }
+}
+
+fn opaque([r, g, b]: [u8; 3]) -> [u8; 4] {
+    [r, g, b, 255]
+}
+
+/// Colour telling apart the materials or objects with neighbouring indices, and black where nothing has been hit.
+fn index_colour(index: f32) -> [u8; 3] {
+    if index < 0.0 {
+        return [0; 3];
+    }
+    let hash = (index as u32 + 1).wrapping_mul(0x9e37_79b1);
+    [hash >> 24, hash >> 16, hash >> 8].map(|channel| channel as u8 | 0x40)
+}